@@ -57,7 +57,7 @@ mod app {
 
     #[shared]
     struct Shared {
-        can1: bxcan::Can<Can<CAN1, (PA12<Alternate<9>>, PA11<Alternate<9>>)>>,
+        can1: bxcan::Can<Can<CAN1>>,
         first_five: fp::FirstFive,
         next_address_id: Result<u32, id_manager::Error>, //@TODO: Overtages af mem
         flash: Memory<
@@ -29,7 +29,7 @@ mod app {
     #[shared]
     struct Shared {
         //can1 opsættes til den interne CAN1, og bliver linket til PA12 og PA11 på den alternative funktion 9.
-        can1: bxcan::Can<Can<CAN1, (PA12<Alternate<9>>, PA11<Alternate<9>>)>>,
+        can1: bxcan::Can<Can<CAN1>>,
     }
 
     // Holds the local resources (used by a single task)
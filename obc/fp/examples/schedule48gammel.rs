@@ -39,7 +39,7 @@ mod app {
     #[shared]
     struct Shared {
         //can1 opsættes til den interne CAN1, og bliver linket til PA12 og PA11 på den alternative funktion 9.
-        can1: bxcan::Can<Can<CAN1, (PA12<Alternate<9>>, PA11<Alternate<9>>)>>,
+        can1: bxcan::Can<Can<CAN1>>,
         sharedtime: [u8; 8],
         sharedtaskid: Vec<[u8; 8], 10>,
         data_from_can: Vec<[u8; 8], 32>,
@@ -11,6 +11,7 @@ use defmt_rtt as _;
 
 use embedded_hal::blocking::serial::write;
 use flash::w25q128::{FlashInfo, Memory};
+use flash::NorFlash;
 //Defines how we should panic -> Using probe-run.
 use panic_probe as _;
 
@@ -19,7 +20,7 @@ use hal::{
     flash::FlashSector,
     pac::{self},
     prelude::*,
-    spi::{Event, Instance},
+    spi::Event,
 };
 use stm32f4xx_hal as hal;
 
@@ -103,8 +104,8 @@ const START_ADDR: u32 = 0x0;
 const TASK_NUM: u8 = 48;
 const TASK_SIZE: u16 = 256;
 
-fn set_executed_bytes<SPI: Instance, PINS, const P: char, const N: u8, MODE>(
-    flash: &mut Memory<SPI, PINS, P, N, MODE>,
+fn set_executed_bytes<F: NorFlash>(
+    flash: &mut F,
 ) {
     let index = 2;
     let mut addr = START_ADDR + index;
@@ -127,8 +128,8 @@ fn set_executed_bytes<SPI: Instance, PINS, const P: char, const N: u8, MODE>(
         counter += 1;
     }
 }
-fn fill<SPI: Instance, PINS, const P: char, const N: u8, MODE>(
-    flash: &mut Memory<SPI, PINS, P, N, MODE>,
+fn fill<F: NorFlash>(
+    flash: &mut F,
 ) {
     let index = 2;
     let mut addr = START_ADDR;
@@ -159,8 +160,8 @@ fn fill<SPI: Instance, PINS, const P: char, const N: u8, MODE>(
     }
 }
 
-fn dump_fp<SPI: Instance, PINS, const P: char, const N: u8, MODE>(
-    flash: &mut Memory<SPI, PINS, P, N, MODE>,
+fn dump_fp<F: NorFlash>(
+    flash: &mut F,
 ) {
     // let len = TASK_SIZE * TASK_NUM as u16;
     let len = TASK_SIZE * 17;
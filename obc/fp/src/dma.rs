@@ -0,0 +1,143 @@
+pub mod dma {
+    //Flash reads and programs of a task as DMA transfers. The flash is only locked to start a
+    //transfer and to poll it, so tasks of a higher priority can use it while the data moves.
+    //A transfer that can not be started, because another one is running or the access does
+    //not fit the buffer, is done as an ordinary blocking access instead.
+    use flash::DmaFlash;
+    use rtic::Mutex;
+
+    pub struct Dma {
+        buffer: Option<&'static mut [u8]>, //None while a transfer owns it, or if it was lost
+    }
+
+    impl Dma {
+        pub fn new(buffer: &'static mut [u8]) -> Self {
+            Dma {
+                buffer: Some(buffer),
+            }
+        }
+
+        //The buffer, if data fits in it.
+        fn take(&mut self, len: usize) -> Option<&'static mut [u8]> {
+            match self.buffer.take() {
+                Some(buffer) if buffer.len() >= len => Some(buffer),
+                buffer => {
+                    self.buffer = buffer;
+                    None
+                }
+            }
+        }
+
+        //Polls the running transfer with the flash released in between. The buffer is kept
+        //when the transfer failed.
+        fn finish<F: DmaFlash>(
+            &mut self,
+            flash: &mut impl Mutex<T = F>,
+        ) -> Result<&'static mut [u8], flash::Error> {
+            loop {
+                match flash.lock(|f| f.poll_transfer()) {
+                    Ok(buffer) => return Ok(buffer),
+                    Err(nb::Error::WouldBlock) => {}
+                    Err(nb::Error::Other((e, buffer))) => {
+                        self.buffer = buffer;
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        //Read data.len() bytes from address.
+        pub fn read<F: DmaFlash>(
+            &mut self,
+            flash: &mut impl Mutex<T = F>,
+            address: u32,
+            data: &mut [u8],
+        ) -> Result<(), flash::Error> {
+            let len = data.len();
+            let Some(buffer) = self.take(len) else {
+                return flash.lock(|f| f.read(address, len, data));
+            };
+            if let Err((_, buffer)) = flash.lock(|f| f.start_read(address, buffer, len)) {
+                self.buffer = Some(buffer);
+                return flash.lock(|f| f.read(address, len, data));
+            }
+            let buffer = self.finish(flash)?;
+            data.copy_from_slice(&buffer[..len]);
+            self.buffer = Some(buffer);
+            Ok(())
+        }
+
+        //Program data from address, a page per transfer. Each page waits for the program
+        //before it, also with the flash released.
+        pub fn write<F: DmaFlash>(
+            &mut self,
+            flash: &mut impl Mutex<T = F>,
+            address: u32,
+            data: &[u8],
+        ) -> Result<(), flash::Error> {
+            let page = flash.lock(|f| f.info().page_size) as usize;
+            let mut index = 0;
+            while index < data.len() {
+                let at = address + index as u32;
+                let part = &data[index..(index + page - at as usize % page).min(data.len())];
+                index += part.len();
+                let Some(buffer) = self.take(part.len()) else {
+                    flash.lock(|f| f.write(at, part))?;
+                    continue;
+                };
+                while flash.lock(|f| f.is_busy())? {}
+                buffer[..part.len()].copy_from_slice(part);
+                if let Err((_, buffer)) = flash.lock(|f| f.start_write_page(at, buffer, part.len()))
+                {
+                    self.buffer = Some(buffer);
+                    flash.lock(|f| f.write(at, part))?;
+                    continue;
+                }
+                let buffer = self.finish(flash)?;
+                self.buffer = Some(buffer);
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use flash::sim::SimFlash;
+        use rtic::Exclusive;
+
+        #[test]
+        fn transfers_match_blocking_accesses() {
+            let mut flash = SimFlash::<0x2000>::new();
+            let mut dma = Dma::new(Box::leak(Box::new([0u8; 300])));
+            let data: Vec<u8> = (0..600u32).map(|i| i as u8).collect();
+            //Split at the page boundaries.
+            dma.write(&mut Exclusive(&mut flash), 0xf0, &data[..300])
+                .unwrap();
+            dma.write(&mut Exclusive(&mut flash), 0x1000, &data)
+                .unwrap();
+            assert_eq!(flash.as_slice()[0xf0..0x21c], data[..300]);
+            assert_eq!(flash.as_slice()[0x1000..0x1258], data[..]);
+
+            let mut read = [0u8; 300];
+            dma.read(&mut Exclusive(&mut flash), 0xf0, &mut read)
+                .unwrap();
+            assert_eq!(read[..], data[..300]);
+            //Too long for the buffer, so blocking.
+            let mut long = [0u8; 600];
+            dma.read(&mut Exclusive(&mut flash), 0x1000, &mut long)
+                .unwrap();
+            assert_eq!(long[..], data[..]);
+            //A transfer that can not start is done blocking.
+            let other: &'static mut [u8] = Box::leak(Box::new([0u8; 4]));
+            flash.start_read(0, other, 4).ok().unwrap();
+            dma.read(&mut Exclusive(&mut flash), 0xf0, &mut read)
+                .unwrap();
+            assert_eq!(read[..], data[..300]);
+            assert!(nb::block!(flash.poll_transfer()).is_ok());
+            let outside = dma.read(&mut Exclusive(&mut flash), 0x1f00, &mut read);
+            assert_eq!(outside, Err(flash::Error::OutOfRange));
+            assert!(dma.buffer.is_some());
+        }
+    }
+}
@@ -1,5 +1,17 @@
 pub mod excan {
     use bxcan::{ExtendedId, Frame};
+    use core::sync::atomic::{AtomicU8, Ordering};
+
+    //Our node ID in the identifier of every frame sent. Loaded from flash during init.
+    static TRANSMITTER_ID: AtomicU8 = AtomicU8::new(1);
+
+    pub fn set_transmitter_id(id: u8) {
+        TRANSMITTER_ID.store(id & 0xf, Ordering::Relaxed);
+    }
+
+    pub fn transmitter_id() -> u8 {
+        TRANSMITTER_ID.load(Ordering::Relaxed)
+    }
     /*TODO: Make an actual externalization of CAN. It would be easier if all CAN handling happend from here.*/
 
     pub struct IdentifierContents {
@@ -98,7 +110,6 @@ pub mod excan {
         frg_count: u8,
         data: &[u8; 8],
     ) -> bxcan::Frame {
-        static TRANSMITTER_ID: u8 = 1; //Should be globally defined during init
         let frame = {
             //Opsætter det korrekte frame format
             //Create a new frame with the correct ID
@@ -107,7 +118,7 @@ pub mod excan {
             id = (id | (rec as u32)) << 3;
             id = (id | (port as u32)) << 8;
             id = (id | (cmd as u32)) << 4;
-            id = (id | (TRANSMITTER_ID as u32)) << 1;
+            id = (id | (transmitter_id() as u32)) << 1;
             id = (id | (start_bit as u32)) << 1;
             id = (id | (end_bit as u32)) << 5;
             id = id | frg_count as u32;
@@ -1,8 +1,14 @@
 pub mod flightplanner {
 
     use defmt::Format;
+    use flash::crc::crc16;
     use heapless::Vec;
 
+    //The last two bytes of a task hold a CRC-16/CCITT of the bytes before them. The status
+    //bits of byte 2 are left out, as they are programmed after the task is written.
+    //Bytes 254-255 in the fixed 256 byte slots of format versions 1 and 2.
+    pub const CRC_INDEX: usize = 254;
+
     #[derive(Copy, Clone, Format)]
     pub struct FFArray {
         pub id: u32,
@@ -106,131 +112,431 @@ pub mod flightplanner {
         }
     }
 
-    pub fn compile_task(data: &Vec<[u8; 8], 32>, debug: bool) -> [u8; 256] {
-        let mut task: [u8; 256] = [0; 256];
+    pub fn compare_tasks(task1: &[u8; 256], task2: &[u8; 256]) -> bool {
+        //Compares two tasks, and returns true if they are the same
+        let mut same = true;
+        for x in 0..256 {
+            if task1[x] != task2[x] {
+                same = false;
+            }
+        }
+        same
+    }
 
-        //Id is imported - moved from [00000PPP][0000RRRR][0000ppp][CCCCCCCC] => [00000000][PPPRRRRp][ppCCCCCC][CCEEEEEE]
-        //Where first P is Priority, R is Reciever, p is port, C is command and E is Executed. - Only last three bytes are relevant for the Task.
-        //Note: Priority is pushed to the start, for easier sorting.
-        let can_id: [u8; 4] = {
-            let mut can_id: u32 = (data[0][0] & 0b00000111) as u32;
-            can_id = (can_id << 4) | (data[0][1] & 0b00001111) as u32;
-            can_id = (can_id << 3) | (data[0][2] & 0b00000111) as u32;
-            can_id = (can_id << 8) | data[0][3] as u32;
-            ((can_id << 6) | 0b00001111).to_be_bytes()
-        };
+    //A stored task, big endian. Stored as a record of just the frames it has (to_record):
+    //|PPPRRRRp|ppCCCCCC|CCSSSSSS|execution time (4 bytes)|DLC|payload (8 bytes per frame)|CRC|
+    //or, up to format version 2, padded to a fixed 256 byte slot with the CRC last (to_bytes),
+    //where it takes the last two bytes of a full payload.
+    //P is priority, R receiver, p port, C command and S the status bits. The DLC counts the
+    //payload frames plus one, for the header frame the task was scheduled with.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Task {
+        pub priority: u8, //3 bits
+        pub receiver: u8, //4 bits
+        pub port: u8,     //3 bits
+        pub command: u8,
+        pub execution_time: i32, //RTC time (unix)
+        pub status: Status,
+        //CAN frames sent when the task is executed. The last two bytes of a full
+        //payload read from a fixed slot are 0.
+        pub payload: Vec<[u8; 8], MAX_PAYLOAD>,
+    }
 
-        //Data Lenght Code is established as the received data, minus 1 (that is the CAN_ID and the execution time)
-        //Program should throw an error if DLC is larger than 30.
-        //@TODO: Determine maximum lenght
-        let dlc = (data.len()) as u8;
+    pub const TASK_BYTES: usize = 256; //The slot of format version 1 and 2
+    pub const HEADER_BYTES: usize = 8;
+    pub const MAX_PAYLOAD: usize = 31; //Frames
+    pub const RECORD_BYTES: usize = HEADER_BYTES + 8 * MAX_PAYLOAD + 2; //Largest record
+    pub const STATUS_INDEX: usize = 2;
+    pub const DLC_INDEX: usize = 7;
 
-        //Empty array for execution time
-        let mut execution_time: [u8; 4] = [0; 4];
+    //Bytes of the record of a task with this DLC, None if no task has it.
+    pub fn record_len(dlc: u8) -> Option<usize> {
+        let frames = (dlc as usize)
+            .checked_sub(1)
+            .filter(|f| *f <= MAX_PAYLOAD)?;
+        Some(HEADER_BYTES + 8 * frames + 2)
+    }
+
+    //The six status bits of byte 2. They are only ever cleared, so every step is one program,
+    //and a reset during a step leaves either the old or the new status.
+    //Empty -> Writing -> Scheduled -> Executing -> Executed -> Reclaimable
+    //Any used slot can go to Reclaimable (deleted) or Quarantined.
+    #[derive(Clone, Copy, Debug, PartialEq, Format)]
+    pub enum Status {
+        Empty,       //0b111111, erased
+        Writing,     //0b011111, programmed before the rest of the task
+        Scheduled,   //0b001111
+        Executing,   //0b000111, programmed before the task is sent
+        Executed,    //0b000101
+        Quarantined, //0b000001, the CRC failed or the slot was left mid-step
+        Reclaimable, //0b000000, deleted
+        Invalid(u8), //Any other bits
+    }
 
-        //CAN_ID is placed in the first three spots of the task
-        for x in 0..=2 {
-            task[x as usize] = can_id[x + 1];
+    impl Status {
+        pub fn bits(self) -> u8 {
+            match self {
+                Status::Empty => 0b111111,
+                Status::Writing => 0b011111,
+                Status::Scheduled => 0b001111,
+                Status::Executing => 0b000111,
+                Status::Executed => 0b000101,
+                Status::Quarantined => 0b000001,
+                Status::Reclaimable => 0b000000,
+                Status::Invalid(bits) => bits & 0b111111,
+            }
         }
 
-        //Execution time is placed from [3] to [6]
-        for x in 0..=3 {
-            task[x + 3 as usize] = data[0][x + 4];
-            execution_time[x] = data[0][x + 4];
+        //Status of a stored task from its byte 2.
+        pub fn from_byte(byte: u8) -> Status {
+            match byte & 0b111111 {
+                0b111111 => Status::Empty,
+                0b011111 => Status::Writing,
+                0b001111 => Status::Scheduled,
+                0b000111 => Status::Executing,
+                0b000101 => Status::Executed,
+                0b000001 => Status::Quarantined,
+                0b000000 => Status::Reclaimable,
+                bits => Status::Invalid(bits),
+            }
         }
 
-        //task [7] is the DLC - Lets us know the amount of data to read.
-        task[7] = dlc;
+        //Byte programmed over byte 2 to move a task to this status. The command bits are
+        //left as they are.
+        pub fn program_byte(self) -> u8 {
+            0b11000000 | self.bits()
+        }
 
-        //Rest of the data is filled into the correct spots - There are 8 bytes per can frame, and up to DLC number of frames that are to be logged
-        for frame_nr in 1..dlc {
-            for part_nr in 0..8 {
-                task[(frame_nr * 8 + part_nr) as usize] =
-                    data[(frame_nr) as usize][part_nr as usize];
-            }
+        //True if the step only clears bits, so it can be programmed without an erase.
+        pub fn can_become(self, next: Status) -> bool {
+            self.bits() & next.bits() == next.bits()
         }
 
-        if debug {
-            defmt::debug!("Recieved ID: {:#010b}", can_id);
-            defmt::debug!("Data is {} long", dlc);
-            defmt::debug!("Data includes: {:#04X}", data[1..dlc as usize]);
-            defmt::debug!("current task:{:#04X}", task);
+        //Ground can delete the task: not being sent, and not deleted already.
+        pub fn can_delete(self) -> bool {
+            !matches!(
+                self,
+                Status::Empty | Status::Executing | Status::Reclaimable
+            )
+        }
+
+        //The slot can be erased and used again.
+        pub fn is_reclaimable(self) -> bool {
+            matches!(
+                self,
+                Status::Executed | Status::Quarantined | Status::Reclaimable
+            )
         }
-        //Returns task
-        task
     }
 
-    pub fn compare_tasks(task1: &[u8; 256], task2: &[u8; 256]) -> bool {
-        //Compares two tasks, and returns true if they are the same
-        let mut same = true;
-        for x in 0..256 {
-            if task1[x] != task2[x] {
-                same = false;
+    //The task was corrupted on the flash, or the slot holds no task. Both are 0 for a record
+    //with an invalid DLC.
+    #[derive(Clone, Copy, Debug, PartialEq, Format)]
+    pub struct CrcError {
+        pub stored: u16,
+        pub computed: u16,
+    }
+
+    impl Task {
+        //Task as scheduled over CAN. First frame: |priority|receiver|port|command|execution time|
+        //The rest is the payload, anything beyond MAX_PAYLOAD frames is dropped.
+        pub fn from_frames(frames: &[[u8; 8]]) -> Task {
+            let header = frames.first().copied().unwrap_or_default();
+            let mut payload = Vec::new();
+            for frame in frames.iter().skip(1).take(MAX_PAYLOAD) {
+                payload.push(*frame).ok();
+            }
+            Task {
+                priority: header[0] & 0b111,
+                receiver: header[1] & 0b1111,
+                port: header[2] & 0b111,
+                command: header[3],
+                execution_time: i32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+                status: Status::Scheduled,
+                payload,
             }
         }
-        same
+
+        //Frames sent to ground: |priority|receiver|port|command|execution time|, then the
+        //address (2 bytes) followed by the payload, at most 32 frames in all.
+        pub fn to_frames(&self, address: u32) -> Vec<[u8; 8], 32> {
+            let t = self.execution_time.to_be_bytes();
+            let mut frames = Vec::new();
+            frames
+                .push([
+                    self.priority,
+                    self.receiver,
+                    self.port,
+                    self.command,
+                    t[0],
+                    t[1],
+                    t[2],
+                    t[3],
+                ])
+                .ok();
+            let mut stream = [0u8; 8 * MAX_PAYLOAD];
+            stream[..2].copy_from_slice(&(address as u16).to_be_bytes());
+            for (i, byte) in self
+                .payload
+                .iter()
+                .flatten()
+                .take(stream.len() - 2)
+                .enumerate()
+            {
+                stream[i + 2] = *byte;
+            }
+            let count = (self.payload.len() + 1).min(MAX_PAYLOAD);
+            for frame in stream.chunks_exact(8).take(count) {
+                frames.push(frame.try_into().unwrap()).ok();
+            }
+            frames
+        }
+
+        //Byte 2 as stored: the two low command bits and the status bits.
+        pub fn status_byte(&self) -> u8 {
+            self.command << 6 | self.status.bits()
+        }
+
+        //Header and payload, then room for the CRC. Payload frames not used are 0.
+        fn encode(&self) -> [u8; RECORD_BYTES] {
+            let mut raw = [0u8; RECORD_BYTES];
+            raw[0] =
+                (self.priority & 0b111) << 5 | (self.receiver & 0b1111) << 1 | (self.port >> 2) & 1;
+            raw[1] = self.port << 6 | self.command >> 2;
+            raw[STATUS_INDEX] = self.status_byte();
+            raw[3..7].copy_from_slice(&self.execution_time.to_be_bytes());
+            raw[DLC_INDEX] = self.payload.len() as u8 + 1;
+            for (i, frame) in self.payload.iter().enumerate() {
+                raw[8 + i * 8..16 + i * 8].copy_from_slice(frame);
+            }
+            raw
+        }
+
+        //Fixed 256 byte slot, the CRC over the end of a full payload.
+        pub fn to_bytes(&self) -> [u8; TASK_BYTES] {
+            let mut raw = [0u8; TASK_BYTES];
+            raw.copy_from_slice(&self.encode()[..TASK_BYTES]);
+            seal(&mut raw);
+            raw
+        }
+
+        pub fn from_bytes(raw: &[u8; TASK_BYTES]) -> Result<Task, CrcError> {
+            check_crc(raw)?;
+            let mut task = decode(raw);
+            if let Some(frame) = task.payload.get_mut(MAX_PAYLOAD - 1) {
+                frame[6..].fill(0); //The CRC
+            }
+            Ok(task)
+        }
+
+        //Record with only the frames of the task, the CRC after them.
+        pub fn to_record(&self) -> Vec<u8, RECORD_BYTES> {
+            let mut raw = self.encode();
+            let len = record_len(raw[DLC_INDEX]).unwrap_or(RECORD_BYTES);
+            let crc = task_crc(&raw[..len]).to_be_bytes();
+            raw[len - 2..len].copy_from_slice(&crc);
+            Vec::from_slice(&raw[..len]).unwrap()
+        }
+
+        //Task from a record, which may be followed by other data.
+        pub fn from_record(raw: &[u8]) -> Result<Task, CrcError> {
+            let len = match raw.get(DLC_INDEX).and_then(|dlc| record_len(*dlc)) {
+                Some(len) if len <= raw.len() => len,
+                _ => {
+                    return Err(CrcError {
+                        stored: 0,
+                        computed: 0,
+                    })
+                }
+            };
+            check_crc(&raw[..len])?;
+            Ok(decode(&raw[..len]))
+        }
     }
 
-    pub fn is_execute_ready(byte: u8) -> bool {
-        let scheduled: bool = 0 == (byte & 0b00110000);
-        let executed: bool = 0b00000101 == (byte & 0b00111111);
-        //Only interested in scheduled tasks, not executed ones
-        if scheduled && !executed {
-            true
-        } else {
-            false
+    //Fields of a task whose CRC has been checked, from as many frames as raw holds.
+    fn decode(raw: &[u8]) -> Task {
+        let mut payload = Vec::new();
+        let frames = (raw[DLC_INDEX] as usize).min(raw.len() / 8);
+        for i in 1..frames.min(MAX_PAYLOAD + 1) {
+            payload.push(raw[i * 8..i * 8 + 8].try_into().unwrap()).ok();
+        }
+        Task {
+            priority: raw[0] >> 5,
+            receiver: (raw[0] >> 1) & 0b1111,
+            port: (raw[0] & 1) << 2 | raw[1] >> 6,
+            command: raw[1] << 2 | raw[STATUS_INDEX] >> 6,
+            execution_time: i32::from_be_bytes([raw[3], raw[4], raw[5], raw[6]]),
+            status: Status::from_byte(raw[STATUS_INDEX]),
+            payload,
         }
     }
 
-    pub fn decompile_task(task: &mut [u8; 256], address: u32) -> Vec<[u8; 8], 32> {
-        defmt::debug!("Decompiling task: {}", address);
-        let mut dlc = task[7] as usize;
-        //[PPPRRRRp][ppCCCCCC][CCEEEEEE] => [00000PPP][0000RRRR][0000ppp][CCCCCCCC]
-        let mut data_vec = Vec::<[u8; 8], 32>::new();
-        //Extract data from the compact version
-        let prio = task[0] >> 5;
-        let rec = (task[0] >> 1) & 0b00001111;
-        let port = (task[0] & 0b00000001) << 2 | (task[1] >> 6);
-        let cmd = (task[1] << 2) | (task[2] >> 6);
+    //Store the CRC of a fixed slot task written without one.
+    pub fn seal(task: &mut [u8; TASK_BYTES]) {
+        let crc = task_crc(task).to_be_bytes();
+        task[CRC_INDEX..].copy_from_slice(&crc);
+    }
 
-        //Makes sure that we do not read more than possible
-        let mut max = dlc * 8;
-        if max > 254 {
-            max = 254;
+    fn check_crc(task: &[u8]) -> Result<(), CrcError> {
+        let (stored, computed) = (stored_crc(task), task_crc(task));
+        if stored != computed {
+            return Err(CrcError { stored, computed });
         }
+        Ok(())
+    }
+
+    //CRC the task should have, of all but its last two bytes.
+    fn task_crc(task: &[u8]) -> u16 {
+        let mut covered = [0u8; RECORD_BYTES - 2];
+        let len = task.len() - 2;
+        covered[..len].copy_from_slice(&task[..len]);
+        covered[STATUS_INDEX] &= 0b11000000;
+        crc16(&covered[..len])
+    }
 
-        //Shifts data two spaces to the right
-        for i in (3..7).rev() {
-            //defmt::debug!("time b{} with b{}", i + 2, i,);
-            task[i + 1] = task[i];
+    //CRC stored with the task, in its last two bytes.
+    fn stored_crc(task: &[u8]) -> u16 {
+        u16::from_be_bytes([task[task.len() - 2], task[task.len() - 1]])
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn task(payload_frames: usize) -> Task {
+            let mut payload = Vec::new();
+            for i in 0..payload_frames {
+                payload.push([i as u8, 1, 2, 3, 4, 5, 0xfe, 0xff]).ok();
+            }
+            Task {
+                priority: 5,
+                receiver: 0b1010,
+                port: 0b101,
+                command: 0xc3,
+                execution_time: 1_700_000_000,
+                status: Status::Scheduled,
+                payload,
+            }
+        }
+
+        #[test]
+        fn round_trip() {
+            for frames in [0, 1, 7, MAX_PAYLOAD - 1] {
+                let task = task(frames);
+                let raw = task.to_bytes();
+                assert_eq!(raw[7] as usize, frames + 1);
+                assert_eq!(Task::from_bytes(&raw), Ok(task));
+            }
+            //The CRC takes the last two bytes of a full payload.
+            let mut full = task(MAX_PAYLOAD);
+            full.payload[MAX_PAYLOAD - 1][6..].fill(0);
+            assert_eq!(Task::from_bytes(&full.to_bytes()), Ok(full));
+        }
+
+        #[test]
+        fn records_hold_only_the_frames_used() {
+            for (frames, len) in [(0, 10), (1, 18), (7, 66), (30, 250), (MAX_PAYLOAD, 258)] {
+                let task = task(frames);
+                let record = task.to_record();
+                assert_eq!(record.len(), len);
+                assert_eq!(record_len(record[DLC_INDEX]), Some(len));
+                assert_eq!(record[..8], task.to_bytes()[..8]);
+                //Whatever follows the record is not part of it.
+                let mut stored = [0x5a; RECORD_BYTES + 8];
+                stored[..len].copy_from_slice(&record);
+                assert_eq!(Task::from_record(&stored), Ok(task.clone()));
+                assert!(Task::from_record(&record[..len - 1]).is_err());
+            }
+            //The CRC of a full record comes after the payload, not over its last bytes.
+            let full = task(MAX_PAYLOAD);
+            let record = full.to_record();
+            assert_eq!(record[248..256], [30, 1, 2, 3, 4, 5, 0xfe, 0xff]);
+            let read = Task::from_record(&record).unwrap();
+            assert_eq!(read.payload[MAX_PAYLOAD - 1][6..], [0xfe, 0xff]);
+            assert_eq!(read, full);
+
+            let invalid = CrcError {
+                stored: 0,
+                computed: 0,
+            };
+            for dlc in [0, 33, 0xff] {
+                let mut record = task(0).to_record();
+                record[DLC_INDEX] = dlc;
+                assert_eq!(Task::from_record(&record), Err(invalid));
+            }
+            let mut record = task(2).to_record();
+            record[12] ^= 1;
+            assert_ne!(Task::from_record(&record), Err(invalid));
         }
 
-        //Shifts data two spaces to the right
-        for i in (8..max).rev() {
-            //defmt::debug!("replace b{} with b{}", i + 2, i,);
-            task[i + 2] = task[i];
+        #[test]
+        fn header_fields_are_packed() {
+            let raw = task(0).to_bytes();
+            //|PPPRRRRp|ppCCCCCC|CCSSSSSS|
+            assert_eq!(raw[..3], [0b101_1010_1, 0b01_110000, 0b11_001111]);
+            assert_eq!(raw[3..7], 1_700_000_000i32.to_be_bytes());
         }
 
-        task[9] = address as u8;
-        task[8] = (address >> 8) as u8;
-        task[3] = cmd;
-        task[2] = port;
-        task[1] = rec;
-        task[0] = prio;
+        #[test]
+        fn status_changes_keep_the_crc() {
+            let mut task = task(3);
+            let mut raw = task.to_bytes();
+            for status in [Status::Executed, Status::Quarantined] {
+                //Programmed in place: bits are only cleared.
+                task.status = status;
+                assert_eq!(raw[STATUS_INDEX] & task.status_byte(), task.status_byte());
+                raw[STATUS_INDEX] = task.status_byte();
+                assert_eq!(Task::from_bytes(&raw), Ok(task.clone()));
+            }
+            assert_eq!(Status::from_byte(0xff), Status::Empty);
+            assert_eq!(Status::from_byte(0b11_010111), Status::Invalid(0b010111));
+        }
 
-        defmt::debug!("Task: {}", task);
+        #[test]
+        fn lifecycle_only_clears_bits() {
+            use Status::*;
+            let lifecycle = [Empty, Writing, Scheduled, Executing, Executed, Reclaimable];
+            for step in lifecycle.windows(2) {
+                assert!(step[0].can_become(step[1]), "{:?}", step);
+                assert!(!step[1].can_become(step[0]), "{:?}", step);
+            }
+            for status in lifecycle[1..].iter() {
+                assert!(status.can_become(Reclaimable));
+                assert_eq!(status.can_become(Quarantined), *status != Reclaimable);
+                assert_eq!(Status::from_byte(status.program_byte()), *status);
+            }
+            //Programming a step over a task keeps the command bits.
+            assert_eq!(0b10_001111 & Executing.program_byte(), 0b10_000111);
+        }
 
-        if dlc == 32 {
-            dlc = 31;
+        #[test]
+        fn corruption_fails_the_crc() {
+            let mut raw = task(2).to_bytes();
+            raw[20] ^= 0x10;
+            assert!(Task::from_bytes(&raw).is_err());
+            assert!(Task::from_bytes(&[0xff; TASK_BYTES]).is_err());
+            assert!(Task::from_bytes(&[0; TASK_BYTES]).is_err());
         }
 
-        for i in 0..(dlc + 1) {
-            let s_b = (i) * 8;
-            let e_b = (i + 1) * 8;
-            data_vec
-                .push(task[s_b..e_b].try_into().expect("Not a real size"))
+        #[test]
+        fn frames_round_trip() {
+            let task = task(2);
+            let mut frames = Vec::<[u8; 8], 32>::new();
+            frames
+                .push([5, 0b1010, 0b101, 0xc3, 0x65, 0x53, 0xf1, 0x00])
                 .ok();
+            frames.extend(task.payload.iter().copied());
+            assert_eq!(Task::from_frames(&frames), task);
+
+            //To ground: header, then the address ahead of the payload.
+            let sent = task.to_frames(0x0300);
+            assert_eq!(sent.len(), 4);
+            assert_eq!(sent[0], frames[0]);
+            assert_eq!(sent[1], [0x03, 0x00, 0, 1, 2, 3, 4, 5]);
+            assert_eq!(sent[2], [0xfe, 0xff, 1, 1, 2, 3, 4, 5]);
+            assert_eq!(sent[3], [0xfe, 0xff, 0, 0, 0, 0, 0, 0]);
+            assert_eq!(self::task(MAX_PAYLOAD).to_frames(0).len(), 32);
         }
-        data_vec
     }
 }
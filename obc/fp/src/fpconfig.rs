@@ -0,0 +1,193 @@
+pub mod fpconfig {
+    //Where the flight plan is on the flash and how big it is. A default is built in, and a
+    //stored configuration item can replace it without a new build. It is checked at boot
+    //against the flash that was found, before anything is read from the plan, and the
+    //superblock, the bad sector layer and the slot map all take their layout from it.
+    use crate::flightplanner::flightplanner as fp;
+    use crate::superblock::superblock::Superblock;
+    use core::ops::Range;
+    use defmt::Format;
+    use flash::bad::BadSectorConfig;
+    use flash::w25q128::FlashInfo;
+
+    pub const CONFIG_BYTES: usize = 8;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Format)]
+    pub struct FlightPlanConfig {
+        pub start: u32,      //Address of the first unit, on a sector boundary
+        pub unit_size: u16,  //Bytes, a task record takes as many units as it needs
+        pub unit_count: u16, //Whole sectors of units
+    }
+
+    //What the rest of the firmware leaves to the flight plan.
+    #[derive(Clone, Debug)]
+    pub struct Limits {
+        pub units: usize,         //Units the slot map in RAM holds
+        pub sectors: usize,       //Sectors the bad sector layer maps, spares included
+        pub spares: usize,        //Spare sectors, right after the plan
+        pub table: u32,           //Address of the bad sector table
+        pub reserved: Range<u32>, //Used for other things, the table included
+    }
+
+    //The number is sent to ground when the plan is refused.
+    #[derive(Clone, Copy, Debug, PartialEq, Format)]
+    pub enum Error {
+        Unaligned = 1,  //Start not on a sector boundary
+        UnitSize = 2,   //Smaller than a task header, larger than a task, or not a part of a sector
+        Capacity = 3, //Not two or more whole sectors, or more than the slot map or bad sectors hold
+        OutOfRange = 4, //The plan or its spares beyond the flash or over a reserved region
+    }
+
+    impl FlightPlanConfig {
+        //First address after the plan.
+        pub const fn end(&self) -> u32 {
+            self.start + self.unit_size as u32 * self.unit_count as u32
+        }
+
+        //Layout of the plan in the superblock.
+        pub const fn layout(&self) -> Superblock {
+            Superblock::new(self.start, self.unit_size, self.unit_count)
+        }
+
+        //Check the layout against the flash found at boot and the limits of this build.
+        pub fn validate(&self, info: &FlashInfo, limits: &Limits) -> Result<(), Error> {
+            let sector = info.sector_size;
+            let unit = self.unit_size as u32;
+            if unit < fp::HEADER_BYTES as u32
+                || unit > fp::TASK_BYTES as u32
+                || !sector.is_multiple_of(unit)
+            {
+                return Err(Error::UnitSize);
+            }
+            if !self.start.is_multiple_of(sector) {
+                return Err(Error::Unaligned);
+            }
+            let bytes = unit * self.unit_count as u32;
+            let sectors = (bytes / sector) as usize;
+            //One sector is kept empty for the garbage collection.
+            if sectors < 2
+                || !bytes.is_multiple_of(sector)
+                || self.unit_count as usize > limits.units
+                || sectors + limits.spares > limits.sectors
+            {
+                return Err(Error::Capacity);
+            }
+            let end = self
+                .start
+                .checked_add(bytes + limits.spares as u32 * sector)
+                .filter(|end| *end <= info.capacity())
+                .ok_or(Error::OutOfRange)?;
+            if self.start < limits.reserved.end && limits.reserved.start < end {
+                return Err(Error::OutOfRange);
+            }
+            Ok(())
+        }
+
+        //Bad sector layer over a validated plan.
+        pub fn bad_sectors(&self, info: &FlashInfo, limits: &Limits) -> BadSectorConfig {
+            BadSectorConfig {
+                start: self.start,
+                sectors: ((self.end() - self.start) / info.sector_size) as usize,
+                spares: limits.spares,
+                table: limits.table,
+            }
+        }
+
+        //|4B start|2B unit size|2B unit count|, BE.
+        pub fn to_bytes(&self) -> [u8; CONFIG_BYTES] {
+            let mut raw = [0u8; CONFIG_BYTES];
+            raw[..4].copy_from_slice(&self.start.to_be_bytes());
+            raw[4..6].copy_from_slice(&self.unit_size.to_be_bytes());
+            raw[6..].copy_from_slice(&self.unit_count.to_be_bytes());
+            raw
+        }
+
+        pub fn from_bytes(raw: &[u8; CONFIG_BYTES]) -> Self {
+            FlightPlanConfig {
+                start: u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]),
+                unit_size: u16::from_be_bytes([raw[4], raw[5]]),
+                unit_count: u16::from_be_bytes([raw[6], raw[7]]),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use flash::w25q128::{W25Q128, W25Q16};
+
+        const LIMITS: Limits = Limits {
+            units: 2048,
+            sectors: 36,
+            spares: 4,
+            table: 0x7000,
+            reserved: 0x7000..0x30000,
+        };
+        const PLAN: FlightPlanConfig = FlightPlanConfig {
+            start: 0,
+            unit_size: 16,
+            unit_count: 768,
+        };
+
+        #[test]
+        fn layouts_are_checked_against_the_flash() {
+            assert_eq!(PLAN.validate(&W25Q16, &LIMITS), Ok(()));
+            let bad = PLAN.bad_sectors(&W25Q16, &LIMITS);
+            assert_eq!((bad.start, bad.sectors, bad.spares), (0, 3, 4));
+
+            //Past the reserved regions, as large as the slot map allows.
+            let moved = FlightPlanConfig {
+                start: 0x30000,
+                unit_size: 64,
+                unit_count: 2048,
+            };
+            assert_eq!(moved.validate(&W25Q128, &LIMITS), Ok(()));
+            assert_eq!(moved.bad_sectors(&W25Q128, &LIMITS).sectors, 32);
+            let at_the_end = FlightPlanConfig {
+                start: W25Q16.capacity() - 0x7000,
+                ..PLAN
+            };
+            assert_eq!(at_the_end.validate(&W25Q16, &LIMITS), Ok(()));
+
+            let end = W25Q16.capacity();
+            let cases = [
+                //|start|unit size|unit count|
+                (0, 4, 768, Error::UnitSize),
+                (0, 512, 768, Error::UnitSize),
+                (0, 24, 768, Error::UnitSize),
+                (0x800, 16, 768, Error::Unaligned),
+                (0, 16, 0, Error::Capacity),
+                (0, 16, 256, Error::Capacity),
+                (0, 16, 700, Error::Capacity),
+                (0, 16, 2304, Error::Capacity),
+                (0, 16, 1024, Error::OutOfRange),
+                (0x20000, 16, 768, Error::OutOfRange),
+                (0x1000, 64, 2048, Error::OutOfRange),
+                (0xffff_f000, 16, 768, Error::OutOfRange),
+                (end - 0x6000, 16, 768, Error::OutOfRange),
+            ];
+            for (start, unit_size, unit_count, error) in cases {
+                let config = FlightPlanConfig {
+                    start,
+                    unit_size,
+                    unit_count,
+                };
+                assert_eq!(
+                    config.validate(&W25Q16, &LIMITS),
+                    Err(error),
+                    "{:?}",
+                    config
+                );
+            }
+        }
+
+        #[test]
+        fn stored_as_eight_bytes() {
+            let raw = PLAN.to_bytes();
+            assert_eq!(raw, [0, 0, 0, 0, 0, 16, 3, 0]);
+            assert_eq!(FlightPlanConfig::from_bytes(&raw), PLAN);
+            assert_eq!(PLAN.layout(), Superblock::new(0, 16, 768));
+            assert_eq!(PLAN.end(), 0x3000);
+        }
+    }
+}
@@ -0,0 +1,650 @@
+pub mod gc {
+    //Garbage collection of executed, quarantined and deleted task records, a sector at a time.
+    //The scheduled tasks of the sector are first copied to empty units of other sectors, and
+    //the sector is only erased once it holds nothing but reclaimable records, so a reset at
+    //any point keeps every task. Each step is one copy or one erase, and the flash can be
+    //released between steps. A copied task has a new address, so the first five must be
+    //sorted again. SlotMap::allocate keeps an empty sector for the copies. The plan must start
+    //on a sector boundary.
+    //A copy is written as Writing, the original is then marked reclaimable and the copy
+    //scheduled. A reset before the original is marked leaves the task twice, see drop_copies.
+    //The flash and the map are locked for each decision and status byte, the record itself
+    //is copied by DMA with both released.
+    use crate::dma::dma::Dma;
+    use crate::flightplanner::flightplanner::{self as fp, Status};
+    use crate::slots::slots::SlotMap;
+    use flash::w25q128::Delete;
+    use flash::{DmaFlash, NorFlash};
+    use rtic::Mutex;
+
+    //What one step of collection did.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Step {
+        Idle,          //Above low water, or no sector can be collected now
+        Moved(u32),    //A scheduled task was copied out of the sector, to this address
+        Erased(usize), //The sector was erased, freeing this many units
+    }
+
+    fn in_sector<const WORDS: usize>(slots: &SlotMap<WORDS>, sector: u32, address: u32) -> bool {
+        (sector..sector + slots.sector_size()).contains(&address)
+    }
+
+    //Units of the reclaimable records in a sector.
+    fn reclaimable_in<const WORDS: usize>(slots: &SlotMap<WORDS>, sector: u32) -> usize {
+        slots
+            .reclaimable()
+            .filter(|a| in_sector(slots, sector, *a))
+            .map(|a| slots.units(a))
+            .sum()
+    }
+
+    //Reads the record at address, and returns its length. None if the DLC is not a task's.
+    fn read_record<F: NorFlash>(
+        flash: &mut F,
+        address: u32,
+        raw: &mut [u8; fp::RECORD_BYTES],
+    ) -> Result<Option<usize>, flash::Error> {
+        flash.read(address, fp::HEADER_BYTES, &mut raw[..fp::HEADER_BYTES])?;
+        let Some(len) = fp::record_len(raw[fp::DLC_INDEX]) else {
+            return Ok(None);
+        };
+        flash.read(address, len, &mut raw[..len])?;
+        Ok(Some(len))
+    }
+
+    //A record the map has as reclaimable, but whose status byte says it is scheduled or
+    //executing, is put right in the map. True if the map was right for every record of sector.
+    fn check_reclaimable<F: NorFlash, const WORDS: usize>(
+        flash: &mut F,
+        slots: &mut SlotMap<WORDS>,
+        sector: u32,
+    ) -> Result<bool, flash::Error> {
+        let mut right = true;
+        let mut next = slots.reclaimable().find(|a| in_sector(slots, sector, *a));
+        while let Some(address) = next {
+            next = slots
+                .reclaimable()
+                .find(|a| *a > address && in_sector(slots, sector, *a));
+            let mut byte = [0u8];
+            flash.read(address + fp::STATUS_INDEX as u32, 1, &mut byte)?;
+            let status = Status::from_byte(byte[0]);
+            if matches!(status, Status::Scheduled | Status::Executing) {
+                slots.set(address, status);
+                right = false;
+            }
+        }
+        Ok(right)
+    }
+
+    //Copy the scheduled task at from to the empty units at to, reserved as Writing, and
+    //mark the original reclaimable. The map follows every status programmed. False if the
+    //task was started or deleted during the copy, the copy is then marked reclaimable.
+    fn move_task<F: DmaFlash, const WORDS: usize>(
+        flash: &mut impl Mutex<T = F>,
+        slots: &mut impl Mutex<T = SlotMap<WORDS>>,
+        dma: &mut Dma,
+        from: u32,
+        to: u32,
+    ) -> Result<bool, flash::Error> {
+        let mut raw = [0u8; fp::RECORD_BYTES];
+        dma.read(flash, from, &mut raw[..fp::HEADER_BYTES])?;
+        let len = fp::record_len(raw[fp::DLC_INDEX]).ok_or(flash::Error::VerifyMismatch)?;
+        dma.read(flash, from, &mut raw[..len])?;
+        let status = Status::from_byte(raw[fp::STATUS_INDEX]);
+        if status != Status::Scheduled {
+            slots.lock(|s| {
+                s.set(from, status);
+                s.free(to);
+            });
+            return Err(flash::Error::VerifyMismatch);
+        }
+        raw[fp::STATUS_INDEX] = Status::Writing.program_byte() & (raw[fp::STATUS_INDEX] | 0b111111);
+        //The status and DLC go first, as for a task from ground.
+        let mut header = [0xff; fp::HEADER_BYTES];
+        header[fp::STATUS_INDEX] = raw[fp::STATUS_INDEX];
+        header[fp::DLC_INDEX] = raw[fp::DLC_INDEX];
+        flash.lock(|f| f.write(to, &header))?;
+        dma.write(flash, to, &raw[..len])?;
+        let mut read_back = [0u8; fp::RECORD_BYTES];
+        dma.read(flash, to, &mut read_back[..len])?;
+        let copied = read_back[..len] == raw[..len];
+        flash.lock(|f| {
+            slots.lock(|s| {
+                //A task started or deleted during the copy stays where it is.
+                if !copied || !s.is_scheduled(from) {
+                    f.write(
+                        to + fp::STATUS_INDEX as u32,
+                        &[Status::Reclaimable.program_byte()],
+                    )?;
+                    s.set(to, Status::Reclaimable);
+                    return if copied {
+                        Ok(false)
+                    } else {
+                        Err(flash::Error::VerifyMismatch)
+                    };
+                }
+                f.write(
+                    from + fp::STATUS_INDEX as u32,
+                    &[Status::Reclaimable.program_byte()],
+                )?;
+                s.set(from, Status::Reclaimable);
+                f.write(
+                    to + fp::STATUS_INDEX as u32,
+                    &[Status::Scheduled.program_byte()],
+                )?;
+                s.set(to, Status::Scheduled);
+                Ok(true)
+            })
+        })
+    }
+
+    //What collect_step does next, decided with the flash and the map locked.
+    enum Next {
+        Done(Option<Step>),
+        Move(u32, u32), //From, to
+    }
+
+    //One step of collecting a sector: copy out its first scheduled task, or erase it once
+    //only reclaimable records are left. None if it holds a task that is neither scheduled
+    //nor reclaimable, or there is no room for its task in another sector.
+    fn collect_step<F: DmaFlash, const WORDS: usize>(
+        flash: &mut impl Mutex<T = F>,
+        slots: &mut impl Mutex<T = SlotMap<WORDS>>,
+        dma: &mut Dma,
+        sector: u32,
+    ) -> Result<Option<Step>, flash::Error> {
+        let next = flash.lock(|f| {
+            slots.lock(|s| {
+                check_reclaimable(f, s, sector)?;
+                let live = s
+                    .records()
+                    .find(|a| in_sector(s, sector, *a) && !s.is_reclaimable(*a));
+                if let Some(from) = live {
+                    if !s.is_scheduled(from) {
+                        return Ok(Next::Done(None));
+                    }
+                    let Some(to) = s.find_free_outside(s.units(from), sector) else {
+                        return Ok(Next::Done(None));
+                    };
+                    s.insert(to, s.units(from), Status::Writing);
+                    return Ok(Next::Move(from, to));
+                }
+                let freed = reclaimable_in(s, sector);
+                f.delete(Delete::SectorErase, sector)?;
+                let mut next = s.records().find(|a| in_sector(s, sector, *a));
+                while let Some(address) = next {
+                    s.free(address);
+                    next = s.records().find(|a| in_sector(s, sector, *a));
+                }
+                Ok(Next::Done(Some(Step::Erased(freed))))
+            })
+        })?;
+        match next {
+            Next::Done(step) => Ok(step),
+            Next::Move(from, to) => match move_task(flash, slots, dma, from, to)? {
+                true => Ok(Some(Step::Moved(to))),
+                false => Ok(None),
+            },
+        }
+    }
+
+    //Free the reclaimable records of one sector, after copying its scheduled tasks out.
+    //Returns the number of units freed, 0 if the sector could not be collected.
+    pub fn collect_sector<F: DmaFlash, const WORDS: usize>(
+        flash: &mut impl Mutex<T = F>,
+        slots: &mut impl Mutex<T = SlotMap<WORDS>>,
+        dma: &mut Dma,
+        sector: u32,
+    ) -> Result<usize, flash::Error> {
+        loop {
+            match collect_step(flash, slots, dma, sector)? {
+                Some(Step::Erased(units)) => return Ok(units),
+                Some(_) => continue,
+                None => return Ok(0),
+            }
+        }
+    }
+
+    //Collect every sector with reclaimable records, for when the plan is full.
+    pub fn collect_all<F: DmaFlash, const WORDS: usize>(
+        flash: &mut impl Mutex<T = F>,
+        slots: &mut impl Mutex<T = SlotMap<WORDS>>,
+        dma: &mut Dma,
+    ) -> Result<usize, flash::Error> {
+        let mut freed = 0;
+        let mut next = slots.lock(|s| s.sectors().next());
+        while let Some(sector) = next {
+            let reclaimable;
+            (next, reclaimable) =
+                slots.lock(|s| (s.sectors().find(|s| *s > sector), reclaimable_in(s, sector)));
+            if reclaimable > 0 {
+                freed += collect_sector(flash, slots, dma, sector)?;
+            }
+        }
+        Ok(freed)
+    }
+
+    //One step of background collection, called from idle. Once fewer than low_water units
+    //are empty, the sector with the most reclaimable units is collected. That spends one
+    //erase on as much room as possible. A sector that can not be collected now is passed over.
+    pub fn step<F: DmaFlash, const WORDS: usize>(
+        flash: &mut impl Mutex<T = F>,
+        slots: &mut impl Mutex<T = SlotMap<WORDS>>,
+        dma: &mut Dma,
+        low_water: usize,
+    ) -> Result<Step, flash::Error> {
+        if slots.lock(|s| s.empty_count()) >= low_water {
+            return Ok(Step::Idle);
+        }
+        //Most reclaimable units first, the lowest address of equals.
+        let mut below = (usize::MAX, u32::MAX);
+        loop {
+            let fullest = slots.lock(|s| {
+                s.sectors()
+                    .map(|sector| (reclaimable_in(s, sector), u32::MAX - sector))
+                    .filter(|key| key.0 > 0 && *key < below)
+                    .max()
+            });
+            let Some(key) = fullest else {
+                return Ok(Step::Idle);
+            };
+            if let Some(step) = collect_step(flash, slots, dma, u32::MAX - key.1)? {
+                return Ok(step);
+            }
+            below = key;
+        }
+    }
+
+    //Run at boot, before the tasks left mid-step are recovered. A copy that a reset left as
+    //Writing, next to the scheduled task it was copied from, is marked reclaimable, so the
+    //task is not scheduled twice. Returns the number of copies dropped.
+    pub fn drop_copies<F: NorFlash, const WORDS: usize>(
+        flash: &mut F,
+        slots: &mut SlotMap<WORDS>,
+    ) -> Result<usize, flash::Error> {
+        let mut dropped = 0;
+        let (mut copy, mut original) = ([0u8; fp::RECORD_BYTES], [0u8; fp::RECORD_BYTES]);
+        let mut next = slots.records().next();
+        while let Some(address) = next {
+            next = slots.records().find(|a| *a > address);
+            if slots.is_scheduled(address) || slots.is_reclaimable(address) {
+                continue;
+            }
+            let len = match read_record(flash, address, &mut copy)? {
+                Some(len) if Status::from_byte(copy[fp::STATUS_INDEX]) == Status::Writing => len,
+                _ => continue,
+            };
+            if fp::Task::from_record(&copy[..len]).is_err() {
+                continue;
+            }
+            copy[fp::STATUS_INDEX] &= 0b11000000;
+            let mut found = false;
+            for other in slots.scheduled() {
+                if read_record(flash, other, &mut original)? == Some(len) {
+                    original[fp::STATUS_INDEX] &= 0b11000000;
+                    found |= original[..len] == copy[..len];
+                }
+            }
+            if found {
+                flash.write(
+                    address + fp::STATUS_INDEX as u32,
+                    &[Status::Reclaimable.program_byte()],
+                )?;
+                slots.set(address, Status::Reclaimable);
+                dropped += 1;
+            }
+        }
+        Ok(dropped)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use flash::sim::{PowerCut, SimFlash};
+        use rtic::Exclusive;
+
+        type Sim = SimFlash<0x2000>;
+
+        //A buffer for the copies, each task has its own.
+        fn dma() -> Dma {
+            Dma::new(Box::leak(Box::new([0u8; fp::RECORD_BYTES])))
+        }
+
+        const STATES: [Status; 8] = [
+            Status::Empty,
+            Status::Writing,
+            Status::Scheduled,
+            Status::Executing,
+            Status::Executed,
+            Status::Quarantined,
+            Status::Reclaimable,
+            Status::Invalid(0b010111),
+        ];
+
+        //A task record of frames payload frames in the given status, its payload tells the
+        //records apart. Returns the units of 16 bytes it takes.
+        fn program(
+            flash: &mut impl NorFlash,
+            address: u32,
+            frames: usize,
+            status: Status,
+        ) -> usize {
+            let mut all = [[address as u8; 8]; 32];
+            all[0] = [1, 2, 3, 4, 0x70, 0, 0, (address >> 8) as u8];
+            let mut raw = fp::Task::from_frames(&all[..frames + 1]).to_record();
+            if status != Status::Empty {
+                raw[fp::STATUS_INDEX] = raw[fp::STATUS_INDEX] & 0b11000000 | status.bits();
+                flash.write(address, &raw).unwrap();
+            }
+            raw.len().div_ceil(16)
+        }
+
+        //Records in the given states (Writing only with a good CRC), without the status bits,
+        //sorted. Tasks are compared by these, as they change address when they are copied.
+        fn tasks<const N: usize>(flash: &mut SimFlash<N>, states: &[Status]) -> Vec<Vec<u8>> {
+            let map = SlotMap::<24>::build(flash, 0, 16, N / 16).unwrap();
+            let mut tasks = Vec::new();
+            for address in map.records() {
+                let mut raw = [0u8; fp::RECORD_BYTES];
+                let Some(len) = read_record(flash, address, &mut raw).unwrap() else {
+                    continue;
+                };
+                let status = Status::from_byte(raw[fp::STATUS_INDEX]);
+                let crc_ok = fp::Task::from_record(&raw[..len]).is_ok();
+                if states.contains(&status) && (status != Status::Writing || crc_ok) {
+                    raw[fp::STATUS_INDEX] &= 0b11000000;
+                    tasks.push(raw[..len].to_vec());
+                }
+            }
+            tasks.sort();
+            tasks
+        }
+
+        #[test]
+        fn every_combination_of_record_states() {
+            //Two records in each of two sectors, every status in every record.
+            const RECORDS: [(u32, usize); 4] = [(0x000, 2), (0x020, 30), (0x1000, 0), (0x1010, 7)];
+            for n in 0..STATES.len().pow(RECORDS.len() as u32) {
+                let mut flash = Sim::new();
+                let mut states = [Status::Empty; RECORDS.len()];
+                let mut units = [0; RECORDS.len()];
+                for (i, (address, frames)) in RECORDS.iter().enumerate() {
+                    states[i] = STATES[n / STATES.len().pow(i as u32) % STATES.len()];
+                    units[i] = program(&mut flash, *address, *frames, states[i]);
+                }
+                let before = flash.as_slice().to_vec();
+                let scheduled = tasks(&mut flash, &[Status::Scheduled]);
+                let mut map = SlotMap::<16>::build(&mut flash, 0, 16, 512).unwrap();
+
+                //A task in the middle of a step keeps its sector from being collected.
+                let blocks = |s: &Status| !s.is_reclaimable() && *s != Status::Scheduled;
+                collect_all(
+                    &mut Exclusive(&mut flash),
+                    &mut Exclusive(&mut map),
+                    &mut dma(),
+                )
+                .unwrap();
+                for (i, (address, _)) in RECORDS.iter().enumerate() {
+                    let record = *address as usize..*address as usize + units[i] * 16;
+                    if states[i] != Status::Empty && blocks(&states[i]) {
+                        assert_eq!(flash.as_slice()[record.clone()], before[record]);
+                    }
+                    //Only a sector with such a task keeps reclaimable records.
+                    if map.is_reclaimable(*address) {
+                        assert!(states[i / 2 * 2..][..2].iter().any(blocks), "{:?}", states);
+                    }
+                }
+                assert_eq!(
+                    tasks(&mut flash, &[Status::Scheduled]),
+                    scheduled,
+                    "{:?}",
+                    states
+                );
+                //The map agrees with the flash, and there is nothing left to do.
+                assert_eq!(map, SlotMap::build(&mut flash, 0, 16, 512).unwrap());
+                assert_eq!(
+                    collect_all(
+                        &mut Exclusive(&mut flash),
+                        &mut Exclusive(&mut map),
+                        &mut dma()
+                    ),
+                    Ok(0)
+                );
+            }
+        }
+
+        //Steps until the sector is erased or nothing is done, counting the tasks copied.
+        fn run(
+            flash: &mut SimFlash<0x3000>,
+            map: &mut SlotMap<24>,
+            low_water: usize,
+        ) -> (usize, Step) {
+            let (mut moved, mut dma) = (0, dma());
+            loop {
+                match step(
+                    &mut Exclusive(&mut *flash),
+                    &mut Exclusive(&mut *map),
+                    &mut dma,
+                    low_water,
+                )
+                .unwrap()
+                {
+                    Step::Moved(_) => moved += 1,
+                    done => return (moved, done),
+                }
+            }
+        }
+
+        #[test]
+        fn step_collects_the_fullest_sector_below_low_water() {
+            //Records of two units, 128 in each of two sectors, the third kept empty.
+            let mut flash = SimFlash::<0x3000>::new();
+            for record in 0..256u32 {
+                let status = match record {
+                    3 | 9 => Status::Executed,
+                    133..=137 => Status::Reclaimable,
+                    _ => Status::Scheduled,
+                };
+                program(&mut flash, record * 0x20, 1, status);
+            }
+            let scheduled = tasks(&mut flash, &[Status::Scheduled]);
+            let mut map = SlotMap::<24>::build(&mut flash, 0, 16, 768).unwrap();
+            assert_eq!(
+                step(
+                    &mut Exclusive(&mut flash),
+                    &mut Exclusive(&mut map),
+                    &mut dma(),
+                    256
+                ),
+                Ok(Step::Idle),
+                "above low water"
+            );
+            assert_eq!(
+                step(
+                    &mut Exclusive(&mut flash),
+                    &mut Exclusive(&mut map),
+                    &mut dma(),
+                    257
+                ),
+                Ok(Step::Moved(0x2000))
+            );
+            assert_eq!(run(&mut flash, &mut map, 257), (122, Step::Erased(256)));
+            assert_eq!(map.empty_count(), 266);
+            assert_eq!(
+                step(
+                    &mut Exclusive(&mut flash),
+                    &mut Exclusive(&mut map),
+                    &mut dma(),
+                    267
+                ),
+                Ok(Step::Moved(0x1000))
+            );
+            assert_eq!(run(&mut flash, &mut map, 267), (125, Step::Erased(256)));
+            assert_eq!(
+                step(
+                    &mut Exclusive(&mut flash),
+                    &mut Exclusive(&mut map),
+                    &mut dma(),
+                    600
+                ),
+                Ok(Step::Idle),
+                "nothing left"
+            );
+            assert_eq!(map.scheduled().count(), 249);
+            assert_eq!(map.allocate(1), Some(0x1fc0));
+            assert_eq!(tasks(&mut flash, &[Status::Scheduled]), scheduled);
+            assert_eq!(map, SlotMap::build(&mut flash, 0, 16, 768).unwrap());
+        }
+
+        #[test]
+        fn live_records_are_kept_whatever_the_map_says() {
+            let mut flash = Sim::new();
+            program(&mut flash, 0x000, 1, Status::Scheduled);
+            program(&mut flash, 0x020, 1, Status::Writing); //Schedule that failed its read back
+            flash.write(0x040, &[0, 0, 0xff]).unwrap(); //Torn
+            program(&mut flash, 0x1000, 1, Status::Executing);
+            program(&mut flash, 0x1020, 1, Status::Executed);
+            let scheduled = tasks(&mut flash, &[Status::Scheduled]);
+            let before = flash.as_slice().to_vec();
+            let mut map = SlotMap::<16>::build(&mut flash, 0, 16, 512).unwrap();
+            for address in [0x000, 0x020, 0x1000] {
+                map.set(address, Status::Reclaimable);
+            }
+            //The scheduled task is copied out first, the executing one keeps its sector.
+            assert_eq!(
+                collect_all(
+                    &mut Exclusive(&mut flash),
+                    &mut Exclusive(&mut map),
+                    &mut dma()
+                ),
+                Ok(5)
+            );
+            assert_eq!(flash.as_slice()[..0x1000], [0xff; 0x1000]);
+            assert_eq!(flash.as_slice()[0x1000..0x1040], before[0x1000..0x1040]);
+            assert!(map.scheduled().eq([0x1040]));
+            assert_eq!(tasks(&mut flash, &[Status::Scheduled]), scheduled);
+            assert!(!map.is_reclaimable(0x1000));
+            assert!(map.reclaimable().eq([0x1020]));
+        }
+
+        //The map as a task of higher priority leaves it: the task at address is started
+        //when the map is locked for the given time.
+        struct Preempted<'a> {
+            map: &'a mut SlotMap<16>,
+            locks: usize,
+            address: u32,
+        }
+
+        impl Mutex for Preempted<'_> {
+            type T = SlotMap<16>;
+            fn lock<R>(&mut self, f: impl FnOnce(&mut SlotMap<16>) -> R) -> R {
+                self.locks -= 1;
+                if self.locks == 0 {
+                    self.map.set(self.address, Status::Executing);
+                }
+                f(self.map)
+            }
+        }
+
+        #[test]
+        fn task_started_during_its_copy_is_not_moved() {
+            let mut flash = Sim::new();
+            program(&mut flash, 0x000, 1, Status::Scheduled);
+            program(&mut flash, 0x020, 1, Status::Executed);
+            let mut map = SlotMap::<16>::build(&mut flash, 0, 16, 512).unwrap();
+            //Started between the copy and the final lock.
+            let mut slots = Preempted {
+                map: &mut map,
+                locks: 2,
+                address: 0x000,
+            };
+            let freed = collect_sector(&mut Exclusive(&mut flash), &mut slots, &mut dma(), 0);
+            assert_eq!(freed, Ok(0));
+            assert!(map.reclaimable().eq([0x020, 0x1000]));
+            assert!(!map.is_scheduled(0x000));
+            let copy = SlotMap::<16>::build(&mut flash, 0, 16, 512).unwrap();
+            assert!(copy.is_reclaimable(0x1000));
+        }
+
+        //Scheduled and executed tasks in both sectors, so both are collected.
+        fn plan() -> Sim {
+            let mut flash = Sim::new();
+            let records = [
+                (0x000, 3, Status::Scheduled),
+                (0x040, 1, Status::Executed),
+                (0x060, 30, Status::Scheduled),
+                (0x160, 0, Status::Quarantined),
+                (0x170, 2, Status::Scheduled),
+                (0x1000, 1, Status::Scheduled),
+                (0x1020, 5, Status::Executed),
+            ];
+            for (address, frames, status) in records {
+                program(&mut flash, address, frames, status);
+            }
+            flash
+        }
+
+        //Collects every sector until the power is cut.
+        fn collect(flash: &mut PowerCut<Sim>) {
+            let Ok(mut map) = SlotMap::<16>::build(flash, 0, 16, 512) else {
+                return;
+            };
+            let mut dma = dma();
+            while let Ok(Step::Moved(_) | Step::Erased(_)) = step(
+                &mut Exclusive(&mut *flash),
+                &mut Exclusive(&mut map),
+                &mut dma,
+                usize::MAX,
+            ) {}
+        }
+
+        //Every possible reset point: after the copies are dropped, each task is scheduled, or
+        //left as Writing with a good CRC for id_manager::recover to schedule, exactly once.
+        #[test]
+        fn reset_at_any_point_keeps_every_task() {
+            let mut full = PowerCut::new(plan(), usize::MAX);
+            let scheduled = tasks(&mut full.flash, &[Status::Scheduled]);
+            collect(&mut full);
+            assert_eq!(tasks(&mut full.flash, &[Status::Scheduled]), scheduled);
+            let total = full.used;
+            for budget in 0..total {
+                let mut cut = PowerCut::new(plan(), budget);
+                collect(&mut cut);
+                let mut flash = cut.flash;
+                let mut map = SlotMap::<16>::build(&mut flash, 0, 16, 512).unwrap();
+                drop_copies(&mut flash, &mut map).unwrap();
+                let live = tasks(&mut flash, &[Status::Scheduled, Status::Writing]);
+                assert_eq!(live, scheduled, "reset after {} of {} steps", budget, total);
+
+                //As recover leaves it, the collection then finishes.
+                for address in map.records().collect::<Vec<_>>() {
+                    let mut raw = [0u8; fp::RECORD_BYTES];
+                    let len = read_record(&mut flash, address, &mut raw).unwrap();
+                    let writing = Status::from_byte(raw[fp::STATUS_INDEX]) == Status::Writing;
+                    if writing && len.is_some_and(|len| fp::Task::from_record(&raw[..len]).is_ok())
+                    {
+                        let status = address + fp::STATUS_INDEX as u32;
+                        flash
+                            .write(status, &[Status::Scheduled.program_byte()])
+                            .unwrap();
+                    } else if !map.is_scheduled(address) {
+                        let status = address + fp::STATUS_INDEX as u32;
+                        flash
+                            .write(status, &[Status::Reclaimable.program_byte()])
+                            .unwrap();
+                    }
+                }
+                let mut map = SlotMap::<16>::build(&mut flash, 0, 16, 512).unwrap();
+                collect_all(
+                    &mut Exclusive(&mut flash),
+                    &mut Exclusive(&mut map),
+                    &mut dma(),
+                )
+                .unwrap();
+                assert_eq!(map.reclaimable().count(), 0);
+                assert_eq!(tasks(&mut flash, &[Status::Scheduled]), scheduled);
+            }
+            println!("{} reset points checked", total);
+        }
+    }
+}
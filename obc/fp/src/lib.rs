@@ -1,4 +1,6 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
+//Every module file wraps its items in a module of the same name.
+#![allow(clippy::module_inception)]
 use defmt as _;
 use defmt_rtt as _; // global logger
 use fugit as _;
@@ -9,3 +11,8 @@ use stm32f4xx_hal as _; // memory layout // time abstractions
 pub mod excan;
 pub mod exrtc;
 pub mod flightplanner;
+pub mod fpconfig;
+pub mod dma;
+pub mod gc;
+pub mod slots;
+pub mod superblock;
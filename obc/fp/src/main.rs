@@ -1,5 +1,7 @@
 #![no_main]
 #![no_std]
+//A failed spawn hands back its message, for most tasks 32 CAN frames.
+#![allow(clippy::result_large_err)]
 
 //use stm32f446_rtic as _; // global logger + panicking-behavior + memory layout
 use rtic_playtime::{self as _}; // global logger + panicking-behavior + memory layout
@@ -10,21 +12,72 @@ mod app {
     use crate::id_manager::{self, FP_task_id_manager};
 
     extern "Rust" {
-        #[task(shared = [flash, next_address_id],priority=2)]
-        fn FP_task_id_manager(_ctx: FP_task_id_manager::Context);
+        #[task(shared = [flash, next_address_id, slots], local = [dma_id_manager], priority=2)]
+        fn FP_task_id_manager(_ctx: FP_task_id_manager::Context, units: usize);
     }
 
-    //The size of a task in bytes in memory
-    pub const TASK_SIZE: u32 = 256; //in bytes
-    pub const MAX_NR_OF_TASKS: usize = 48; //3 sectors
-    pub const FP_START_ID: u32 = 0x000000; //Start ID og the FP
+    //Default layout of the FP, unless the configuration holds another (Key::FlightPlan).
+    //The FP is split in units, a task takes as many as its record needs (see fp::record_len):
+    //one for a task without payload, 17 for a task of 31 frames. 3 sectors.
+    pub const FP_CONFIG: FlightPlanConfig = FlightPlanConfig {
+        start: 0x000000,
+        unit_size: 16,
+        unit_count: 768,
+    };
+    //Tasks a reset left in the middle of a step that are logged at boot, the rest are only recovered
+    pub const RECOVER_LOG_MAX: usize = 16;
+
+    //Index of every task record in RAM, one bit per unit and state. Sets the largest FP.
+    pub const SLOT_WORDS: usize = 64;
+    pub type Slots = SlotMap<SLOT_WORDS>;
+
+    //Sectors of the FP that fail to erase or program are moved to the spares after it.
+    //The bad sector table, FP superblock, configuration and event log are not moved with the FP.
+    pub const FP_BAD_SECTORS: usize = 36; //FP and spare sectors the table can map
+    pub const FP_LIMITS: Limits = Limits {
+        units: SLOT_WORDS * 32,
+        sectors: FP_BAD_SECTORS,
+        spares: 4,
+        table: 0x7000,
+        reserved: 0x7000..LOG_START + LOG_SECTORS * 0x1000,
+    };
+    //Task reads and GC copies are DMA transfers (see rtic_playtime::dma), with the lock only
+    //held to start and poll them. Every other access holds the lock until it is done.
+    //SPI1_RX is DMA2 stream 0, SPI1_TX is DMA2 stream 3.
+    type FpFlash = BadSectors<Memory<DmaHalDevice<SPI1, 'B', 6, PushPull, Stream0<DMA2>, Stream3<DMA2>>>, FP_BAD_SECTORS>;
+    //Tasks that read the FP by DMA, each with its own buffer.
+    const DMA_TASKS: usize = 6;
+
+    //Layout of the FP, checked against the superblock in the two sectors after the bad sector table.
+    pub const FP_SUPERBLOCK: u32 = 0x8000;
+
+    //Configuration items in the key-value store, in the 64K block after the FP.
+    pub const KV_START: u32 = 0x10000;
+    #[derive(Clone, Copy)]
+    pub enum Key {
+        TransmitterId = 0, //CAN node ID, 1 byte
+        CanBitTiming = 1,  //bxcan BTR value, 4 bytes
+        RtcTime = 2,       //Last stored RTC time (unix), 8 bytes
+        BootCount = 3,     //4 bytes
+        FirstAlarm = 4,    //First RTC alarm (unix), 4 bytes
+        FlightPlan = 5,    //FP layout, fpconfig::FlightPlanConfig, 8 bytes
+    }
 
-    //Sets up the stuff above for flash
-    #[derive(defmt::Format)]
-    pub enum FpConfig {
-        TaskSize = 256,
-        TaskNum = MAX_NR_OF_TASKS as isize,
-        StartAddress = 0x0,
+    //Event log for port 4, in the 64K block after the configuration.
+    pub const LOG_START: u32 = 0x20000;
+    pub const LOG_SECTORS: u32 = 16;
+    pub const LOG_DOWNLOAD_MAX: usize = 10; //Events per download reply, 3 frames each
+
+    //Reads a configuration item, or the default if it is missing or can not be read.
+    fn stored<const L: usize>(
+        kv: &Option<KvStore>,
+        flash: &mut impl NorFlash,
+        key: Key,
+        default: [u8; L],
+    ) -> [u8; L] {
+        kv.as_ref()
+            .and_then(|kv| kv.get_array(flash, key as u8).ok().flatten())
+            .unwrap_or(default)
     }
 
     //START OF RTIC CODE!
@@ -32,41 +85,49 @@ mod app {
     use bxcan::Fifo;
     use dwt_systick_monotonic::{DwtSystick, ExtU32};
 
+    use flash::bad::BadSectors;
+    use flash::kv::KvStore;
+    use flash::log::EventLog;
+    use flash::stm32::{DmaHalDevice, HalDevice};
     use flash::w25q128::Memory;
+    use flash::NorFlash;
     use heapless::Vec;
+    use rtic::{Exclusive, Mutex};
+    use rtic_playtime::dma::dma::Dma;
     use rtic_playtime::excan::excan::{self as ec};
     use rtic_playtime::exrtc::exrtc::{self as er};
     use rtic_playtime::flightplanner::flightplanner::{self as fp};
+    use rtic_playtime::fpconfig::fpconfig::{FlightPlanConfig, Limits};
+    use rtic_playtime::gc::gc;
+    use rtic_playtime::slots::slots::SlotMap;
+    use rtic_playtime::superblock::superblock::{self, Superblock};
+    use stm32f4xx_hal::dma::{Stream0, Stream3, StreamsTuple};
     use stm32f4xx_hal::gpio::PushPull;
     use stm32f4xx_hal::{
         can::Can,
-        gpio::{
-            gpioa::{PA11, PA12, PA5, PA6, PA7},
-            Alternate,
-        },
         pac::CAN1,
+        pac::DMA2,
         pac::SPI1,
         prelude::*,
         rtc::Rtc,
         {self as hal},
     };
-    use time::macros::{date, time};
+    use time::{OffsetDateTime, PrimitiveDateTime};
 
     #[monotonic(binds = SysTick, default = true)]
     type MyMono = DwtSystick<180_000_000>; // 180 MHz
 
     #[shared]
     struct Shared {
-        can1: bxcan::Can<Can<CAN1, (PA12<Alternate<9>>, PA11<Alternate<9>>)>>,
+        can1: bxcan::Can<Can<CAN1>>,
         first_five: fp::FirstFive,
         next_address_id: Result<u32, id_manager::Error>, //@TODO: Overtages af mem
-        flash: Memory<
-            SPI1,
-            (PA5<Alternate<5>>, PA6<Alternate<5>>, PA7<Alternate<5>>),
-            'B',
-            6,
-            PushPull,
-        >,
+        flash: FpFlash,
+        kv: Option<KvStore>, //Configuration items, None if the flash could not be read
+        log: Option<EventLog>, //Event log, None if the flash could not be read
+        plan: Result<Superblock, superblock::Error>, //FP commands are refused on Err
+        fp_config: FlightPlanConfig, //Layout of the FP, checked against the flash at boot
+        slots: Slots, //Index of every task record, kept in step with the flash
         rtc: er::RTCSTRUCT,
         can_reply: u8, // mutex for can replys to tasks
     }
@@ -77,10 +138,17 @@ mod app {
         can_output: Vec<[u8; 8], 32>, //Stores outgoing messages over multiple frames
         fragment_count: u8,          //Counts the number of frames in a message
         current_alarm_time: i32,
+        //DMA buffers for the flash, see read_task
+        dma_idle: Dma,
+        dma_request_schedule: Dma,
+        dma_request_ff: Dma,
+        dma_sort_full: Dma,
+        dma_execute: Dma,
+        dma_id_manager: Dma,
     }
 
     // The init function is called in the beginning of the program
-    #[init]
+    #[init(local = [dma_buffers: [[u8; fp::RECORD_BYTES]; DMA_TASKS] = [[0; fp::RECORD_BYTES]; DMA_TASKS]])]
     fn init(ctx: init::Context) -> (Shared, Local, init::Monotonics) {
         defmt::debug!("init");
         // Cortex-M peripherals
@@ -118,43 +186,6 @@ mod app {
         let gpioa = _device.GPIOA.split();
         let gpiob = _device.GPIOB.split();
 
-        /**********************************************************************
-        CAN SETUP
-        ***********************************************************************/
-        let mut can1 = {
-            // CAN pins alternate function 9 as per datasheet
-            // https://www.st.com/resource/en/datasheet/stm32f446mc.pdf page 57
-            let rx = gpioa.pa11.into_alternate::<9>();
-            let tx = gpioa.pa12.into_alternate::<9>();
-
-            let can = _device.CAN1.can((tx, rx));
-
-            defmt::debug!("CAN1, waiting for 11 recessive bits...");
-            bxcan::Can::builder(can)
-                // APB1 (PCLK1): 45MHz, Bit rate: 1MBit/s, Sample Point 87.5%
-                // Value was calculated with http://www.bittiming.can-wiki.info/
-                //.set_bit_timing(0x001b0002)
-                .set_bit_timing(0x00390002)
-                .set_automatic_retransmit(true)
-                .enable()
-        };
-        defmt::debug!("CAN1, waiting for 11 recessive bits... (done)");
-
-        can1.enable_interrupts({
-            use bxcan::Interrupts as If;
-            If::FIFO0_MESSAGE_PENDING | If::FIFO0_FULL | If::FIFO0_OVERRUN
-        });
-
-        // Configure filters so that can frames can be received - should be configured for
-        can1.modify_filters()
-            .enable_bank(0, Fifo::Fifo0, Mask32::accept_all());
-        let can_input = Vec::<[u8; 8], 32>::new();
-        let can_output = Vec::<[u8; 8], 32>::new();
-
-        /**********************************************************************
-        END OF CAN SETUP
-        ***********************************************************************/
-
         /**********************************************************************
         MEM SETUP
         ***********************************************************************/
@@ -186,29 +217,204 @@ mod app {
             10.MHz(),           //Setting clock
             &clocks,            //Give a reference to system clocks.
         );
-        let flash = Memory::new_w25q128(spi, cs);
+        let streams = StreamsTuple::new(_device.DMA2);
+        let device = DmaHalDevice::new(HalDevice::new(spi, cs), streams.0, streams.3);
+        //Detect a missing or swapped chip at boot. Without a known chip the
+        //W25Q128 geometry is kept, and memory requests will be NAK'ed.
+        let mut memory = match Memory::probe(device) {
+            Ok(flash) => {
+                defmt::info!("Flash found: {} Mbit", flash.get_info().capacity_mbit);
+                flash
+            }
+            Err((e, spi)) => {
+                defmt::error!("Flash not detected: {}", e);
+                Memory::new_w25q128_device(spi)
+            }
+        };
+        //Configuration items stored on the flash, with defaults for missing ones.
+        //Mounted before the bad sector layer, which is set up from the FP layout stored in it.
+        let mut kv = match KvStore::mount(&mut memory, KV_START) {
+            Ok(kv) => Some(kv),
+            Err(e) => {
+                defmt::error!("Configuration not loaded: {}", e);
+                None
+            }
+        };
+        let info = *memory.get_info();
+        let stored_config = stored(&kv, &mut memory, Key::FlightPlan, FP_CONFIG.to_bytes());
+        let fp_config = match FlightPlanConfig::from_bytes(&stored_config) {
+            config if config == FP_CONFIG => FP_CONFIG,
+            config => match config.validate(&info, &FP_LIMITS) {
+                Ok(()) => config,
+                Err(e) => {
+                    defmt::error!("Stored FP layout {} refused: {}, default used", config, e);
+                    FP_CONFIG
+                }
+            },
+        };
+        let fp_valid = fp_config.validate(&info, &FP_LIMITS);
+        defmt::info!("FP layout: {}", fp_config);
+
+        #[allow(unused_mut)]
+        let mut flash = BadSectors::new(memory, fp_config.bad_sectors(&info, &FP_LIMITS));
+        match flash.load() {
+            Ok(()) if flash.bad_count() > 0 => defmt::warn!(
+                "{} bad sectors, {} spares left",
+                flash.bad_count(),
+                flash.spares_left()
+            ),
+            Ok(()) => {}
+            Err(e) => defmt::error!("Bad sector table not loaded: {}", e),
+        }
 
         #[cfg(feature = "clean")]
-        flash.delete(Flash::w25q128::Delete::BlockErase64, 0x00);
+        flash.delete(flash::w25q128::Delete::BlockErase64, 0x00).unwrap();
+
+        let transmitter_id = stored(&kv, &mut flash, Key::TransmitterId, [1])[0];
+        ec::set_transmitter_id(transmitter_id);
+        let bit_timing = u32::from_be_bytes(stored(
+            &kv,
+            &mut flash,
+            Key::CanBitTiming,
+            0x00390002u32.to_be_bytes(),
+        ));
+        let last_time = i64::from_be_bytes(stored(&kv, &mut flash, Key::RtcTime, [0; 8]));
+        let first_alarm =
+            i32::from_be_bytes(stored(&kv, &mut flash, Key::FirstAlarm, 50i32.to_be_bytes()));
+        let boot_count = u32::from_be_bytes(stored(&kv, &mut flash, Key::BootCount, [0; 4])) + 1;
+        if let Some(kv) = kv.as_mut() {
+            kv.set(&mut flash, Key::BootCount as u8, &boot_count.to_be_bytes()).ok();
+        }
+        defmt::info!("Boot {}, transmitter ID {}", boot_count, transmitter_id);
+
+        let mut log = match EventLog::mount(&mut flash, LOG_START, LOG_SECTORS) {
+            Ok(log) => Some(log),
+            Err(e) => {
+                defmt::error!("Event log not loaded: {}", e);
+                None
+            }
+        };
+        if let Some(log) = log.as_mut() {
+            //Boot event, kind 0 with the boot count.
+            let mut data = [0u8; 8];
+            data[..4].copy_from_slice(&boot_count.to_be_bytes());
+            log.append(&mut flash, last_time as u32, transmitter_id, 0, data).ok();
+        }
+
+        //Check the layout of the FP before any task is read.
+        let plan = fp_valid
+            .map_err(superblock::Error::Config)
+            .and_then(|()| superblock::mount(&mut flash, FP_SUPERBLOCK, &fp_config.layout()))
+            .map(|(block, migrated)| {
+                if migrated {
+                    defmt::warn!("Flight plan migrated to version {}", block.version);
+                }
+                block
+            });
+        if let Err(e) = plan {
+            defmt::error!("Flight plan refused: {}", e);
+            if let Some(log) = log.as_mut() {
+                //Event kind 2: flight plan refused, with the frame sent to ground
+                let reply = plan_nak(e);
+                let data = reply.last().copied().unwrap_or_default();
+                log.append(&mut flash, last_time as u32, transmitter_id, 2, data).ok();
+            }
+            can_send::spawn(3, 2, 0, 0, plan_nak(e), true).ok();
+        }
+
+        //Read once, every status change after this goes through the map as well.
+        //No units while the plan is refused.
+        let mut slots = match plan {
+            Ok(_) => build_slots(&mut flash, &fp_config).unwrap_or_else(|e| {
+                defmt::error!("Slot map could not be read: {}", e);
+                Slots::new(fp_config.start, fp_config.unit_size as u32, 0, info.sector_size)
+            }),
+            Err(_) => Slots::new(fp_config.start, fp_config.unit_size as u32, 0, info.sector_size),
+        };
+
+        //Tasks a reset left in the middle of a step, before the first five is built from them.
+        if plan.is_ok() {
+            match gc::drop_copies(&mut flash, &mut slots) {
+                Ok(0) => {}
+                Ok(n) => defmt::warn!("{} tasks were copied twice by the GC", n),
+                Err(e) => defmt::error!("GC copies could not be checked: {}", e),
+            }
+            match id_manager::recover::<_, RECOVER_LOG_MAX>(&mut flash, &mut slots) {
+                Ok(recovered) => {
+                    for r in recovered {
+                        defmt::warn!("Task {:x} recovered: {} -> {}", r.address, r.from, r.to);
+                        //Event kind 3: recovered task | 4B address | old status | new status | 0 | 0 |
+                        let a = r.address.to_be_bytes();
+                        let data = [a[0], a[1], a[2], a[3], r.from.bits(), r.to.bits(), 0, 0];
+                        if let Some(log) = log.as_mut() {
+                            log.append(&mut flash, last_time as u32, transmitter_id, 3, data).ok();
+                        }
+                    }
+                }
+                Err(e) => defmt::error!("Task recovery failed: {}", e),
+            }
+        }
         /**********************************************************************
         END OF MEM SETUP
         ***********************************************************************/
+
+        /**********************************************************************
+        CAN SETUP
+        ***********************************************************************/
+        let mut can1 = {
+            // CAN pins alternate function 9 as per datasheet
+            // https://www.st.com/resource/en/datasheet/stm32f446mc.pdf page 57
+            let rx = gpioa.pa11.into_alternate::<9>();
+            let tx = gpioa.pa12.into_alternate::<9>();
+
+            let can = _device.CAN1.can((tx, rx));
+
+            defmt::debug!("CAN1, waiting for 11 recessive bits...");
+            bxcan::Can::builder(can)
+                // APB1 (PCLK1): 45MHz, Bit rate: 1MBit/s, Sample Point 87.5%
+                // Value was calculated with http://www.bittiming.can-wiki.info/
+                //.set_bit_timing(0x001b0002)
+                .set_bit_timing(bit_timing)
+                .set_automatic_retransmit(true)
+                .enable()
+        };
+        defmt::debug!("CAN1, waiting for 11 recessive bits... (done)");
+
+        can1.enable_interrupts({
+            use bxcan::Interrupts as If;
+            If::FIFO0_MESSAGE_PENDING | If::FIFO0_FULL | If::FIFO0_OVERRUN
+        });
+
+        // Configure filters so that can frames can be received - should be configured for
+        can1.modify_filters()
+            .enable_bank(0, Fifo::Fifo0, Mask32::accept_all());
+        let can_input = Vec::<[u8; 8], 32>::new();
+        let can_output = Vec::<[u8; 8], 32>::new();
+
+        /**********************************************************************
+        END OF CAN SETUP
+        ***********************************************************************/
+
         //Sets up the first five vector - used for keeping track on the next upcoming tasks.
         let first_five = fp::FirstFive::new();
 
         // RTC SETUPS
         let mut rtc = Rtc::new_lsi(_device.RTC, &mut _device.PWR);
-        rtc.set_date(&date!(1970 - 01 - 01)).unwrap();
-        rtc.set_time(&time!(00:00:00)).unwrap();
+        //Continue from the last stored time instead of 1970.
+        let start =
+            OffsetDateTime::from_unix_timestamp(last_time).unwrap_or(OffsetDateTime::UNIX_EPOCH);
+        rtc.set_datetime(&PrimitiveDateTime::new(start.date(), start.time())).unwrap();
 
         //Configures the first alarm
-        let first_alarm: i32 = 50;
         let current_alarm_time = first_alarm;
         //Inistialises the alarm part of the RTC
         let rtc = er::RTCSTRUCT::new(_device.EXTI, _device.PWR, rtc, first_alarm);
 
         //Finally, the initialization of the first five vector.
-        FP_sort_first_five_full::spawn().ok();
+        if plan.is_ok() {
+            FP_sort_first_five_full::spawn().ok();
+        }
+        let [idle, request_schedule, request_ff, sort_full, execute, id_manager] = ctx.local.dma_buffers;
         defmt::debug!("Init done!");
         ping::spawn().ok();
         (
@@ -217,6 +423,11 @@ mod app {
                 first_five,
                 next_address_id: Result::Ok(0),
                 flash,
+                kv,
+                log,
+                plan,
+                slots,
+                fp_config,
                 rtc,
                 can_reply: 0,
             },
@@ -225,25 +436,51 @@ mod app {
                 can_output,
                 fragment_count: 0,
                 current_alarm_time,
+                dma_idle: Dma::new(idle),
+                dma_request_schedule: Dma::new(request_schedule),
+                dma_request_ff: Dma::new(request_ff),
+                dma_sort_full: Dma::new(sort_full),
+                dma_execute: Dma::new(execute),
+                dma_id_manager: Dma::new(id_manager),
             },
             init::Monotonics(mono),
         )
     }
 
     // The idle function is called when there is nothing else to do
-    #[idle]
-    fn idle(_: idle::Context) -> ! {
+    #[idle(shared = [flash, slots], local = [dma_idle])]
+    fn idle(mut ctx: idle::Context) -> ! {
         loop {
-            continue;
+            //Reclaim executed tasks a sector at a time while the plan runs low on empty slots.
+            //Collection starts when fewer units than a sector's worth are empty, besides the
+            //sector kept for the collection. One copy or erase per step, see gc::step.
+            let low_water = ctx.shared.slots.lock(|s| 2 * (s.sector_size() / s.unit_size()) as usize);
+            match gc::step(&mut ctx.shared.flash, &mut ctx.shared.slots, ctx.local.dma_idle, low_water) {
+                Ok(gc::Step::Moved(_)) => {
+                    FP_sort_first_five_full::spawn().ok();
+                }
+                Ok(_) => {}
+                Err(e) => defmt::error!("GC: Flash error: {}", e),
+            }
+            //Keep the flash in deep power-down between operations, tasks wake it on access.
+            ctx.shared.flash.lock(|f| f.flash().power_down()).ok();
         }
     }
 
     // The task functions are called by the scheduler
-    #[task(shared = [rtc])]
+    #[task(shared = [rtc, flash, kv])]
     fn ping(_ctx: ping::Context) {
         let mut rtc = _ctx.shared.rtc;
         let time = rtc.lock(|r| r.get_time(false));
         defmt::debug!("RTC: {}", time);
+        //Once a minute, so the time survives a reset.
+        if time % 60 < 5 {
+            (_ctx.shared.flash, _ctx.shared.kv).lock(|f, kv| {
+                if let Some(kv) = kv.as_mut() {
+                    kv.set(f, Key::RtcTime as u8, &time.to_be_bytes()).ok();
+                }
+            });
+        }
         ping::spawn_after(5.secs()).ok();
     }
 
@@ -366,7 +603,10 @@ mod app {
                         Ok(_) => defmt::debug!("Flight Planner task spawned"),
                     },
                     //Port 4: Log
-                    4 => defmt::debug!("Log not implemented"),
+                    4 => match Log::spawn(frame_id, can_input.clone()) {
+                        Err(_) => defmt::error!("Log task not spawned"),
+                        Ok(_) => defmt::debug!("Log task spawned"),
+                    },
                     //Port 5: RTC
                     5 => match RTC_get_time::spawn() {
                         Err(_) => defmt::error!("RTC task not spawned"),
@@ -401,12 +641,110 @@ mod app {
         can_send::spawn(3, 2, 0, 0, data, true).ok();
     }
 
-    #[task(priority = 3, capacity = 3)] //Determines command and sends it to the right task
+    #[task(priority = 3, capacity = 3)] //Determines the log command and sends it to the right task
+    fn Log(_ctx: Log::Context, frame_id: ec::IdentifierContents, data: Vec<[u8; 8], 32>) {
+        match frame_id.cmd {
+            //CMD 0: Event, CMD 1: Error - every frame is stored as one record
+            0 | 1 => log_write::spawn(frame_id.trans, frame_id.cmd, data).ok(),
+            //CMD 2: Download - | 4B first sequence number | 1B count |
+            2 => {
+                let seq = u32::from_be_bytes([data[0][0], data[0][1], data[0][2], data[0][3]]);
+                log_download::spawn(frame_id.trans, seq, data[0][4]).ok()
+            }
+            //CMD 3: Status - oldest and next sequence number
+            3 => log_status::spawn(frame_id.trans).ok(),
+            //CMD 4-255: Not implemented
+            _ => {
+                defmt::debug!("CMD {} has not been implemented", frame_id.cmd);
+                None
+            }
+        };
+    }
+
+    #[task(shared = [rtc, flash, log], priority = 2)] //Stores frames in the event log
+    fn log_write(ctx: log_write::Context, source: u8, kind: u8, data: Vec<[u8; 8], 32>) {
+        let mut rtc = ctx.shared.rtc;
+        let time = rtc.lock(|r| r.get_time(false)) as u32;
+        let result = (ctx.shared.flash, ctx.shared.log).lock(|f, log| {
+            let log = log.as_mut().ok_or(flash::Error::NoDevice)?;
+            let mut seq = 0;
+            for frame in data.iter() {
+                seq = log.append(f, time, source, kind, *frame)?;
+            }
+            Ok(seq)
+        });
+        let reply = match result {
+            Ok(seq) => {
+                let s = seq.to_be_bytes();
+                let mut reply = Vec::<[u8; 8], 32>::new();
+                reply.push([0x06, 0, 0, 0, s[0], s[1], s[2], s[3]]).ok(); //ACK, last sequence number
+                reply
+            }
+            Err(e) => flash_nak(e),
+        };
+        can_send::spawn(3, source, 0, 0, reply, true).ok();
+    }
+
+    //Reply per event: | 4B seq | 4B time |, | source | kind | 6B 0 |, | 8B data |
+    #[task(shared = [flash, log], priority = 2)]
+    fn log_download(ctx: log_download::Context, receiver: u8, seq: u32, count: u8) {
+        let mut reply = Vec::<[u8; 8], 32>::new();
+        let result = (ctx.shared.flash, ctx.shared.log).lock(|f, log| {
+            let log = log.as_ref().ok_or(flash::Error::NoDevice)?;
+            let mut next = seq;
+            for _ in 0..(count as usize).min(LOG_DOWNLOAD_MAX) {
+                let event = match log.read_from(f, next)? {
+                    Some(event) => event,
+                    None => break,
+                };
+                let (s, t) = (event.seq.to_be_bytes(), event.time.to_be_bytes());
+                reply.push([s[0], s[1], s[2], s[3], t[0], t[1], t[2], t[3]]).ok();
+                reply.push([event.source, event.kind, 0, 0, 0, 0, 0, 0]).ok();
+                reply.push(event.data).ok();
+                next = event.seq + 1;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            reply = flash_nak(e);
+        } else if reply.is_empty() {
+            reply.push([0x15, 0x4E, 0x6F, 0x45, 0x76, 0x65, 0x6E, 0x74]).ok(); //NAK "NoEvent"
+        }
+        can_send::spawn(3, receiver, 0, 0, reply, true).ok();
+    }
+
+    #[task(shared = [flash, log], priority = 2)] //Reply: | 4B oldest seq | 4B next seq |
+    fn log_status(ctx: log_status::Context, receiver: u8) {
+        let result = (ctx.shared.flash, ctx.shared.log).lock(|f, log| {
+            let log = log.as_ref().ok_or(flash::Error::NoDevice)?;
+            let next = log.next_seq();
+            Ok((log.oldest(f)?.unwrap_or(next), next))
+        });
+        let reply = match result {
+            Ok((oldest, next)) => {
+                let (o, n) = (oldest.to_be_bytes(), next.to_be_bytes());
+                let mut reply = Vec::<[u8; 8], 32>::new();
+                reply.push([o[0], o[1], o[2], o[3], n[0], n[1], n[2], n[3]]).ok();
+                reply
+            }
+            Err(e) => flash_nak(e),
+        };
+        can_send::spawn(3, receiver, 0, 0, reply, true).ok();
+    }
+
+    #[task(shared = [plan], priority = 3, capacity = 3)] //Determines command and sends it to the right task
     fn Flight_Planner(
-        _ctx: Flight_Planner::Context,
+        mut ctx: Flight_Planner::Context,
         frame_id: ec::IdentifierContents,
         data: Vec<[u8; 8], 32>,
     ) {
+        //A plan in an unknown layout is left alone until ground formats it.
+        if let Err(e) = ctx.shared.plan.lock(|plan| *plan) {
+            if frame_id.cmd != 5 {
+                can_send::spawn(3, 2, 0, 0, plan_nak(e), true).ok();
+                return;
+            }
+        }
         match frame_id.cmd {
             //CMD 0: Reply
             0 => FP_read_reply::spawn(data[0][0]).ok(),
@@ -432,8 +770,10 @@ mod app {
             //CMD 4: Delete
             4 => {
                 let address: u32 = u32::from_be_bytes([0, 0, data[0][0], data[0][1]]);
-                FP_delete_task::spawn(address, true).ok()
+                FP_delete_task::spawn(address, None).ok()
             }
+            //CMD 5: Format - first frame "FORMAT", drops every task
+            5 if data[0][..6] == *b"FORMAT" => FP_format::spawn().ok(),
             //CMD 3-255: Not implemented - try_into().ok() to
             _ => defmt::debug!("CMD {} has not been implemented", frame_id.cmd)
                 .try_into()
@@ -448,37 +788,118 @@ mod app {
         reply.lock(|can_reply| *can_reply = data);
     }
 
-    #[task(shared=[flash])] //Request Schedule
-    fn FP_request_schedule(ctx: FP_request_schedule::Context) {
-        defmt::debug!("Full schedule has been requested!");
+    //Reads the task record at address: the header for its length, then the record.
+    //Both by DMA, the flash is free for other tasks while they run.
+    fn read_task(
+        flash: &mut impl Mutex<T = FpFlash>,
+        dma: &mut Dma,
+        address: u32,
+    ) -> Result<Result<fp::Task, fp::CrcError>, flash::Error> {
+        let mut raw = [0u8; fp::RECORD_BYTES];
+        dma.read(flash, address, &mut raw[..fp::HEADER_BYTES])?;
+        let len = fp::record_len(raw[fp::DLC_INDEX]).unwrap_or(fp::HEADER_BYTES);
+        dma.read(flash, address, &mut raw[..len])?;
+        Ok(fp::Task::from_record(&raw[..len]))
+    }
+
+    //Index of the task records of the FP.
+    fn build_slots(flash: &mut FpFlash, config: &FlightPlanConfig) -> Result<Slots, flash::Error> {
+        Slots::build(flash, config.start, config.unit_size as u32, config.unit_count as usize)
+    }
+
+    //Reports a memory failure to ground instead of the data that was read.
+    fn flash_nak(e: flash::Error) -> Vec<[u8; 8], 32> {
+        defmt::error!("Flash error: {}", e);
+        let mut reply = Vec::<[u8; 8], 32>::new();
+        reply.push([0x15, 0x46, 0x6C, 0x61, 0x73, 0x68, 0x45, 0x72]).ok(); //NAK "FlashEr"
+        reply
+    }
+
+    //Reply for a refused plan: NAK "FPFrmat", then | version | 0 | 2B unit size | 2B unit count | 0 | 0 |
+    //with version 0xff if the superblock can not be read,
+    //or for a layout that does not fit the flash: NAK "FPCnfig", then | fpconfig::Error | 0.. |
+    fn plan_nak(e: superblock::Error) -> Vec<[u8; 8], 32> {
+        let block = match e {
+            superblock::Error::Flash(e) => return flash_nak(e),
+            superblock::Error::Config(e) => {
+                let mut reply = Vec::<[u8; 8], 32>::new();
+                reply.push([0x15, 0x46, 0x50, 0x43, 0x6E, 0x66, 0x69, 0x67]).ok(); //NAK "FPCnfig"
+                reply.push([e as u8, 0, 0, 0, 0, 0, 0, 0]).ok();
+                return reply;
+            }
+            superblock::Error::UnknownVersion(block) | superblock::Error::Geometry(block) => block,
+            superblock::Error::Unreadable => Superblock {
+                version: 0xff,
+                ..Superblock::new(0, 0, 0)
+            },
+        };
+        let (size, count) = (block.unit_size.to_be_bytes(), block.unit_count.to_be_bytes());
+        let mut reply = Vec::<[u8; 8], 32>::new();
+        reply.push([0x15, 0x46, 0x50, 0x46, 0x72, 0x6D, 0x61, 0x74]).ok(); //NAK "FPFrmat"
+        reply.push([block.version, 0, size[0], size[1], count[0], count[1], 0, 0]).ok();
+        reply
+    }
+
+    //Erase the FP and write a superblock for this build. Reply: ACK | 0 | 0 | 0 | 4B generation |
+    #[task(shared = [flash, plan, slots, fp_config])]
+    fn FP_format(ctx: FP_format::Context) {
         let mut flash = ctx.shared.flash;
-        //Get the addresses of the task list
-        let mut executed_list = Vec::<u32, MAX_NR_OF_TASKS>::new();
-        for i in 0..FpConfig::TaskNum as u32 {
-            let address = (FpConfig::StartAddress as u32 + i as u32) * FpConfig::TaskSize as u32;
-            let mut executed_byte: [u8; 1] = [0; 1];
-            //Executed is in byte 3 - thus address + 2
-            flash.lock(|f| f.read(address + 2, 1, &mut executed_byte));
-            //If a task is scheduled: 0bxx001111, if it is executed: 0bxx000101
-            if fp::is_execute_ready(executed_byte[0]) {
-                executed_list.push(address).ok();
+        let mut plan = ctx.shared.plan;
+        let mut slots = ctx.shared.slots;
+        let mut fp_config = ctx.shared.fp_config;
+        let config = fp_config.lock(|c| *c);
+        let result = match plan.lock(|plan| *plan) {
+            //A layout that does not fit is not formatted either.
+            Err(superblock::Error::Config(e)) => Err(superblock::Error::Config(e)),
+            _ => flash.lock(|f| {
+                let block = superblock::format(f, FP_SUPERBLOCK, &config.layout())?;
+                let map = build_slots(f, &config)?;
+                slots.lock(|slots| *slots = map);
+                Ok(block)
+            }),
+        };
+        plan.lock(|plan| *plan = result);
+        let reply = match result {
+            Ok(block) => {
+                defmt::warn!("Flight plan formatted, generation {}", block.generation);
+                let g = block.generation.to_be_bytes();
+                let mut reply = Vec::<[u8; 8], 32>::new();
+                reply.push([0x06, 0, 0, 0, g[0], g[1], g[2], g[3]]).ok();
+                reply
             }
-        }
+            Err(e) => plan_nak(e),
+        };
+        //Empties the first five and the alarm
+        FP_sort_first_five_full::spawn().ok();
+        can_send::spawn(3, 2, 0, 0, reply, true).ok();
+    }
 
+    #[task(shared=[flash, slots], local=[dma_request_schedule])] //Request Schedule
+    fn FP_request_schedule(ctx: FP_request_schedule::Context) {
+        defmt::debug!("Full schedule has been requested!");
+        let mut flash = ctx.shared.flash;
+        let mut slots = ctx.shared.slots;
+        //The scheduled tasks in address order, one at a time from the map.
         //No sorting implemented yet
-        if !executed_list.is_empty() {
-            for i in executed_list.iter() {
-                let address = *i;
-                let mut flash_task: [u8; 256] = [0; 256];
-                flash.lock(|f| f.read(address, flash_task.len(), &mut flash_task));
-
-                let data_vec = fp::decompile_task(&mut flash_task, address);
-
-                loop {
-                    let err = can_send::spawn(3, 2, 0, 0, data_vec.clone(), true).is_err();
-                    if !err {
-                        break;
-                    }
+        let mut next = slots.lock(|s| s.scheduled().next());
+        while let Some(address) = next {
+            next = slots.lock(|s| s.scheduled().find(|a| *a > address));
+            let data_vec = match read_task(&mut flash, ctx.local.dma_request_schedule, address) {
+                Ok(Ok(task)) => task.to_frames(address),
+                Ok(Err(e)) => {
+                    FP_quarantine_task::spawn(address, e.stored, e.computed).ok();
+                    continue;
+                }
+                Err(e) => {
+                    can_send::spawn(3, 2, 0, 0, flash_nak(e), true).ok();
+                    return;
+                }
+            };
+
+            loop {
+                let err = can_send::spawn(3, 2, 0, 0, data_vec.clone(), true).is_err();
+                if !err {
+                    break;
                 }
             }
         }
@@ -493,7 +914,7 @@ mod app {
         }
     }
 
-    #[task(shared=[first_five,flash])] //Request Schedule
+    #[task(shared=[first_five,flash], local=[dma_request_ff])] //Request Schedule
     fn FP_request_ff(ctx: FP_request_ff::Context) {
         defmt::debug!("First Five has been requested!");
         let mut flash = ctx.shared.flash;
@@ -506,10 +927,17 @@ mod app {
         for i in 0..ffl.len() {
             let ff_task = ffl[i];
             defmt::debug!("Sending task: {}", ff_task.id);
-            let mut task: [u8; 256] = [0; 256];
-            flash.lock(|f| f.read(ff_task.id, (ff_task.dlc * 8) as usize, &mut task));
-
-            let data_vec = fp::decompile_task(&mut task, ff_task.id);
+            let data_vec = match read_task(&mut flash, ctx.local.dma_request_ff, ff_task.id) {
+                Ok(Ok(task)) => task.to_frames(ff_task.id),
+                Ok(Err(e)) => {
+                    FP_quarantine_task::spawn(ff_task.id, e.stored, e.computed).ok();
+                    continue;
+                }
+                Err(e) => {
+                    can_send::spawn(3, 2, 0, 0, flash_nak(e), true).ok();
+                    return;
+                }
+            };
 
             loop {
                 if can_send::spawn(3, 2, 0, 0, data_vec.clone(), true).is_ok() {
@@ -573,50 +1001,42 @@ mod app {
         defmt::debug!("Update done");
     }
 
-    #[task(shared=[first_five,flash],priority = 3)]
+    #[task(shared=[first_five,flash,slots], local=[dma_sort_full], priority = 3)]
     fn FP_sort_first_five_full(ctx: FP_sort_first_five_full::Context) {
         let mut firstfive = ctx.shared.first_five;
         let mut flash = ctx.shared.flash;
+        let mut slots = ctx.shared.slots;
+
+        //Every scheduled task is read, and only the five first kept, as in the quick sort.
+        let mut ff = Vec::<fp::FFArray, 5>::new();
+        let mut next = slots.lock(|s| s.scheduled().next());
+        while let Some(address) = next {
+            next = slots.lock(|s| s.scheduled().find(|a| *a > address));
+            let task = match read_task(&mut flash, ctx.local.dma_sort_full, address) {
+                Ok(Ok(task)) => task,
+                Ok(Err(e)) => {
+                    //A corrupted task is left out of the first five
+                    FP_quarantine_task::spawn(address, e.stored, e.computed).ok();
+                    continue;
+                }
+                Err(e) => {
+                    defmt::error!("SFFF: Flash error: {}", e);
+                    return;
+                }
+            };
 
-        let mut executed_list = Vec::<u32, MAX_NR_OF_TASKS>::new();
-        for i in 0..MAX_NR_OF_TASKS {
-            let address = (FpConfig::StartAddress as u32 + i as u32) * FpConfig::TaskSize as u32;
-            let mut executed_byte: [u8; 1] = [0; 1];
-            //Executed ligger i byte nr 3 (derad + 3)
-            flash.lock(|f| f.read(address + 2, 1, &mut executed_byte));
-            //Hvis en task er schedules: 0bxx001111, hvis den er executed: 0bxx000101
-            if fp::is_execute_ready(executed_byte[0]) {
-                executed_list.push(address).ok();
-            }
-        }
-
-        let mut full_task_list = Vec::<fp::FFArray, MAX_NR_OF_TASKS>::new();
-        if !executed_list.is_empty() {
-            for i in executed_list.iter() {
-                let address = *i;
-                let mut flash_task: [u8; 8] = [0; 8];
-                flash.lock(|f| f.read(address, 8, &mut flash_task));
-
-                let priority: u8 = flash_task[0] >> 5;
-                let execution_time: i32 = i32::from_be_bytes([
-                    flash_task[3],
-                    flash_task[4],
-                    flash_task[5],
-                    flash_task[6],
-                ]);
-                let dlc = flash_task[7];
-
-                full_task_list
-                    .push(fp::FFArray {
-                        id: address,
-                        execution_time: execution_time,
-                        priority: priority,
-                        dlc,
-                    })
-                    .ok();
-            }
+            let mut candidates = Vec::<fp::FFArray, 6>::new();
+            candidates.extend(ff.iter().copied());
+            candidates
+                .push(fp::FFArray {
+                    id: address,
+                    execution_time: task.execution_time,
+                    priority: task.priority,
+                    dlc: task.payload.len() as u8 + 1,
+                })
+                .ok();
+            ff = fp::sort_to_ff(&candidates);
         }
-        let ff = fp::sort_to_ff(&full_task_list);
 
         //Set a new alarm - if nothing is in list, disable alarm
         if ff.is_empty() {
@@ -675,71 +1095,144 @@ mod app {
             defmt::debug!("data[{}]: {:?}", i, new_data[i]);
         }
         defmt::debug!("data lenght: {}", new_data.len());
-        FP_delete_task::spawn(address, false).ok();
-        FP_schedule_task::spawn(new_data, true).ok();
+        FP_delete_task::spawn(address, Some(new_data)).ok();
     }
 
-    #[task(shared=[flash])] //Delete Task
-    fn FP_delete_task(ctx: FP_delete_task::Context, address: u32, respond: bool) {
+    //Deletes the task at address, and schedules its replacement when it is altered. Only the
+    //start of a task that is not being sent can be deleted.
+    #[task(shared=[flash, slots])] //Delete Task
+    fn FP_delete_task(ctx: FP_delete_task::Context, address: u32, replacement: Option<Vec<[u8; 8], 32>>) {
         defmt::debug!("Begun Delete Task");
-        let mut flash = ctx.shared.flash;
-        flash.lock(|f| f.write(address + 2, &[0b00000101]));
-        defmt::debug!("Task {} has been deleted!", address);
-        if respond {
+        let result = (ctx.shared.flash, ctx.shared.slots).lock(|f, s| s.delete(f, address));
+        if result.is_ok() {
             FP_sort_first_five_full::spawn().ok();
-            let mut reply = Vec::<[u8; 8], 32>::new();
-            reply.push([0x06, 0, 0, 0, 0, 0, 0, 0]).ok();
-            can_send::spawn(3, 2, 0, 0, reply, true).ok();
         }
+        let mut reply = Vec::<[u8; 8], 32>::new();
+        match result {
+            Ok(true) => {
+                defmt::debug!("Task {} has been deleted!", address);
+                if let Some(data) = replacement {
+                    FP_schedule_task::spawn(data, true).ok();
+                    return;
+                }
+                reply.push([0x06, 0, 0, 0, 0, 0, 0, 0]).ok();
+            }
+            Ok(false) => {
+                defmt::error!("No task to delete at {}", address);
+                reply.push([0x15, 0x42, 0x61, 0x64, 0x41, 0x64, 0x64, 0x72]).ok(); //NAK "BadAddr"
+            }
+            Err(e) => reply = flash_nak(e),
+        }
+        can_send::spawn(3, 2, 0, 0, reply, true).ok();
     }
 
-    #[task(shared = [next_address_id,flash,rtc])]
+    //Marks a task whose CRC failed, so it is never executed or sent, and reports it.
+    //Reply: NAK "TaskCRC", then | 4B address | 2B stored CRC | 2B computed CRC |
+    #[task(shared = [flash, log, rtc, slots], capacity = 4)]
+    fn FP_quarantine_task(ctx: FP_quarantine_task::Context, address: u32, stored: u16, computed: u16) {
+        defmt::error!("Task {} failed its CRC and is quarantined", address);
+        let mut rtc = ctx.shared.rtc;
+        let time = rtc.lock(|r| r.get_time(false)) as u32;
+        let (a, s, c) = (address.to_be_bytes(), stored.to_be_bytes(), computed.to_be_bytes());
+        let details = [a[0], a[1], a[2], a[3], s[0], s[1], c[0], c[1]];
+        let mut slots = ctx.shared.slots;
+        let result = (ctx.shared.flash, ctx.shared.log).lock(|f, log| {
+            let status = address + fp::STATUS_INDEX as u32;
+            f.write(status, &[fp::Status::Quarantined.program_byte()])?;
+            slots.lock(|s| s.set(address, fp::Status::Quarantined));
+            //Event kind 1: quarantined task
+            if let Some(log) = log.as_mut() {
+                log.append(f, time, ec::transmitter_id(), 1, details)?;
+            }
+            Ok(())
+        });
+        let mut reply = match result {
+            Ok(()) => {
+                let mut reply = Vec::<[u8; 8], 32>::new();
+                reply.push([0x15, 0x54, 0x61, 0x73, 0x6B, 0x43, 0x52, 0x43]).ok(); //NAK "TaskCRC"
+                reply
+            }
+            Err(e) => flash_nak(e),
+        };
+        reply.push(details).ok();
+        can_send::spawn(3, 2, 0, 0, reply, true).ok();
+    }
+
+    #[task(shared = [next_address_id,flash,rtc,slots])]
     fn FP_schedule_task(
         ctx: FP_schedule_task::Context,
         data: Vec<[u8; 8], 32>,
         is_alter_trigger: bool,
     ) {
-        let dlc: u8 = data.len() as u8;
+        let mut task = fp::Task::from_frames(&data);
+        let dlc: u8 = task.payload.len() as u8 + 1;
         defmt::debug!("data lenght: {}", dlc);
         //WHEN SENDING TO SCHEDULE TASK, THE FIRST CAN PACKAGE MUST be:
         //| 1B priority | 1B receiver| 1B port | 1B command | 4B execution time |
         //An address ID is collected form the adress manager task, and collected from the shared(mutex)
-        FP_task_id_manager::spawn().unwrap();
         let mut id_man = ctx.shared.next_address_id;
         let mut flash = ctx.shared.flash;
         let mut rtc = ctx.shared.rtc;
+        let mut slots = ctx.shared.slots;
+        let units = slots.lock(|s| s.units_for(dlc));
+        FP_task_id_manager::spawn(units).unwrap();
 
         let mut integrety_check: bool = false;
         let mut bad_time: bool = false;
-        let mut fp_full: bool = false;
+        let mut flash_error: Option<flash::Error> = None;
 
         let mut reply = Vec::<[u8; 8], 32>::new();
 
         let address = id_man.lock(|id_man| *id_man);
-        fp_full = address.is_err();
-        if !fp_full {
-            let address = address.unwrap();
-
-            //Task array is initialized, filled with zeros - NOTE: Array is used to make space for Executed byte at the end.
-            let task = fp::compile_task(&data, false);
+        if let Err(id_manager::Error::Flash(e)) = address {
+            defmt::debug!("Address manager could not read the memory!");
+            reply = flash_nak(e);
+        } else if let Ok(address) = address {
+            //Written as Writing, and Scheduled once it has been read back.
+            task.status = fp::Status::Writing;
+            let raw = task.to_record();
+            let status = address + fp::STATUS_INDEX as u32;
             //Sorts the new task into the list
-            let priority: u8 = data[0][0];
-            let exe_time: i32 =
-                i32::from_be_bytes([data[0][4], data[0][5], data[0][6], data[0][7]]);
+            let priority: u8 = task.priority;
+            let exe_time: i32 = task.execution_time;
 
             let current_time: i32 = rtc.lock(|r| r.get_time(false)) as i32;
             bad_time = !(exe_time > current_time);
 
             integrety_check = {
-                if !(bad_time || fp_full) {
+                if !bad_time {
                     //Writes the task to memory
-                    flash.lock(|f| f.write(address, &task));
                     //Reads the task back from memory, for confirmation of task
-                    let mut read_back_content: [u8; 256] = [0; 256];
-                    flash.lock(|f| f.read(address, dlc as usize * 8, &mut read_back_content));
+                    let mut read_back_content = [0u8; fp::RECORD_BYTES];
+                    //A slot left in an unknown state is erased by the next reclaim
+                    let mut next = fp::Status::Reclaimable;
+                    //The status and DLC go first, so a reset never leaves a task without a length.
+                    let mut header = [0xff; fp::HEADER_BYTES];
+                    header[fp::STATUS_INDEX] = fp::Status::Writing.program_byte();
+                    header[fp::DLC_INDEX] = dlc;
+                    slots.lock(|s| s.insert(address, units, fp::Status::Writing));
+                    let written = flash.lock(|f| {
+                        f.write(address, &header)?;
+                        f.write(address, &raw)?;
+                        f.read(address, raw.len(), &mut read_back_content[..raw.len()])?;
+                        //The task as stored, not just the bytes, so a payload the record
+                        //could not hold is never acknowledged.
+                        let stored = fp::Task::from_record(&read_back_content[..raw.len()]);
+                        if stored.as_ref() == Ok(&task) {
+                            next = fp::Status::Scheduled;
+                        }
+                        f.write(status, &[next.program_byte()])
+                    });
+                    slots.lock(|s| s.set(address, next));
                     defmt::debug!("Read back content: {:?}", read_back_content[0..8]);
 
-                    fp::compare_tasks(&task, &read_back_content)
+                    match written {
+                        Ok(()) => next == fp::Status::Scheduled,
+                        Err(e) => {
+                            flash_error = Some(e);
+                            false
+                        }
+                    }
                 } else {
                     false
                 }
@@ -751,6 +1244,8 @@ mod app {
                         defmt::debug!("Task has succesfully been written to memory!");
                         let add = address.to_be_bytes();
                         [0x06, 0, 0, 0, 0, 0, add[2], add[3]]
+                    } else if let Some(e) = flash_error {
+                        flash_nak(e)[0]
                     } else {
                         if bad_time {
                             defmt::debug!("Invalid time! Time has happend!");
@@ -762,7 +1257,8 @@ mod app {
                     }
                 })
                 .ok();
-            if !bad_time {
+            //Only a task that reached Scheduled goes in the first five.
+            if integrety_check {
                 defmt::debug!("Time officially good");
                 //Checks if task belongs in first_five
                 if is_alter_trigger {
@@ -794,7 +1290,7 @@ mod app {
         FP_execute_task::spawn().ok();
     }
 
-    #[task(shared=[first_five,rtc,flash,can_reply], priority = 2)] //local = [exe_spawn])] //execute_task
+    #[task(shared=[first_five,rtc,flash,can_reply,slots], local=[dma_execute], priority = 2)] //local = [exe_spawn])] //execute_task
     fn FP_execute_task(ctx: FP_execute_task::Context) {
         let mut ffs = ctx.shared.first_five;
         let mut rtc = ctx.shared.rtc;
@@ -812,59 +1308,79 @@ mod app {
             if time >= firsttask.execution_time {
                 //Request time from memory
                 defmt::debug!("Time to execute task {} at time {}", firsttask.id, time);
-                let mut task: [u8; 256] = [0; 256];
-                flash.lock(|f| f.read(firsttask.id, firsttask.dlc as usize * 8, &mut task));
-                let non_executed_byte = task[2];
-                defmt::debug!("read task: {:?}", task);
-                let executed_byte = non_executed_byte & 0b11000101;
-                let can_id = u32::from_be_bytes([0, task[0], task[1], task[2]]);
-                let (prio, rec, port, cmd) = (
-                    ((can_id >> 21) as u8 & 7),
-                    ((can_id >> 17) as u8 & 15),
-                    ((can_id >> 14) as u8 & 7),
-                    ((can_id >> 6) as u8 & 255),
-                );
-                defmt::debug!("prio: {}, rec: {}, port: {}, cmd: {}", prio, rec, port, cmd);
-                let mut data = Vec::<[u8; 8], 32>::new();
-                let dlc: usize = task[7] as usize;
-                defmt::debug!("Executed task has a dlc of {}", dlc);
-
-                for i in 1..dlc {
-                    defmt::debug!("packing message {}", i);
-                    //Start compiling at task nr 8
-                    let mul = i * 8;
-                    //package byte [8-15][16...]...
-                    let package: [u8; 8] = [
-                        task[(mul) as usize],
-                        task[(mul + 1) as usize],
-                        task[(mul + 2) as usize],
-                        task[(mul + 3) as usize],
-                        task[(mul + 4) as usize],
-                        task[(mul + 5) as usize],
-                        task[(mul + 6) as usize],
-                        task[(mul + 7) as usize],
-                    ];
-                    data.push(package).ok();
-                }
-                defmt::debug!("Data vec lenght: {:?}", data.len());
-
-                //TRANSMIT CAN
-                can_send::spawn(prio, rec, port, cmd, data, true).ok();
-
-                //RECEIVE ACKNOWLEDGEMENT
-                let mut reply_ctx = ctx.shared.can_reply;
-                defmt::debug!("Waiting for reply");
-                loop {
-                    let reply = reply_ctx.lock(|reply| *reply);
-                    if reply == 0x06 {
-                        defmt::debug!("Task {} executed!", firsttask.id);
-                        reply_ctx.lock(|reply| *reply = 0);
-                        break;
+                let dma = ctx.local.dma_execute;
+                match read_task(&mut flash, dma, firsttask.id) {
+                    Err(e) => {
+                        //Nothing is sent on the bus, ground gets the error instead.
+                        can_send::spawn(3, 2, 0, 0, flash_nak(e), true).ok();
+                    }
+                    Ok(Err(e)) => {
+                        //Corrupted since it was scheduled, nothing is sent on the bus.
+                        FP_quarantine_task::spawn(firsttask.id, e.stored, e.computed).ok();
+                        FP_sort_first_five_full::spawn().ok();
+                    }
+                    Ok(Ok(mut task)) => {
+                        defmt::debug!(
+                            "prio: {}, rec: {}, port: {}, cmd: {}",
+                            task.priority,
+                            task.receiver,
+                            task.port,
+                            task.command
+                        );
+                        let mut data = Vec::<[u8; 8], 32>::new();
+                        data.extend(task.payload.iter().copied());
+
+                        //Executing before anything is sent, so a reset never sends it twice.
+                        //The task is read again under the same lock, it may have been deleted,
+                        //executed or moved by the GC since.
+                        let status = firsttask.id + fp::STATUS_INDEX as u32;
+                        let read = task.clone();
+                        task.status = fp::Status::Executing;
+                        let marked = flash.lock(|f| {
+                            let now = read_task(&mut Exclusive(f), dma, firsttask.id)?;
+                            if read.status != fp::Status::Scheduled || now.as_ref() != Ok(&read) {
+                                return Ok(false);
+                            }
+                            f.write(status, &[task.status_byte()]).map(|()| true)
+                        });
+                        if let Err(e) = marked {
+                            defmt::error!("Task {} could not be marked executing: {}", firsttask.id, e);
+                            can_send::spawn(3, 2, 0, 0, flash_nak(e), true).ok();
+                        } else if marked == Ok(false) {
+                            defmt::warn!("Task {} is no longer scheduled, skipped", firsttask.id);
+                        } else {
+                            let mut slots = ctx.shared.slots;
+                            slots.lock(|s| s.set(firsttask.id, fp::Status::Executing));
+                            defmt::debug!("Data vec lenght: {:?}", data.len());
+
+                            //TRANSMIT CAN
+                            can_send::spawn(task.priority, task.receiver, task.port, task.command, data, true)
+                                .ok();
+
+                            //RECEIVE ACKNOWLEDGEMENT
+                            let mut reply_ctx = ctx.shared.can_reply;
+                            defmt::debug!("Waiting for reply");
+                            loop {
+                                let reply = reply_ctx.lock(|reply| *reply);
+                                if reply == 0x06 {
+                                    defmt::debug!("Task {} executed!", firsttask.id);
+                                    reply_ctx.lock(|reply| *reply = 0);
+                                    break;
+                                }
+                            }
+                            //Write executed byte to memory
+                            task.status = fp::Status::Executed;
+                            match flash.lock(|f| f.write(status, &[task.status_byte()])) {
+                                Ok(()) => slots.lock(|s| s.set(firsttask.id, task.status)),
+                                Err(e) => {
+                                    defmt::error!("Task {} could not be marked executed: {}", firsttask.id, e);
+                                    can_send::spawn(3, 2, 0, 0, flash_nak(e), true).ok();
+                                }
+                            }
+                        }
+                        FP_sort_first_five_full::spawn().ok();
                     }
                 }
-                //Write executed byte to memory
-                flash.lock(|f| f.write(firsttask.id + 2, &[executed_byte]));
-                FP_sort_first_five_full::spawn().ok();
             }
 
             //Checks if there is a next task - If there is, check for time, if not, disable alarm
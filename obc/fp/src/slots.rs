@@ -0,0 +1,396 @@
+pub mod slots {
+    //In-RAM index of the flight plan, so finding room for a task or the scheduled tasks
+    //needs no flash reads. Built once at boot, and kept up to date by every task that
+    //programs a status byte. The plan is split in units, and a task record takes as many
+    //whole units as it needs, never across a sector (see flightplanner::Task::to_record).
+    //One bit per unit and map, WORDS * 32 units at most. A unit is empty (erased), the start
+    //of a record, or a part of the record started before it.
+    use crate::flightplanner::flightplanner::{self as fp, Status};
+    use flash::NorFlash;
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct SlotMap<const WORDS: usize> {
+        start: u32,       //Address of the first unit
+        unit_size: u32,   //Bytes
+        count: usize,     //Units
+        sector_size: u32, //Records never cross a sector boundary
+        empty: [u32; WORDS],
+        starts: [u32; WORDS],
+        scheduled: [u32; WORDS],   //Of record starts
+        reclaimable: [u32; WORDS], //Of record starts: executed, quarantined, deleted or unreadable
+    }
+
+    //Set bits of a bitmap, lowest first.
+    fn ones<const WORDS: usize>(bits: &[u32; WORDS]) -> impl Iterator<Item = usize> + '_ {
+        bits.iter().enumerate().flat_map(|(w, word)| {
+            let mut word = *word;
+            core::iter::from_fn(move || {
+                if word == 0 {
+                    return None;
+                }
+                let bit = word.trailing_zeros() as usize;
+                word &= word - 1;
+                Some(w * 32 + bit)
+            })
+        })
+    }
+
+    fn get<const WORDS: usize>(bits: &[u32; WORDS], unit: usize) -> bool {
+        bits[unit / 32] & 1 << (unit % 32) > 0
+    }
+
+    fn put<const WORDS: usize>(bits: &mut [u32; WORDS], unit: usize, value: bool) {
+        if value {
+            bits[unit / 32] |= 1 << (unit % 32);
+        } else {
+            bits[unit / 32] &= !(1 << (unit % 32));
+        }
+    }
+
+    impl<const WORDS: usize> SlotMap<WORDS> {
+        //All units in use, until they are set. Panics if WORDS is too small for count.
+        pub const fn new(start: u32, unit_size: u32, count: usize, sector_size: u32) -> Self {
+            assert!(count <= WORDS * 32);
+            SlotMap {
+                start,
+                unit_size,
+                count,
+                sector_size,
+                empty: [0; WORDS],
+                starts: [0; WORDS],
+                scheduled: [0; WORDS],
+                reclaimable: [0; WORDS],
+            }
+        }
+
+        //Walk the records of every sector. Units that are neither erased nor a record (a
+        //task torn while it was written) are taken as one unit records to reclaim.
+        pub fn build<F: NorFlash>(
+            flash: &mut F,
+            start: u32,
+            unit_size: u32,
+            count: usize,
+        ) -> Result<Self, flash::Error> {
+            let mut map = Self::new(start, unit_size, count, flash.get_info_sectorsize());
+            let mut raw = [0u8; fp::TASK_BYTES];
+            let unit = unit_size as usize;
+            if unit > raw.len() || unit < fp::HEADER_BYTES {
+                return Err(flash::Error::Unsupported);
+            }
+            let mut address = start;
+            while address < map.end() {
+                flash.read(address, unit, &mut raw[..unit])?;
+                let status = Status::from_byte(raw[fp::STATUS_INDEX]);
+                let units = fp::record_len(raw[fp::DLC_INDEX]).map(|len| len.div_ceil(unit));
+                let room = map.room(address);
+                if raw[..unit].iter().all(|b| *b == 0xff) {
+                    let unit = map.index(address);
+                    put(&mut map.empty, unit, true);
+                    address += unit_size;
+                } else if let Some(units) = units.filter(|u| *u <= room && status != Status::Empty)
+                {
+                    map.insert(address, units, status);
+                    address += units as u32 * unit_size;
+                } else {
+                    map.insert(address, 1, Status::Reclaimable);
+                    address += unit_size;
+                }
+            }
+            Ok(map)
+        }
+
+        pub fn address(&self, unit: usize) -> u32 {
+            self.start + unit as u32 * self.unit_size
+        }
+
+        //Unit at address, None outside the plan or between units.
+        pub fn unit(&self, address: u32) -> Option<usize> {
+            let offset = address.checked_sub(self.start)?;
+            let unit = (offset / self.unit_size) as usize;
+            (offset.is_multiple_of(self.unit_size) && unit < self.count).then_some(unit)
+        }
+
+        fn index(&self, address: u32) -> usize {
+            ((address - self.start) / self.unit_size) as usize
+        }
+
+        pub fn start(&self) -> u32 {
+            self.start
+        }
+
+        //First address after the last unit.
+        pub fn end(&self) -> u32 {
+            self.address(self.count)
+        }
+
+        pub fn unit_size(&self) -> u32 {
+            self.unit_size
+        }
+
+        pub fn sector_size(&self) -> u32 {
+            self.sector_size
+        }
+
+        //Units from address to the end of its sector, or of the plan.
+        fn room(&self, address: u32) -> usize {
+            let sector_end = address - address % self.sector_size + self.sector_size;
+            ((sector_end.min(self.end()) - address) / self.unit_size) as usize
+        }
+
+        //Units taken by the record of a task with this DLC.
+        pub fn units_for(&self, dlc: u8) -> usize {
+            fp::record_len(dlc).map_or(1, |len| len.div_ceil(self.unit_size as usize))
+        }
+
+        //Record the start of a record of units at address, as it is programmed.
+        pub fn insert(&mut self, address: u32, units: usize, status: Status) {
+            let Some(first) = self.unit(address) else {
+                return;
+            };
+            for unit in first..(first + units).min(self.count) {
+                put(&mut self.empty, unit, false);
+                put(&mut self.starts, unit, unit == first);
+                put(&mut self.scheduled, unit, false);
+                put(&mut self.reclaimable, unit, false);
+            }
+            self.set(address, status);
+        }
+
+        //Record the status programmed at address. Only record starts have one.
+        pub fn set(&mut self, address: u32, status: Status) {
+            let Some(unit) = self.unit(address).filter(|u| get(&self.starts, *u)) else {
+                return;
+            };
+            put(&mut self.scheduled, unit, status == Status::Scheduled);
+            put(&mut self.reclaimable, unit, status.is_reclaimable());
+        }
+
+        //The record at address has been erased.
+        pub fn free(&mut self, address: u32) {
+            let Some(first) = self.unit(address) else {
+                return;
+            };
+            for unit in first..first + self.units(address) {
+                put(&mut self.empty, unit, true);
+                put(&mut self.starts, unit, false);
+                put(&mut self.scheduled, unit, false);
+                put(&mut self.reclaimable, unit, false);
+            }
+        }
+
+        //Program the record at address as deleted. Refused (false) unless address is the start
+        //of a record whose status allows it, anywhere else the status byte is a byte of
+        //another record or outside the plan.
+        pub fn delete<F: NorFlash>(
+            &mut self,
+            flash: &mut F,
+            address: u32,
+        ) -> Result<bool, flash::Error> {
+            if !self.is_start(address) {
+                return Ok(false);
+            }
+            let status = address + fp::STATUS_INDEX as u32;
+            let mut byte = [0u8];
+            flash.read(status, 1, &mut byte)?;
+            if !Status::from_byte(byte[0]).can_delete() {
+                return Ok(false);
+            }
+            flash.write(status, &[Status::Reclaimable.program_byte()])?;
+            self.set(address, Status::Reclaimable);
+            Ok(true)
+        }
+
+        pub fn is_start(&self, address: u32) -> bool {
+            self.unit(address).is_some_and(|u| get(&self.starts, u))
+        }
+
+        pub fn is_scheduled(&self, address: u32) -> bool {
+            self.unit(address).is_some_and(|u| get(&self.scheduled, u))
+        }
+
+        pub fn is_reclaimable(&self, address: u32) -> bool {
+            self.unit(address)
+                .is_some_and(|u| get(&self.reclaimable, u))
+        }
+
+        //Units of the record at address.
+        pub fn units(&self, address: u32) -> usize {
+            let Some(first) = self.unit(address) else {
+                return 0;
+            };
+            1 + (first + 1..first + self.room(address))
+                .take_while(|u| !get(&self.empty, *u) && !get(&self.starts, *u))
+                .count()
+        }
+
+        pub fn empty_count(&self) -> usize {
+            self.empty
+                .iter()
+                .map(|word| word.count_ones() as usize)
+                .sum()
+        }
+
+        //Address of the first run of empty units that fits a record, within one sector.
+        pub fn find_free(&self, units: usize) -> Option<u32> {
+            self.find_run(units, |_| false)
+        }
+
+        //As find_free, but not in the sector starting at sector.
+        pub fn find_free_outside(&self, units: usize, sector: u32) -> Option<u32> {
+            self.find_run(units, |s| s == sector)
+        }
+
+        //Room for a new task. Unlike find_free, the last sector with every unit empty is
+        //kept, so the garbage collection always has room to copy the tasks of a sector to.
+        pub fn allocate(&self, units: usize) -> Option<u32> {
+            let mut empty = self.sectors().filter(|s| self.sector_is_empty(*s));
+            match (empty.next(), empty.next()) {
+                (Some(reserve), None) => self.find_free_outside(units, reserve),
+                _ => self.find_free(units),
+            }
+        }
+
+        fn find_run(&self, units: usize, skip: impl Fn(u32) -> bool) -> Option<u32> {
+            let mut run = 0;
+            for unit in 0..self.count {
+                let address = self.address(unit);
+                if address.is_multiple_of(self.sector_size) {
+                    run = 0;
+                }
+                let free = get(&self.empty, unit) && !skip(address - address % self.sector_size);
+                run = if free { run + 1 } else { 0 };
+                if run == units {
+                    return Some(self.address(unit + 1 - units));
+                }
+            }
+            None
+        }
+
+        //Start address of every sector the plan has units in.
+        pub fn sectors(&self) -> impl Iterator<Item = u32> {
+            let first = self.start - self.start % self.sector_size;
+            (first..self.end()).step_by(self.sector_size as usize)
+        }
+
+        fn sector_is_empty(&self, sector: u32) -> bool {
+            let first = sector.max(self.start);
+            let units = self.index(first)..self.index(first) + self.room(first);
+            units.into_iter().all(|u| get(&self.empty, u))
+        }
+
+        //Addresses of every record, lowest first.
+        pub fn records(&self) -> impl Iterator<Item = u32> + '_ {
+            ones(&self.starts).map(|unit| self.address(unit))
+        }
+
+        //Addresses of the scheduled tasks, lowest first.
+        pub fn scheduled(&self) -> impl Iterator<Item = u32> + '_ {
+            ones(&self.scheduled).map(|unit| self.address(unit))
+        }
+
+        //Addresses of the records that can be erased and the units used again, lowest first.
+        pub fn reclaimable(&self) -> impl Iterator<Item = u32> + '_ {
+            ones(&self.reclaimable).map(|unit| self.address(unit))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use flash::sim::SimFlash;
+
+        fn record(frames: usize, status: Status) -> heapless::Vec<u8, { fp::RECORD_BYTES }> {
+            let mut task = fp::Task::from_frames(&[[frames as u8; 8]; 32][..frames + 1]);
+            task.status = status;
+            task.to_record()
+        }
+
+        #[test]
+        fn built_by_walking_the_records() {
+            let mut flash = SimFlash::<0x2000>::new();
+            //Units of 16 bytes: records of 1, 2 and 16 units, then a gap and a torn write.
+            flash.write(0x000, &record(0, Status::Scheduled)).unwrap();
+            flash.write(0x010, &record(1, Status::Executed)).unwrap();
+            flash.write(0x030, &record(30, Status::Writing)).unwrap();
+            flash
+                .write(0x150, &[0xff, 0xff, 0xff, 0, 0, 0, 0, 0x21])
+                .unwrap();
+            //A record that would cross into the next sector is not one.
+            flash
+                .write(0xff0, &record(1, Status::Scheduled)[..16])
+                .unwrap();
+            flash.write(0x1000, &record(3, Status::Scheduled)).unwrap();
+
+            let map = SlotMap::<16>::build(&mut flash, 0, 16, 512).unwrap();
+            let records = [0x000, 0x010, 0x030, 0x150, 0xff0, 0x1000];
+            assert!(map.records().eq(records));
+            assert_eq!(records.map(|a| map.units(a)), [1, 2, 16, 1, 1, 3]);
+            assert!(map.scheduled().eq([0x000, 0x1000]));
+            assert!(map.reclaimable().eq([0x010, 0x150, 0xff0]));
+            assert_eq!(map.empty_count(), 512 - 24);
+            assert_eq!(map.find_free(1), Some(0x130));
+            assert_eq!(map.find_free(3), Some(0x160));
+        }
+
+        #[test]
+        fn kept_up_to_date_without_reads() {
+            assert_eq!(
+                SlotMap::<2>::new(0x1800, 0x40, 60, 0x1000).find_free(1),
+                None
+            );
+            let mut flash = SimFlash::<0x3000>::new();
+            let mut map = SlotMap::<2>::build(&mut flash, 0x1800, 0x40, 60).unwrap();
+            assert_eq!(map.empty_count(), 60);
+            map.insert(0x1800, 4, Status::Writing);
+            map.insert(0x1900, 1, Status::Scheduled);
+            assert_eq!((map.units(0x1800), map.units(0x1900)), (4, 1));
+            assert!(map.scheduled().eq([0x1900]));
+            assert_eq!(map.find_free(2), Some(0x1940));
+            map.set(0x1800, Status::Scheduled);
+            map.set(0x1900, Status::Executed);
+            assert!(map.scheduled().eq([0x1800]));
+            assert!(map.reclaimable().eq([0x1900]));
+            map.free(0x1900);
+            assert_eq!(map.find_free(1), Some(0x1900));
+            assert_eq!(map.empty_count(), 56);
+
+            //Not the start of a record.
+            for address in [0x17c0, 0x1840, 0x1820, 0x2700] {
+                map.set(address, Status::Scheduled);
+            }
+            assert!(map.scheduled().eq([0x1800]));
+
+            //Runs do not cross the sector boundary at 0x2000, unit 32.
+            for unit in 4..29 {
+                map.insert(map.address(unit), 1, Status::Scheduled);
+            }
+            assert_eq!(map.find_free(4), Some(0x2000));
+            assert_eq!(map.find_free(3), Some(0x1f40));
+            //The sector at 0x2000 is the last empty one, and is kept for the collection.
+            assert_eq!(map.allocate(4), None);
+            assert_eq!(map.allocate(3), Some(0x1f40));
+            assert_eq!(map.find_free_outside(3, 0x1000), Some(0x2000));
+            map.insert(0x2000, 1, Status::Scheduled);
+            assert_eq!(map.allocate(4), Some(0x2040));
+        }
+
+        #[test]
+        fn only_record_starts_are_deleted() {
+            let mut flash = SimFlash::<0x2000>::new();
+            flash.write(0x000, &record(3, Status::Scheduled)).unwrap();
+            flash.write(0x030, &record(0, Status::Executing)).unwrap();
+            let mut map = SlotMap::<16>::build(&mut flash, 0, 16, 256).unwrap();
+            let before = flash.as_slice().to_vec();
+            //Inside the first record, a record being sent, an empty unit, past the plan.
+            for address in [0x010, 0x002, 0x030, 0x040, 0x1000] {
+                assert_eq!(map.delete(&mut flash, address), Ok(false), "{:x}", address);
+            }
+            assert_eq!(flash.as_slice(), &before[..]);
+
+            assert_eq!(map.delete(&mut flash, 0x000), Ok(true));
+            assert!(map.reclaimable().eq([0x000]));
+            assert_eq!(map.delete(&mut flash, 0x000), Ok(false), "deleted already");
+            assert_eq!(map, SlotMap::build(&mut flash, 0, 16, 256).unwrap());
+        }
+    }
+}
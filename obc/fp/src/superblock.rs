@@ -0,0 +1,467 @@
+pub mod superblock {
+    //Flight plan superblock: the layout the tasks on the flash were written with.
+    //It lives in two sectors of its own. Records are appended, and the valid one with the
+    //highest generation in either sector is the current one. When the sector of the current
+    //record is full the other one is erased and written, so a reset while writing keeps the
+    //old record.
+    use crate::flightplanner::flightplanner::{self as fp, Status};
+    use crate::fpconfig::fpconfig;
+    use defmt::Format;
+    use flash::crc::crc16;
+    use flash::w25q128::Delete;
+    use flash::NorFlash;
+
+    //Format versions:
+    //0: No superblock, tasks without a CRC. Migrated by adding the CRC.
+    //1: 256 byte tasks with a CRC, see flightplanner::Task.
+    //2: Writing and Executing steps in the status bits. Version 1 firmware takes them for
+    //   corruption, so the version keeps it from reading the plan.
+    //3: Tasks as records of whole units (flightplanner::Task::to_record). Versions 0 to 2 are
+    //   migrated by rewriting each 256 byte slot as a record at the same address.
+    pub const VERSION: u8 = 3;
+    pub const RECORD_SIZE: usize = 32;
+    const SECTOR_SIZE: u32 = 0x1000;
+    const RECORDS: u32 = SECTOR_SIZE / RECORD_SIZE as u32;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Format)]
+    pub struct Superblock {
+        pub version: u8,
+        pub start: u32,     //Address of the first unit
+        pub unit_size: u16, //Bytes, the task size up to version 2
+        pub unit_count: u16,
+        pub generation: u32, //One higher for every record written
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Format)]
+    pub enum Error {
+        Flash(flash::Error),
+        Unreadable, //The superblock sectors hold no valid record, the tasks are not touched
+        UnknownVersion(Superblock), //Written by other firmware, the tasks are not touched
+        Geometry(Superblock), //Unit size, count or start differs from this build
+        Config(fpconfig::Error), //The configured layout does not fit the flash, nothing is read
+    }
+
+    impl From<flash::Error> for Error {
+        fn from(e: flash::Error) -> Self {
+            Error::Flash(e)
+        }
+    }
+
+    impl Superblock {
+        //Layout of this build, generation 0.
+        pub const fn new(start: u32, unit_size: u16, unit_count: u16) -> Self {
+            Superblock {
+                version: VERSION,
+                start,
+                unit_size,
+                unit_count,
+                generation: 0,
+            }
+        }
+
+        //|"FPSB"|version|0|unit size|unit count|start|generation|0..|CRC-16|, BE.
+        pub fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+            let mut raw = [0u8; RECORD_SIZE];
+            raw[..4].copy_from_slice(b"FPSB");
+            raw[4] = self.version;
+            raw[6..8].copy_from_slice(&self.unit_size.to_be_bytes());
+            raw[8..10].copy_from_slice(&self.unit_count.to_be_bytes());
+            raw[10..14].copy_from_slice(&self.start.to_be_bytes());
+            raw[14..18].copy_from_slice(&self.generation.to_be_bytes());
+            let crc = crc16(&raw[..RECORD_SIZE - 2]).to_be_bytes();
+            raw[RECORD_SIZE - 2..].copy_from_slice(&crc);
+            raw
+        }
+
+        //None for an erased or torn record.
+        pub fn from_bytes(raw: &[u8; RECORD_SIZE]) -> Option<Self> {
+            let crc = crc16(&raw[..RECORD_SIZE - 2]).to_be_bytes();
+            if raw[..4] != *b"FPSB" || raw[RECORD_SIZE - 2..] != crc {
+                return None;
+            }
+            Some(Superblock {
+                version: raw[4],
+                unit_size: u16::from_be_bytes([raw[6], raw[7]]),
+                unit_count: u16::from_be_bytes([raw[8], raw[9]]),
+                start: u32::from_be_bytes([raw[10], raw[11], raw[12], raw[13]]),
+                generation: u32::from_be_bytes([raw[14], raw[15], raw[16], raw[17]]),
+            })
+        }
+
+        //First address after the plan.
+        pub fn end(&self) -> u32 {
+            self.start + self.unit_size as u32 * self.unit_count as u32
+        }
+
+        //Equal but for the generation.
+        fn same_layout(&self, other: &Superblock) -> bool {
+            Superblock {
+                generation: 0,
+                ..*self
+            } == Superblock {
+                generation: 0,
+                ..*other
+            }
+        }
+    }
+
+    //Newest record in one sector, and the index of the first unused record.
+    fn scan<F: NorFlash>(flash: &mut F, sector: u32) -> Result<(Option<Superblock>, u32), Error> {
+        let (mut found, mut next) = (None::<Superblock>, 0);
+        for i in 0..RECORDS {
+            let mut raw = [0u8; RECORD_SIZE];
+            flash.read(sector + i * RECORD_SIZE as u32, RECORD_SIZE, &mut raw)?;
+            if raw.iter().any(|b| *b != 0xff) {
+                next = i + 1;
+            }
+            if let Some(block) = Superblock::from_bytes(&raw) {
+                if found.is_none_or(|f| block.generation > f.generation) {
+                    found = Some(block);
+                }
+            }
+        }
+        Ok((found, next))
+    }
+
+    //Newest record of the two sectors from sector, the sector it is in and the index of the
+    //first unused record there. The first sector if neither holds a record.
+    fn newest<F: NorFlash>(
+        flash: &mut F,
+        sector: u32,
+    ) -> Result<(Option<Superblock>, u32, u32), Error> {
+        let (first, first_next) = scan(flash, sector)?;
+        let (second, second_next) = scan(flash, sector + SECTOR_SIZE)?;
+        match second {
+            Some(b) if first.is_none_or(|a| b.generation > a.generation) => {
+                Ok((second, sector + SECTOR_SIZE, second_next))
+            }
+            _ => Ok((first, sector, first_next)),
+        }
+    }
+
+    //Both sectors erased, no superblock was ever written.
+    fn blank<F: NorFlash>(flash: &mut F, sector: u32) -> Result<bool, Error> {
+        Ok(scan(flash, sector)?.1 == 0 && scan(flash, sector + SECTOR_SIZE)?.1 == 0)
+    }
+
+    //Append a record for layout with the next generation. The sector of the newest record is
+    //never erased, a full one is left for the other sector.
+    fn write<F: NorFlash>(
+        flash: &mut F,
+        sector: u32,
+        layout: &Superblock,
+    ) -> Result<Superblock, Error> {
+        let (found, mut current, mut next) = newest(flash, sector)?;
+        let block = Superblock {
+            generation: found.map_or(1, |f| f.generation + 1),
+            ..*layout
+        };
+        if next == RECORDS {
+            current = if current == sector {
+                sector + SECTOR_SIZE
+            } else {
+                sector
+            };
+            flash.delete(Delete::SectorErase, current)?;
+            next = 0;
+        }
+        flash.write(current + next * RECORD_SIZE as u32, &block.to_bytes())?;
+        Ok(block)
+    }
+
+    //Check the superblock in the two sectors from sector against the layout of this build.
+    //If both sectors are erased the plan is taken to be version 0 over the same bytes and
+    //migrated. The bool is true if it was.
+    pub fn mount<F: NorFlash>(
+        flash: &mut F,
+        sector: u32,
+        layout: &Superblock,
+    ) -> Result<(Superblock, bool), Error> {
+        match newest(flash, sector)?.0 {
+            Some(block) if block.same_layout(layout) => Ok((block, false)),
+            Some(block) if matches!(block.version, 1 | 2) && fixed_slots(&block, layout) => {
+                migrate_fixed(flash, layout, false)?;
+                Ok((write(flash, sector, layout)?, true))
+            }
+            Some(block) if block.version != VERSION => Err(Error::UnknownVersion(block)),
+            Some(block) => Err(Error::Geometry(block)),
+            None if !blank(flash, sector)? => Err(Error::Unreadable),
+            None => {
+                migrate_fixed(flash, layout, true)?;
+                Ok((write(flash, sector, layout)?, true))
+            }
+        }
+    }
+
+    //The old plan is 256 byte slots over the bytes of layout.
+    fn fixed_slots(old: &Superblock, layout: &Superblock) -> bool {
+        old.unit_size as usize == fp::TASK_BYTES
+            && old.start == layout.start
+            && old.end() == layout.end()
+    }
+
+    //Versions 0 to 2 to 3: rewrite every used 256 byte slot as a record at its address, the
+    //rest of the slot erased. Version 0 slots are sealed first. A slot whose CRC fails is
+    //quarantined as it is, and so is a full payload, which lost its last two bytes to the
+    //CRC and whose record is longer than the slot. Each sector is rewritten at most once, and a slot that already
+    //holds a record is skipped, so a reset before the superblock is written only repeats
+    //the work.
+    fn migrate_fixed<F: NorFlash>(
+        flash: &mut F,
+        layout: &Superblock,
+        seal: bool,
+    ) -> Result<(), Error> {
+        let mut buffer = [0u8; SECTOR_SIZE as usize];
+        let mut sector = layout.start;
+        while sector < layout.end() {
+            let len = ((layout.end() - sector) as usize).min(buffer.len());
+            flash.read(sector, len, &mut buffer[..len])?;
+            let mut changed = false;
+            for slot in buffer[..len].chunks_exact_mut(fp::TASK_BYTES) {
+                let slot: &mut [u8; fp::TASK_BYTES] = slot.try_into().unwrap();
+                let status = Status::from_byte(slot[fp::STATUS_INDEX]);
+                if status == Status::Empty || fp::Task::from_record(slot).is_ok() {
+                    continue;
+                }
+                if seal {
+                    fp::seal(slot);
+                }
+                match fp::Task::from_bytes(slot) {
+                    Ok(task) if task.payload.len() < fp::MAX_PAYLOAD => {
+                        let record = task.to_record();
+                        slot.fill(0xff);
+                        slot[..record.len()].copy_from_slice(&record);
+                    }
+                    _ if status == Status::Quarantined => continue,
+                    _ => slot[fp::STATUS_INDEX] &= Status::Quarantined.program_byte(),
+                }
+                changed = true;
+            }
+            if changed {
+                flash.update(sector, &buffer[..len])?;
+            }
+            sector += SECTOR_SIZE;
+        }
+        Ok(())
+    }
+
+    //Drop the plan and start over with the layout of this build, for a refused superblock.
+    //Also writes a superblock over sectors that hold no valid record.
+    pub fn format<F: NorFlash>(
+        flash: &mut F,
+        sector: u32,
+        layout: &Superblock,
+    ) -> Result<Superblock, Error> {
+        let mut address = layout.start - layout.start % SECTOR_SIZE;
+        while address < layout.end() {
+            flash.delete(Delete::SectorErase, address)?;
+            address += SECTOR_SIZE;
+        }
+        write(flash, sector, layout)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::slots::slots::SlotMap;
+        use flash::sim::{PowerCut, SimFlash};
+
+        const LAYOUT: Superblock = Superblock::new(0, 16, 768);
+        const FIXED: Superblock = Superblock::new(0, 256, 48);
+        const SECTOR: u32 = 0x8000;
+        type Sim = SimFlash<0xa000>;
+
+        fn task(time: i32) -> fp::Task {
+            let mut frames = [[0u8; 8]; 2];
+            frames[0] = [1, 2, 3, 4, 0, 0, 0, 0];
+            frames[0][4..].copy_from_slice(&time.to_be_bytes());
+            frames[1] = [9; 8];
+            fp::Task::from_frames(&frames)
+        }
+
+        fn read(flash: &mut Sim, address: u32) -> [u8; fp::TASK_BYTES] {
+            let mut raw = [0u8; fp::TASK_BYTES];
+            flash.read(address, raw.len(), &mut raw).unwrap();
+            raw
+        }
+
+        #[test]
+        fn headerless_plan_is_migrated_once() {
+            let mut flash = Sim::new();
+            //Version 0 task: same bytes, no CRC.
+            let mut legacy = task(100).to_bytes();
+            legacy[fp::CRC_INDEX..].fill(0);
+            flash.write(0x1100, &legacy).unwrap();
+            flash.write(0x0200, &task(200).to_bytes()).unwrap();
+
+            let (block, migrated) = mount(&mut flash, SECTOR, &LAYOUT).unwrap();
+            assert!(migrated);
+            assert_eq!(block.generation, 1);
+            for (address, time) in [(0x1100, 100), (0x0200, 200)] {
+                let raw = read(&mut flash, address);
+                assert_eq!(fp::Task::from_record(&raw), Ok(task(time)));
+                let len = task(time).to_record().len();
+                assert_eq!(raw[len..], [0xff; fp::TASK_BYTES][len..]);
+            }
+            assert_eq!(
+                read(&mut flash, 0x0300),
+                [0xff; fp::TASK_BYTES],
+                "empty slots stay erased"
+            );
+
+            assert_eq!(mount(&mut flash, SECTOR, &LAYOUT), Ok((block, false)));
+        }
+
+        #[test]
+        fn fixed_slots_are_migrated_in_place() {
+            for version in [1, 2] {
+                let mut flash = Sim::new();
+                flash.write(0x0000, &task(100).to_bytes()).unwrap();
+                let mut corrupt = task(200).to_bytes();
+                corrupt[20] ^= 1;
+                flash.write(0x0100, &corrupt).unwrap();
+                let full = fp::Task::from_frames(&[[7; 8]; 32]);
+                flash.write(0x0200, &full.to_bytes()).unwrap();
+                let old = Superblock {
+                    version,
+                    generation: 3,
+                    ..FIXED
+                };
+                flash.write(SECTOR, &old.to_bytes()).unwrap();
+                let (block, migrated) = mount(&mut flash, SECTOR, &LAYOUT).unwrap();
+                assert!(migrated);
+                assert_eq!((block.version, block.generation), (VERSION, 4));
+                assert_eq!(fp::Task::from_record(&read(&mut flash, 0)), Ok(task(100)));
+                for address in [0x0100, 0x0200] {
+                    let raw = read(&mut flash, address);
+                    assert_eq!(
+                        Status::from_byte(raw[fp::STATUS_INDEX]),
+                        Status::Quarantined
+                    );
+                }
+
+                //A reset before the superblock was written does the same again.
+                let after = flash.as_slice().to_vec();
+                migrate_fixed(&mut flash, &LAYOUT, false).unwrap();
+                assert_eq!(flash.as_slice(), &after[..]);
+                let map = SlotMap::<24>::build(&mut flash, 0, 16, 768).unwrap();
+                assert!(map.scheduled().eq([0x0000]));
+                assert!(map.reclaimable().take(1).eq([0x0100]));
+                assert!(map.is_reclaimable(0x0200));
+            }
+            //Other geometries are still refused.
+            let mut flash = Sim::new();
+            let other = Superblock {
+                version: 1,
+                generation: 9,
+                ..Superblock::new(0, 256, 64)
+            };
+            flash.write(SECTOR, &other.to_bytes()).unwrap();
+            assert_eq!(
+                mount(&mut flash, SECTOR, &LAYOUT),
+                Err(Error::UnknownVersion(other))
+            );
+        }
+
+        #[test]
+        fn other_layouts_are_refused_until_formatted() {
+            let mut flash = Sim::new();
+            flash.write(0x0000, &task(100).to_record()).unwrap();
+            let newer = Superblock {
+                version: VERSION + 1,
+                generation: 7,
+                ..LAYOUT
+            };
+            flash.write(SECTOR, &newer.to_bytes()).unwrap();
+            assert_eq!(
+                mount(&mut flash, SECTOR, &LAYOUT),
+                Err(Error::UnknownVersion(newer))
+            );
+            let bigger = Superblock::new(0, 16, 1024);
+            assert_eq!(
+                mount(&mut flash, SECTOR, &bigger),
+                Err(Error::UnknownVersion(newer))
+            );
+            assert_eq!(
+                flash.as_slice()[2],
+                task(100).status_byte(),
+                "tasks untouched"
+            );
+
+            let block = format(&mut flash, SECTOR, &bigger).unwrap();
+            assert_eq!(block.generation, 8);
+            assert_eq!(flash.as_slice()[..0x4000], [0xff; 0x4000]);
+            assert_eq!(mount(&mut flash, SECTOR, &bigger), Ok((block, false)));
+            assert_eq!(
+                mount(&mut flash, SECTOR, &LAYOUT),
+                Err(Error::Geometry(block))
+            );
+        }
+
+        #[test]
+        fn full_sector_and_torn_record() {
+            let mut flash = Sim::new();
+            for _ in 0..RECORDS + 3 {
+                format(&mut flash, SECTOR, &LAYOUT).unwrap();
+            }
+            //Half of the next record, as left by a reset.
+            let (found, current, next) = newest(&mut flash, SECTOR).unwrap();
+            assert_eq!((current, next), (SECTOR + SECTOR_SIZE, 3));
+            let torn = &Superblock::new(0, 512, 8).to_bytes()[..12];
+            flash
+                .write(current + next * RECORD_SIZE as u32, torn)
+                .unwrap();
+            let (block, migrated) = mount(&mut flash, SECTOR, &LAYOUT).unwrap();
+            assert_eq!((Some(block), migrated), (found, false));
+            assert_eq!(block.generation, RECORDS + 3);
+        }
+        #[test]
+        fn unreadable_superblock_is_not_migrated() {
+            let mut flash = Sim::new();
+            flash.write(0x0120, &task(100).to_record()).unwrap();
+            let torn = &LAYOUT.to_bytes()[..12];
+            flash.write(SECTOR + SECTOR_SIZE, torn).unwrap();
+            let before = flash.as_slice().to_vec();
+            assert_eq!(mount(&mut flash, SECTOR, &LAYOUT), Err(Error::Unreadable));
+            assert_eq!(flash.as_slice(), &before[..], "tasks untouched");
+            let block = format(&mut flash, SECTOR, &LAYOUT).unwrap();
+            assert_eq!(mount(&mut flash, SECTOR, &LAYOUT), Ok((block, false)));
+        }
+
+        //A full first sector and a plan of records that do not start on 256 byte slots.
+        fn full_superblock() -> Sim {
+            let mut flash = Sim::new();
+            flash.write(0x0000, &task(100).to_record()).unwrap();
+            flash.write(0x0120, &task(200).to_record()).unwrap();
+            for _ in 0..RECORDS {
+                write(&mut flash, SECTOR, &LAYOUT).unwrap();
+            }
+            flash
+        }
+
+        //Every possible reset point of the write that moves to the second sector: the plan
+        //mounts with the old or the new record, and is not touched.
+        #[test]
+        fn reset_while_changing_sector_keeps_a_superblock() {
+            let mut full = PowerCut::new(full_superblock(), usize::MAX);
+            let plan = full.flash.as_slice()[..0x1000].to_vec();
+            write(&mut full, SECTOR, &LAYOUT).unwrap();
+            let total = full.used;
+            for budget in 0..total {
+                let mut cut = PowerCut::new(full_superblock(), budget);
+                assert!(write(&mut cut, SECTOR, &LAYOUT).is_err());
+                let mut flash = cut.flash;
+                let (block, migrated) = mount(&mut flash, SECTOR, &LAYOUT).unwrap();
+                assert_eq!((block.generation, migrated), (RECORDS, false));
+                assert_eq!(
+                    flash.as_slice()[..0x1000],
+                    plan[..],
+                    "reset after {}",
+                    budget
+                );
+                let block = write(&mut flash, SECTOR, &LAYOUT).unwrap();
+                assert_eq!(mount(&mut flash, SECTOR, &LAYOUT), Ok((block, false)));
+            }
+        }
+    }
+}
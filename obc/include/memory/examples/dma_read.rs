@@ -0,0 +1,65 @@
+#![no_main] //Tell the rust compiler Main isn't used.
+#![no_std] //Tell the rust compiler we are using the core library.
+
+//Include defferred formatting and global logger.
+use defmt as _;
+use defmt_rtt as _;
+//Defines how we should panic -> Using probe-run.
+use panic_probe as _;
+
+use flash::stm32::{DmaHalDevice, HalDevice};
+use flash::w25q128::Memory;
+use flash::DmaFlash;
+
+//Hal for stm32f4 series -> f446re is defined in Cargo.toml
+use hal::{dma::StreamsTuple, pac, prelude::*};
+use stm32f4xx_hal as hal;
+
+use cortex_m_rt::entry;
+
+#[entry]
+fn main() -> ! {
+    defmt::info!("DMA read example");
+    let dp = pac::Peripherals::take().unwrap();
+    let rcc = dp.RCC.constrain();
+    let clocks = rcc.cfgr.use_hse(8.MHz()).sysclk(180.MHz()).pclk2(90.MHz()).freeze();
+
+    //Same wiring as the flight planner: SPI1 on PA5-PA7, chip select on PB6.
+    let gpioa = dp.GPIOA.split();
+    let gpiob = dp.GPIOB.split();
+    let sclk = gpioa.pa5.into_alternate().speed(hal::gpio::Speed::VeryHigh);
+    let miso = gpioa.pa6.into_alternate().speed(hal::gpio::Speed::VeryHigh);
+    let mosi = gpioa.pa7.into_alternate().speed(hal::gpio::Speed::VeryHigh);
+    let cs = gpiob.pb6.into_push_pull_output();
+    let spi_mode = hal::spi::Mode {
+        polarity: hal::spi::Polarity::IdleLow,
+        phase: hal::spi::Phase::CaptureOnFirstTransition,
+    };
+    let spi = dp.SPI1.spi((sclk, miso, mosi), spi_mode, 10.MHz(), &clocks);
+
+    //SPI1_RX is DMA2 stream 0, SPI1_TX is DMA2 stream 3 (both channel 3).
+    let streams = StreamsTuple::new(dp.DMA2);
+    let device = DmaHalDevice::new(HalDevice::new(spi, cs), streams.0, streams.3);
+    let mut memory = Memory::new_w25q128_device(device);
+
+    //DMA buffers must live for the whole transfer.
+    let buffer: &'static mut [u8; 4096] = cortex_m::singleton!(: [u8; 4096] = [0; 4096]).unwrap();
+    if let Err((e, _)) = memory.start_read(0x0, buffer, 4096) {
+        defmt::panic!("Could not start: {}", e);
+    }
+    //The CPU is free while the sector is read, here it just counts.
+    let mut spins: u32 = 0;
+    let buffer = loop {
+        match memory.poll_transfer() {
+            Ok(buffer) => break buffer,
+            Err(nb::Error::WouldBlock) => spins += 1,
+            Err(nb::Error::Other((e, _))) => defmt::panic!("Transfer failed: {}", e),
+        }
+    };
+    defmt::info!("Read {} bytes while spinning {} times", buffer.len(), spins);
+    defmt::info!("First task header: {:x}", buffer[..8]);
+
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
@@ -81,7 +81,7 @@ fn main()-> ! {
         spi.bit_format(hal::spi::BitFormat::MsbFirst); //Set bit_format MSB is standard
         spi.enable(true); //On by default after declaration, but needed after disable
         //spi.enable(false); //Disables SPI, make sure no transmission is occuring.
-        spi.listen(Event::Rxne); //Enables hardware interrupt on RXNE. 
+        spi.listen(Event::RxNotEmpty); //Enables hardware interrupt on RXNE. 
         let mut memory = Memory::new_w25q128(spi, cs);
         // let sectorsize = 256*16;
         let sectorsize = memory.get_info_sectorsize();
@@ -95,7 +95,7 @@ fn main()-> ! {
         let mut addrcnt = 0x0;
         for i in 0..2 {
         let mut data: [u8;1024*4] = [0;1024*4];
-        memory.read(addr, 1024*4, &mut data);
+        memory.read(addr, 1024*4, &mut data).unwrap();
         let mut counter =0;
         for i in data {
             defmt::info!("{}: {:x}",addrcnt, i);
@@ -115,16 +115,16 @@ fn main()-> ! {
         let read_before: usize = change_adrress[0] as usize;
         let read_after: usize = sectorsize as usize - change_adrress[1] as usize;
 
-        memory.read(addr, read_before, &mut buffer[0..read_before]);
-        memory.read(change_adrress[1]+1, read_after, &mut buffer[read_before..]);
+        memory.read(addr, read_before, &mut buffer[0..read_before]).unwrap();
+        memory.read(change_adrress[1]+1, read_after, &mut buffer[read_before..]).unwrap();
         for i in buffer {
             defmt::info!("{:x}",i);
         }
-        memory.delete(flash::w25q128::Delete::SectorErase, 0x0);
+        memory.delete(flash::w25q128::Delete::SectorErase, 0x0).unwrap();
 
         //Rewrite old data.
-        memory.write(addr, &buffer[0..read_before]); 
-        memory.write(change_adrress[1]+1, &buffer[read_before..read_before+read_after]);
+        memory.write(addr, &buffer[0..read_before]).unwrap(); 
+        memory.write(change_adrress[1]+1, &buffer[read_before..read_before+read_after]).unwrap();
 
         //Write new data.
         let mut new_data: [u8;200] = [0xff;200];
@@ -133,11 +133,11 @@ fn main()-> ! {
             new_data[i] = slice[i];
             defmt::info!("Hello {:x}", slice[i]);
         }
-        memory.write(change_adrress[0], &new_data);
+        memory.write(change_adrress[0], &new_data).unwrap();
 
         let mut addr = addr;
         for i in 0..2 {
-            memory.read(addr, buffer.len(), &mut buffer);
+            memory.read(addr, buffer.len(), &mut buffer).unwrap();
             addr += 0x1000;
             for i in buffer {
                 defmt::info!("{}", i);
@@ -80,7 +80,7 @@ fn main()-> ! {
         spi.bit_format(hal::spi::BitFormat::MsbFirst); //Set bit_format MSB is standard
         spi.enable(true); //On by default after declaration, but needed after disable
         //spi.enable(false); //Disables SPI, make sure no transmission is occuring.
-        spi.listen(Event::Rxne); //Enables hardware interrupt on RXNE. 
+        spi.listen(Event::RxNotEmpty); //Enables hardware interrupt on RXNE. 
         let mut memory = Memory::new_w25q128(spi, cs);
         
         // let address: u32 = {0x1012ff};
@@ -88,7 +88,7 @@ fn main()-> ! {
         // for i in output {
         //     defmt::info!("{:x}",i);
         // }
-        memory.delete(flash::w25q128::Delete::BlockErase64, 0x8000);
+        memory.delete(flash::w25q128::Delete::BlockErase64, 0x8000).unwrap();
         let mut writebuffer:[u8;1024*4] = [0;1024*4];
         let mut smallcount: u8 = 0; 
         for i in 0..1024*4 {
@@ -101,7 +101,7 @@ fn main()-> ! {
         }
         let mut addr = 0x0;
         for i in 0..16 {
-            memory.write(addr, &writebuffer);
+            memory.write(addr, &writebuffer).unwrap();
             addr +=0x1000
         }
         // for i in writebuffer {
@@ -112,7 +112,7 @@ fn main()-> ! {
         let mut addr = 0x0;
         for i in 0..17 {
         let mut data: [u8;1024*4] = [0;1024*4];
-        memory.read(addr, 1024*4, &mut data);
+        memory.read(addr, 1024*4, &mut data).unwrap();
         let mut counter =0;
         for i in data {
             defmt::info!("{}: {}",counter, i);
@@ -0,0 +1,600 @@
+//Failed sector management on top of any NorFlash.
+//Every erase and program in a managed region is read back. A sector that does not erase
+//to 0xFF, or keeps bits set that were programmed to 0, is replaced by a spare sector and
+//its content is copied over. The replacements are recorded in a table sector, so they
+//survive a reset. Addresses outside the region are passed through unchecked, except for
+//the spares and the table which can not be used directly.
+use crate::w25q128::{Delete, FlashInfo};
+use crate::wear::{parse, record};
+use crate::{DmaFlash, Error, NorFlash};
+
+const SECTOR_SIZE: usize = 0x1000;
+const RECORD_SIZE: u32 = 8;
+const CHUNK: usize = 256; //Read back size
+
+//Table records, same layout as the wear leveling log.
+const TABLE: u8 = 0x54; //Table header, a and b are the sector and spare counts
+const REMAP: u8 = 0x52; //Logical a moved to physical b, the old sector is bad
+const BAD_SPARE: u8 = 0x58; //Spare b failed before it was used
+
+#[derive(Clone, Copy, Debug)]
+pub struct BadSectorConfig {
+    pub start: u32,     //Address of the first managed sector
+    pub sectors: usize, //Managed sectors
+    pub spares: usize,  //Spare sectors, right after the managed ones
+    pub table: u32,     //Address of the table sector
+}
+
+//N is the largest number of managed and spare sectors together, at most 255.
+pub struct BadSectors<F: NorFlash, const N: usize> {
+    flash: F,
+    config: BadSectorConfig,
+    map: [u8; N],   //Physical sector of each managed sector
+    bad: [bool; N], //Sectors known to be bad
+    loaded: bool,   //Table read, remapping allowed
+    table_offset: u32,
+    written: Option<(u32, usize)>, //Managed address and length of a running DMA program
+}
+
+impl<F: NorFlash, const N: usize> BadSectors<F, N> {
+    //No memory access, every sector starts on itself. Call load before use.
+    pub fn new(flash: F, config: BadSectorConfig) -> Self {
+        BadSectors {
+            flash,
+            config,
+            map: core::array::from_fn(|i| i as u8),
+            bad: [false; N],
+            loaded: false,
+            table_offset: RECORD_SIZE,
+            written: None,
+        }
+    }
+
+    //Read the table, or start an empty one if the table sector holds none.
+    //If this fails no sectors are replaced, and failures are returned as VerifyMismatch.
+    pub fn load(&mut self) -> Result<(), Error> {
+        let config = self.config;
+        let physical = config.sectors + config.spares;
+        if physical > N.min(255) || self.flash.info().sector_size as usize != SECTOR_SIZE {
+            return Err(Error::Unsupported);
+        }
+        let end = config.start + (physical * SECTOR_SIZE) as u32;
+        let capacity = self.flash.info().capacity();
+        if end > capacity || config.table + SECTOR_SIZE as u32 > capacity {
+            return Err(Error::OutOfRange);
+        }
+        if config.table < end && config.start < config.table + SECTOR_SIZE as u32 {
+            return Err(Error::OutOfRange); //Table inside the region
+        }
+        let header = record(TABLE, config.sectors as u8, config.spares as u8, 0);
+        let mut page = [0u8; CHUNK];
+        self.flash.read(config.table, CHUNK, &mut page)?;
+        let has_header = match parse(&page[..8]) {
+            Some((TABLE, ..)) if page[..8] == header => true,
+            Some(_) => return Err(Error::Unsupported), //Table for another layout
+            None => false,
+        };
+        'pages: for page_addr in (config.table..config.table + SECTOR_SIZE as u32).step_by(CHUNK) {
+            self.flash.read(page_addr, CHUNK, &mut page)?;
+            for (i, raw) in page.chunks(RECORD_SIZE as usize).enumerate() {
+                let offset = page_addr - config.table + i as u32 * RECORD_SIZE;
+                if offset < RECORD_SIZE {
+                    continue; //Header
+                }
+                match parse(raw) {
+                    Some((REMAP, logical, to, _))
+                        if (logical as usize) < config.sectors && (to as usize) < physical =>
+                    {
+                        self.bad[self.map[logical as usize] as usize] = true;
+                        self.map[logical as usize] = to;
+                    }
+                    Some((BAD_SPARE, _, spare, _)) if (spare as usize) < physical => {
+                        self.bad[spare as usize] = true;
+                    }
+                    _ if raw.iter().all(|b| *b == 0xff) => break 'pages,
+                    _ => {} //Torn by a reset, the next record goes after it
+                }
+                self.table_offset = offset + RECORD_SIZE;
+            }
+        }
+        //Without a header the table is only started again if nothing follows it, as after
+        //a format cut short. A header damaged later is left, the records after it still count.
+        if !has_header && self.table_offset == RECORD_SIZE {
+            self.flash.delete(Delete::SectorErase, config.table)?;
+            self.flash.write(config.table, &header)?;
+        }
+        self.loaded = true;
+        Ok(())
+    }
+
+    //Number of sectors found bad so far.
+    pub fn bad_count(&self) -> usize {
+        self.bad.iter().filter(|b| **b).count()
+    }
+
+    //Spares not yet used or found bad.
+    pub fn spares_left(&self) -> usize {
+        (self.config.sectors..self.config.sectors + self.config.spares)
+            .filter(|p| self.is_free(*p))
+            .count()
+    }
+
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    //The flash underneath, for things like power-down.
+    pub fn flash(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    fn is_free(&self, physical: usize) -> bool {
+        !self.bad[physical] && !self.map[..self.config.sectors].contains(&(physical as u8))
+    }
+
+    fn sector_address(&self, physical: usize) -> u32 {
+        self.config.start + (physical * SECTOR_SIZE) as u32
+    }
+
+    //Where an address is, sector by sector.
+    fn locate(&self, addr: u32) -> Location {
+        let start = self.config.start;
+        let sector = (addr.wrapping_sub(start) as usize) / SECTOR_SIZE;
+        if addr >= start && sector < self.config.sectors {
+            Location::Managed(sector)
+        } else if (addr >= start && sector < self.config.sectors + self.config.spares)
+            || addr & !(SECTOR_SIZE as u32 - 1) == self.config.table
+        {
+            Location::Reserved
+        } else {
+            Location::Other
+        }
+    }
+
+    fn append(&mut self, record: [u8; 8]) -> Result<(), Error> {
+        if self.table_offset + RECORD_SIZE > SECTOR_SIZE as u32 {
+            return Err(Error::Unsupported); //Can not happen, one record per spare
+        }
+        self.flash
+            .write(self.config.table + self.table_offset, &record)?;
+        self.table_offset += RECORD_SIZE;
+        Ok(())
+    }
+
+    //A program left no bit set that should have been cleared.
+    fn programmed(&mut self, addr: u32, data: &[u8]) -> Result<bool, Error> {
+        let mut read_back = [0u8; CHUNK];
+        for (i, chunk) in data.chunks(CHUNK).enumerate() {
+            self.flash
+                .read(addr + (i * CHUNK) as u32, chunk.len(), &mut read_back)?;
+            if chunk.iter().zip(read_back.iter()).any(|(d, r)| r & !d != 0) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn erased(&mut self, addr: u32) -> Result<bool, Error> {
+        let mut read_back = [0u8; CHUNK];
+        for chunk in (addr..addr + SECTOR_SIZE as u32).step_by(CHUNK) {
+            self.flash.read(chunk, CHUNK, &mut read_back)?;
+            if read_back.iter().any(|b| *b != 0xff) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn write_checked(&mut self, addr: u32, data: &[u8]) -> Result<bool, Error> {
+        match self.flash.write(addr, data) {
+            Ok(()) => self.programmed(addr, data),
+            Err(Error::VerifyMismatch) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    //Move a managed sector to a spare. The new content is the old one with the failed
+    //program applied, or erased if the erase failed.
+    fn replace(&mut self, logical: usize, program: Option<(usize, &[u8])>) -> Result<(), Error> {
+        if !self.loaded {
+            return Err(Error::VerifyMismatch);
+        }
+        let mut content = [0xffu8; SECTOR_SIZE];
+        if let Some((offset, data)) = program {
+            let old = self.sector_address(self.map[logical] as usize);
+            self.flash.read(old, SECTOR_SIZE, &mut content)?;
+            for (byte, new) in content[offset..offset + data.len()].iter_mut().zip(data) {
+                *byte &= new;
+            }
+        }
+        let spares = self.config.sectors..self.config.sectors + self.config.spares;
+        while let Some(spare) = spares.clone().find(|p| self.is_free(*p)) {
+            let address = self.sector_address(spare);
+            //Only a spare that fails to erase or program is retired, other errors are returned.
+            let mut good = match self.flash.delete(Delete::SectorErase, address) {
+                Ok(()) => self.erased(address)?,
+                Err(Error::VerifyMismatch) => false,
+                Err(e) => return Err(e),
+            };
+            for (i, page) in content.chunks(CHUNK).enumerate() {
+                if good && page.iter().any(|b| *b != 0xff) {
+                    good = self.write_checked(address + (i * CHUNK) as u32, page)?;
+                }
+            }
+            if good {
+                self.bad[self.map[logical] as usize] = true;
+                self.map[logical] = spare as u8;
+                return self.append(record(REMAP, logical as u8, spare as u8, 0));
+            }
+            self.bad[spare] = true;
+            self.append(record(BAD_SPARE, 0, spare as u8, 0))?;
+        }
+        Err(Error::VerifyMismatch) //Out of spares
+    }
+
+    fn erase_managed(&mut self, logical: usize) -> Result<(), Error> {
+        let address = self.sector_address(self.map[logical] as usize);
+        self.flash.delete(Delete::SectorErase, address)?;
+        if !self.erased(address)? {
+            return self.replace(logical, None);
+        }
+        Ok(())
+    }
+
+    //Physical address of an access within one sector.
+    fn physical(&self, addr: u32, len: usize) -> Result<u32, Error> {
+        if addr as usize % SECTOR_SIZE + len > SECTOR_SIZE {
+            return Err(Error::OutOfRange);
+        }
+        match self.locate(addr) {
+            Location::Managed(logical) => {
+                Ok(self.sector_address(self.map[logical] as usize) + addr % SECTOR_SIZE as u32)
+            }
+            Location::Reserved => Err(Error::OutOfRange),
+            Location::Other => Ok(addr),
+        }
+    }
+
+    //Splits an access into parts within one sector.
+    fn for_each_sector<A>(&mut self, addr: u32, len: usize, mut access: A) -> Result<(), Error>
+    where
+        A: FnMut(&mut Self, u32, usize, usize) -> Result<(), Error>,
+    {
+        let mut index = 0;
+        while index < len {
+            let address = addr + index as u32;
+            let chunk = (SECTOR_SIZE - address as usize % SECTOR_SIZE).min(len - index);
+            access(self, address, index, chunk)?;
+            index += chunk;
+        }
+        Ok(())
+    }
+}
+
+enum Location {
+    Managed(usize),
+    Reserved,
+    Other,
+}
+
+impl<F: NorFlash, const N: usize> NorFlash for BadSectors<F, N> {
+    fn read(&mut self, addr: u32, len: usize, data: &mut [u8]) -> Result<(), Error> {
+        let len = len.min(data.len());
+        self.for_each_sector(addr, len, |s, address, index, chunk| {
+            let physical = s.physical(address, chunk)?;
+            s.flash
+                .read(physical, chunk, &mut data[index..index + chunk])
+        })
+    }
+
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        self.for_each_sector(addr, data.len(), |s, address, index, chunk| {
+            let part = &data[index..index + chunk];
+            match s.locate(address) {
+                Location::Managed(logical) => {
+                    let offset = address as usize % SECTOR_SIZE;
+                    let physical = s.sector_address(s.map[logical] as usize) + offset as u32;
+                    if !s.write_checked(physical, part)? {
+                        s.replace(logical, Some((offset, part)))?;
+                    }
+                    Ok(())
+                }
+                Location::Reserved => Err(Error::OutOfRange),
+                Location::Other => s.flash.write(address, part),
+            }
+        })
+    }
+
+    //Blocks touching the region, the spares or the table are erased sector by sector.
+    fn delete(&mut self, option: Delete, addr: u32) -> Result<(), Error> {
+        let (size, capacity) = (self.flash.info().block_size, self.flash.info().capacity());
+        let (first, len) = match option {
+            Delete::SectorErase => (addr & !0xfff, 0x1000),
+            Delete::BlockErase32 => (addr & !0x7fff, 0x8000),
+            Delete::BlockErase64 => (addr & !0xffff, 0x10000),
+            Delete::ChipErase => (0, capacity),
+        };
+        let step = len.min(size);
+        for block in (first..first + len).step_by(step as usize) {
+            let touched = (block..block + step)
+                .step_by(SECTOR_SIZE)
+                .any(|sector| !matches!(self.locate(sector), Location::Other));
+            if !touched {
+                let option = match step {
+                    0x1000 => Delete::SectorErase,
+                    0x8000 => Delete::BlockErase32,
+                    _ => Delete::BlockErase64,
+                };
+                self.flash.delete(option, block)?;
+                continue;
+            }
+            for sector in (block..block + step).step_by(SECTOR_SIZE) {
+                match self.locate(sector) {
+                    Location::Managed(logical) => self.erase_managed(logical)?,
+                    Location::Reserved if len == SECTOR_SIZE as u32 => {
+                        return Err(Error::OutOfRange)
+                    }
+                    Location::Reserved => {}
+                    Location::Other => self.flash.delete(Delete::SectorErase, sector)?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_busy(&mut self) -> Result<bool, Error> {
+        self.flash.is_busy()
+    }
+
+    fn info(&self) -> &FlashInfo {
+        self.flash.info()
+    }
+}
+
+//A DMA program in the region is read back when its transfer is collected, and the sector
+//replaced if it failed.
+impl<F: DmaFlash, const N: usize> DmaFlash for BadSectors<F, N> {
+    fn start_read(
+        &mut self,
+        addr: u32,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        match self.physical(addr, len) {
+            Ok(physical) => self.flash.start_read(physical, buffer, len),
+            Err(e) => Err((e, buffer)),
+        }
+    }
+
+    fn start_write_page(
+        &mut self,
+        addr: u32,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        let physical = match self.physical(addr, len) {
+            Ok(physical) => physical,
+            Err(e) => return Err((e, buffer)),
+        };
+        self.flash.start_write_page(physical, buffer, len)?;
+        if let Location::Managed(_) = self.locate(addr) {
+            self.written = Some((addr, len));
+        }
+        Ok(())
+    }
+
+    fn poll_transfer(
+        &mut self,
+    ) -> nb::Result<&'static mut [u8], (Error, Option<&'static mut [u8]>)> {
+        let buffer = match self.flash.poll_transfer() {
+            Ok(buffer) => buffer,
+            Err(nb::Error::WouldBlock) => return Err(nb::Error::WouldBlock),
+            Err(e) => {
+                self.written = None;
+                return Err(e);
+            }
+        };
+        let Some((addr, len)) = self.written.take() else {
+            return Ok(buffer);
+        };
+        let (physical, offset) = (self.physical(addr, len), addr as usize % SECTOR_SIZE);
+        let checked = physical.and_then(|physical| {
+            if !self.programmed(physical, &buffer[..len])? {
+                if let Location::Managed(logical) = self.locate(addr) {
+                    self.replace(logical, Some((offset, &buffer[..len])))?;
+                }
+            }
+            Ok(())
+        });
+        match checked {
+            Ok(()) => Ok(buffer),
+            Err(e) => Err(nb::Error::Other((e, Some(buffer)))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimFlash;
+
+    const CONFIG: BadSectorConfig = BadSectorConfig {
+        start: 0,
+        sectors: 3,
+        spares: 2,
+        table: 0x5000,
+    };
+    type Sim = SimFlash<0x10000>;
+
+    fn mounted(flash: Sim) -> BadSectors<Sim, 8> {
+        let mut bad = BadSectors::new(flash, CONFIG);
+        bad.load().unwrap();
+        bad
+    }
+
+    #[test]
+    fn failed_program_moves_sector_to_spare() {
+        let mut flash = mounted(Sim::new());
+        flash.write(0x1000, &[0x11; 256]).unwrap();
+        let mut inner = flash.release();
+        inner.worn = 1 << 1;
+        let mut flash = mounted(inner);
+        flash.write(0x1100, &[0x22; 256]).unwrap();
+        let mut data = [0u8; 512];
+        flash.read(0x1000, 512, &mut data).unwrap();
+        assert_eq!(data[..256], [0x11; 256]);
+        assert_eq!(data[256..], [0x22; 256]);
+        assert_eq!(flash.bad_count(), 1);
+        assert_eq!(flash.spares_left(), 1);
+
+        //The replacement survives a reset.
+        let mut flash = mounted(flash.release());
+        flash.read(0x1000, 512, &mut data).unwrap();
+        assert_eq!(data[256..], [0x22; 256]);
+        assert_eq!(flash.spares_left(), 1);
+    }
+
+    #[test]
+    fn failed_dma_program_moves_sector_to_spare() {
+        let mut flash = mounted(Sim::new());
+        flash.write(0x1000, &[0x11; 256]).unwrap();
+        let mut inner = flash.release();
+        inner.worn = 1 << 1;
+        let mut flash = mounted(inner);
+        let page: &'static mut [u8] = Box::leak(Box::new([0x22u8; 256]));
+        flash.start_write_page(0x1100, page, 256).ok().unwrap();
+        //The sector is only checked once the transfer is collected.
+        assert_eq!(flash.bad_count(), 0);
+        nb::block!(flash.poll_transfer()).ok().unwrap();
+        assert_eq!(flash.bad_count(), 1);
+
+        let buffer: &'static mut [u8] = Box::leak(Box::new([0u8; 512]));
+        flash.start_read(0x1000, buffer, 512).ok().unwrap();
+        let buffer = nb::block!(flash.poll_transfer()).ok().unwrap();
+        assert_eq!(buffer[..256], [0x11; 256]);
+        assert_eq!(buffer[256..], [0x22; 256]);
+        //A transfer may not leave its sector.
+        let (e, _) = flash.start_read(0xf00, buffer, 512).err().unwrap();
+        assert_eq!(e, Error::OutOfRange);
+    }
+
+    #[test]
+    fn failed_erase_skips_bad_spare() {
+        let mut inner = Sim::new();
+        inner.worn = (1 << 0) | (1 << 3); //Sector 0 and the first spare
+        let mut flash = mounted(inner);
+        flash.write(0x10, &[0x00; 4]).unwrap();
+        flash.delete(Delete::SectorErase, 0x0).unwrap();
+        let mut data = [0u8; 16];
+        flash.read(0x0, 16, &mut data).unwrap();
+        assert_eq!(data, [0xff; 16]);
+        assert_eq!(flash.bad_count(), 2);
+        assert_eq!(flash.spares_left(), 0);
+        //Sector 0 is on the second spare, and the first spare is recorded as bad.
+        let flash = mounted(flash.release());
+        assert_eq!(flash.map[0], 4);
+        assert!(flash.bad[0] && flash.bad[3] && !flash.bad[4]);
+    }
+
+    //Fails the next erase with a bus timeout, like a chip that stays busy.
+    struct Flaky {
+        sim: Sim,
+        timeouts: usize,
+    }
+
+    impl NorFlash for Flaky {
+        fn read(&mut self, addr: u32, len: usize, data: &mut [u8]) -> Result<(), Error> {
+            self.sim.read(addr, len, data)
+        }
+        fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+            self.sim.write(addr, data)
+        }
+        fn delete(&mut self, option: Delete, addr: u32) -> Result<(), Error> {
+            if self.timeouts > 0 {
+                self.timeouts -= 1;
+                return Err(Error::Timeout);
+            }
+            self.sim.delete(option, addr)
+        }
+        fn is_busy(&mut self) -> Result<bool, Error> {
+            self.sim.is_busy()
+        }
+        fn info(&self) -> &FlashInfo {
+            self.sim.info()
+        }
+    }
+
+    #[test]
+    fn timeout_does_not_retire_a_spare() {
+        let mut sim = Sim::new();
+        sim.worn = 1 << 0;
+        let mut flash = BadSectors::<Flaky, 8>::new(Flaky { sim, timeouts: 0 }, CONFIG);
+        flash.load().unwrap();
+        flash.flash().timeouts = 1;
+        assert_eq!(flash.write(0x10, &[0x00; 4]), Err(Error::Timeout));
+        assert_eq!((flash.bad_count(), flash.spares_left()), (0, 2));
+        //The same spare is used once the chip answers again.
+        flash.write(0x10, &[0x00; 4]).unwrap();
+        assert_eq!(flash.map[0], 3);
+        assert_eq!(flash.spares_left(), 1);
+    }
+
+    #[test]
+    fn torn_table_record_is_skipped() {
+        let mut inner = Sim::new();
+        inner.worn = 1 << 1;
+        let mut flash = mounted(inner);
+        flash.write(0x1000, &[0x00]).unwrap();
+        let end = CONFIG.table + flash.table_offset;
+        let mut inner = flash.release();
+        //Half a record, as left by a reset during the next replacement.
+        inner.write(end, &[REMAP, 2, 4]).unwrap();
+        inner.worn |= 1 << 2;
+        let mut flash = mounted(inner);
+        assert_eq!(flash.table_offset, end - CONFIG.table + RECORD_SIZE);
+        flash.write(0x2000, &[0x00]).unwrap();
+
+        let flash = mounted(flash.release());
+        assert_eq!(flash.map[..3], [0, 3, 4]);
+        assert_eq!(flash.bad_count(), 2);
+    }
+
+    #[test]
+    fn damaged_header_keeps_the_remaps() {
+        let mut inner = Sim::new();
+        inner.worn = 1 << 1;
+        let mut flash = mounted(inner);
+        flash.write(0x1000, &[0x11; 16]).unwrap();
+        let mut inner = flash.release();
+        inner.write(CONFIG.table, &[TABLE & 0x0f]).unwrap(); //Bits lost in orbit
+        let mut flash = mounted(inner);
+        assert_eq!(flash.map[..3], [0, 3, 2]);
+        let mut data = [0u8; 16];
+        flash.read(0x1000, 16, &mut data).unwrap();
+        assert_eq!(data, [0x11; 16]);
+
+        //A header torn by the first format, with nothing after it, is written again.
+        let mut inner = Sim::new();
+        inner.write(CONFIG.table, &[TABLE, 3]).unwrap();
+        let flash = mounted(inner);
+        let mut raw = [0u8; 8];
+        flash.release().read(CONFIG.table, 8, &mut raw).unwrap();
+        assert_eq!(raw, record(TABLE, 3, 2, 0));
+    }
+
+    #[test]
+    fn out_of_spares_and_reserved_sectors() {
+        let mut inner = Sim::new();
+        inner.worn = 0b111;
+        let mut flash = mounted(inner);
+        assert_eq!(flash.write(0x0, &[0x00]), Ok(()));
+        assert_eq!(flash.write(0x1000, &[0x00]), Ok(()));
+        assert_eq!(flash.write(0x2000, &[0x00]), Err(Error::VerifyMismatch));
+        assert_eq!(flash.write(0x3000, &[0x00]), Err(Error::OutOfRange));
+        assert_eq!(flash.read(0x5000, 1, &mut [0]), Err(Error::OutOfRange));
+        //A block erase skips the spares and the table.
+        flash.delete(Delete::BlockErase64, 0x0).unwrap();
+        assert_eq!(flash.spares_left(), 0);
+        assert_eq!(mounted(flash.release()).bad_count(), 2); //Sector 2 had no spare left
+    }
+}
@@ -0,0 +1,358 @@
+//Log structured key-value store for small configuration items.
+//Two sectors are used in turn. Records are appended to the active one, and the newest
+//record of a key wins. When the active sector is full, the newest record of every key is
+//copied to the other sector, whose header is written last, so an interrupted compaction
+//leaves the old sector in use. A reset during a write leaves a record with a bad CRC,
+//which is dropped at mount together with everything after it.
+//The store does not own the flash, it is passed to every call so it can be shared.
+use crate::crc::crc16;
+use crate::w25q128::Delete;
+use crate::{Error, NorFlash};
+
+const SECTOR_SIZE: u32 = 0x1000;
+const HEADER_SIZE: u32 = 8;
+const MAGIC: [u8; 2] = [0x4b, 0x56]; //"KV"
+const REMOVED: u8 = 0xfe; //Length of a record removing its key
+pub const MAX_VALUE: usize = 64;
+pub const MAX_KEY: u8 = 0xfe; //0xFF is erased flash
+
+//Sector header: |"KV"|seq (4 bytes, BE)|CRC-16|
+//Record:        |key|len|value|CRC-16 of key, len and value|
+pub struct KvStore {
+    start: u32,                         //Address of the two sectors
+    active: u32,                        //Address of the active sector
+    seq: u32,                           //Sequence number of the active sector
+    offset: u32,                        //Next free byte in the active sector
+    index: [u16; MAX_KEY as usize + 1], //Offset of the newest record of each key, 0 if none
+}
+
+fn header(seq: u32) -> [u8; 8] {
+    let s = seq.to_be_bytes();
+    let mut header = [MAGIC[0], MAGIC[1], s[0], s[1], s[2], s[3], 0, 0];
+    let crc = crc16(&header[..6]).to_be_bytes();
+    header[6..].copy_from_slice(&crc);
+    header
+}
+
+impl KvStore {
+    //Find the newest sector and rebuild the index. An empty or unreadable pair of sectors
+    //is formatted, and a sector with a torn record is compacted.
+    pub fn mount<F: NorFlash>(flash: &mut F, start: u32) -> Result<Self, Error> {
+        if !start.is_multiple_of(SECTOR_SIZE) || start + 2 * SECTOR_SIZE > flash.info().capacity() {
+            return Err(Error::OutOfRange);
+        }
+        let mut kv = KvStore {
+            start,
+            active: start,
+            seq: 0,
+            offset: HEADER_SIZE,
+            index: [0; MAX_KEY as usize + 1],
+        };
+        for sector in [start, start + SECTOR_SIZE] {
+            let mut raw = [0u8; 8];
+            flash.read(sector, raw.len(), &mut raw)?;
+            let seq = u32::from_be_bytes([raw[2], raw[3], raw[4], raw[5]]);
+            if raw == header(seq) && seq > kv.seq {
+                kv.active = sector;
+                kv.seq = seq;
+            }
+        }
+        if kv.seq == 0 {
+            //Nothing stored yet, the compaction formats the first sector.
+            kv.active = start + SECTOR_SIZE;
+            return kv.compact(flash).map(|_| kv);
+        }
+        if !kv.replay(flash)? {
+            kv.compact(flash)?;
+        }
+        Ok(kv)
+    }
+
+    //Index the records of the active sector. False if the free space is not erased.
+    fn replay<F: NorFlash>(&mut self, flash: &mut F) -> Result<bool, Error> {
+        let mut record = [0u8; MAX_VALUE + 4];
+        while self.offset + 4 <= SECTOR_SIZE {
+            let address = self.active + self.offset;
+            flash.read(address, 2, &mut record)?;
+            let (key, len) = (record[0], record[1]);
+            if key == 0xff && len == 0xff {
+                break;
+            }
+            let size = match record_size(len) {
+                Some(size) if self.offset + size <= SECTOR_SIZE => size,
+                _ => return Ok(false),
+            };
+            flash.read(address, size as usize, &mut record)?;
+            if !valid(&record[..size as usize]) {
+                return Ok(false); //Torn by a reset
+            }
+            self.index[key as usize] = if len == REMOVED {
+                0
+            } else {
+                self.offset as u16
+            };
+            self.offset += size;
+        }
+        //A reset early in a program can leave bits cleared before the first record byte.
+        let mut chunk = [0u8; 256];
+        let mut address = self.active + self.offset;
+        while address < self.active + SECTOR_SIZE {
+            let len = (self.active + SECTOR_SIZE - address).min(256) as usize;
+            flash.read(address, len, &mut chunk)?;
+            if chunk[..len].iter().any(|b| *b != 0xff) {
+                return Ok(false);
+            }
+            address += len as u32;
+        }
+        Ok(true)
+    }
+
+    //Copy the newest record of every key to the other sector.
+    fn compact<F: NorFlash>(&mut self, flash: &mut F) -> Result<(), Error> {
+        let next = if self.active == self.start {
+            self.start + SECTOR_SIZE
+        } else {
+            self.start
+        };
+        flash.delete(Delete::SectorErase, next)?;
+        let mut index = [0u16; MAX_KEY as usize + 1];
+        let mut offset = HEADER_SIZE;
+        let mut record = [0u8; MAX_VALUE + 4];
+        for key in 0..=MAX_KEY {
+            if self.index[key as usize] == 0 {
+                continue;
+            }
+            let size = self.read_record(flash, key, &mut record)?;
+            if !valid(&record[..size]) {
+                continue; //Lost, the old value can not be trusted
+            }
+            flash.write(next + offset, &record[..size])?;
+            index[key as usize] = offset as u16;
+            offset += size as u32;
+        }
+        flash.write(next, &header(self.seq + 1))?;
+        self.active = next;
+        self.seq += 1;
+        self.offset = offset;
+        self.index = index;
+        Ok(())
+    }
+
+    fn read_record<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        key: u8,
+        record: &mut [u8; MAX_VALUE + 4],
+    ) -> Result<usize, Error> {
+        let address = self.active + self.index[key as usize] as u32;
+        flash.read(address, 2, record)?;
+        let size = record_size(record[1]).unwrap_or(4).min(record.len() as u32) as usize;
+        flash.read(address, size, record)?;
+        Ok(size)
+    }
+
+    //Copies the value of key into value, and returns its length. None if it is not stored.
+    pub fn get<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        key: u8,
+        value: &mut [u8],
+    ) -> Result<Option<usize>, Error> {
+        if key > MAX_KEY || self.index[key as usize] == 0 {
+            return Ok(None);
+        }
+        let mut record = [0u8; MAX_VALUE + 4];
+        let size = self.read_record(flash, key, &mut record)?;
+        if !valid(&record[..size]) || record[0] != key {
+            return Err(Error::VerifyMismatch);
+        }
+        let len = (size - 4).min(value.len());
+        value[..len].copy_from_slice(&record[2..2 + len]);
+        Ok(Some(size - 4))
+    }
+
+    //A value of exactly L bytes, like a number stored with to_be_bytes.
+    pub fn get_array<F: NorFlash, const L: usize>(
+        &self,
+        flash: &mut F,
+        key: u8,
+    ) -> Result<Option<[u8; L]>, Error> {
+        let mut value = [0u8; L];
+        match self.get(flash, key, &mut value)? {
+            Some(len) if len == L => Ok(Some(value)),
+            _ => Ok(None),
+        }
+    }
+
+    //Store a value, unless it is already stored.
+    pub fn set<F: NorFlash>(&mut self, flash: &mut F, key: u8, value: &[u8]) -> Result<(), Error> {
+        if key > MAX_KEY || value.len() > MAX_VALUE {
+            return Err(Error::OutOfRange);
+        }
+        let mut current = [0u8; MAX_VALUE];
+        if let Ok(Some(len)) = self.get(flash, key, &mut current) {
+            if current[..len] == *value {
+                return Ok(());
+            }
+        }
+        self.append(flash, key, value.len() as u8, value)
+    }
+
+    pub fn remove<F: NorFlash>(&mut self, flash: &mut F, key: u8) -> Result<(), Error> {
+        if key > MAX_KEY || self.index[key as usize] == 0 {
+            return Ok(());
+        }
+        self.append(flash, key, REMOVED, &[])
+    }
+
+    fn append<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        key: u8,
+        len: u8,
+        value: &[u8],
+    ) -> Result<(), Error> {
+        let size = value.len() as u32 + 4;
+        if self.offset + size > SECTOR_SIZE {
+            self.compact(flash)?;
+            if self.offset + size > SECTOR_SIZE {
+                return Err(Error::OutOfRange); //Full of other keys
+            }
+        }
+        let mut record = [0u8; MAX_VALUE + 4];
+        record[0] = key;
+        record[1] = len;
+        record[2..2 + value.len()].copy_from_slice(value);
+        let crc = crc16(&record[..2 + value.len()]).to_be_bytes();
+        record[2 + value.len()..size as usize].copy_from_slice(&crc);
+        if let Err(e) = flash.write(self.active + self.offset, &record[..size as usize]) {
+            self.offset = SECTOR_SIZE; //Unknown content, compact before the next record
+            return Err(e);
+        }
+        self.index[key as usize] = if len == REMOVED {
+            0
+        } else {
+            self.offset as u16
+        };
+        self.offset += size;
+        Ok(())
+    }
+}
+
+fn record_size(len: u8) -> Option<u32> {
+    match len {
+        REMOVED => Some(4),
+        len if len as usize <= MAX_VALUE => Some(len as u32 + 4),
+        _ => None,
+    }
+}
+
+fn valid(record: &[u8]) -> bool {
+    let (data, crc) = record.split_at(record.len() - 2);
+    record[0] <= MAX_KEY && crc16(data).to_be_bytes() == *crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{PowerCut, SimFlash};
+
+    type Sim = SimFlash<0x2000>;
+
+    const KEYS: usize = 5;
+    const OPS: usize = 400;
+
+    //Operation i sets a key to a value of varying length, every 37th removes key 2.
+    fn op(i: usize) -> (u8, Option<Vec<u8>>) {
+        if i % 37 == 36 {
+            (2, None)
+        } else {
+            (i as u8 % KEYS as u8, Some(vec![i as u8; i % 20 + 1]))
+        }
+    }
+
+    fn expected(ops: usize) -> Vec<Option<Vec<u8>>> {
+        let mut state = vec![None; KEYS];
+        for i in 0..ops {
+            let (key, value) = op(i);
+            state[key as usize] = value;
+        }
+        state
+    }
+
+    //Runs all operations until the power is cut, and returns how many completed.
+    fn run(flash: &mut PowerCut<Sim>) -> usize {
+        let mut kv = match KvStore::mount(flash, 0) {
+            Ok(kv) => kv,
+            Err(_) => return 0,
+        };
+        for i in 0..OPS {
+            let result = match op(i) {
+                (key, Some(value)) => kv.set(flash, key, &value),
+                (key, None) => kv.remove(flash, key),
+            };
+            if result.is_err() {
+                return i;
+            }
+        }
+        OPS
+    }
+
+    fn stored(flash: &mut Sim, kv: &KvStore) -> Vec<Option<Vec<u8>>> {
+        (0..KEYS as u8)
+            .map(|key| {
+                let mut value = [0u8; MAX_VALUE];
+                let len = kv.get(flash, key, &mut value).unwrap()?;
+                Some(value[..len].to_vec())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+    }
+
+    #[test]
+    fn values_survive_remount_and_compaction() {
+        let mut flash = Sim::new();
+        let mut kv = KvStore::mount(&mut flash, 0).unwrap();
+        for i in 0..OPS {
+            match op(i) {
+                (key, Some(value)) => kv.set(&mut flash, key, &value).unwrap(),
+                (key, None) => kv.remove(&mut flash, key).unwrap(),
+            }
+        }
+        assert!(kv.seq > 1, "the operations should compact");
+        let kv = KvStore::mount(&mut flash, 0).unwrap();
+        assert_eq!(stored(&mut flash, &kv), expected(OPS));
+        assert_eq!(kv.get_array::<_, 1>(&mut flash, 9).unwrap(), None);
+    }
+
+    //Every possible reset point: after the power returns, the store holds the state
+    //before or after the interrupted operation, and keeps working.
+    #[test]
+    fn reset_at_any_point_keeps_a_consistent_state() {
+        let mut full = PowerCut::new(Sim::new(), usize::MAX);
+        assert_eq!(run(&mut full), OPS);
+        let total = full.used;
+        for budget in 0..total {
+            let mut cut = PowerCut::new(Sim::new(), budget);
+            let done = run(&mut cut);
+            let mut flash = cut.flash;
+            let mut kv = KvStore::mount(&mut flash, 0).unwrap();
+            let state = stored(&mut flash, &kv);
+            assert!(
+                state == expected(done) || state == expected(done + 1),
+                "reset after {} of {} writes, {} operations done",
+                budget,
+                total,
+                done
+            );
+            kv.set(&mut flash, 0, &[0xaa; 8]).unwrap();
+            let kv = KvStore::mount(&mut flash, 0).unwrap();
+            assert_eq!(kv.get_array(&mut flash, 0).unwrap(), Some([0xaa; 8]));
+        }
+        println!("{} reset points checked", total);
+    }
+}
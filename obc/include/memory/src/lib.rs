@@ -1,2 +1,130 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
+pub mod bad;
+pub mod crc;
+pub mod kv;
+pub mod log;
+pub mod sfdp;
+pub mod sim;
+pub mod status;
+pub mod stm32;
 pub mod w25q128;
+pub mod wear;
+
+use embedded_hal_1::spi::ErrorKind;
+use w25q128::{Delete, FlashInfo};
+
+//Errors reported by the flash driver and everything built on top of it.
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format)]
+pub enum Error {
+    Bus(ErrorKind),         //The SPI bus reported an error
+    OutOfRange,             //Address (or address + lenght) outside the memory
+    Timeout,                //The chip stayed busy for too long
+    VerifyMismatch,         //Read back after programming did not match
+    WriteProtected,         //The chip refused to enable writes
+    NoDevice,               //No chip answered the JEDEC ID
+    UnknownDevice([u8; 3]), //JEDEC ID of a chip that is not in the table
+    Unsupported,            //The chip can not do the requested operation
+}
+
+impl Error {
+    //Used with map_err on SPI results.
+    pub fn bus<E: embedded_hal_1::spi::Error>(e: E) -> Self {
+        Error::Bus(e.kind())
+    }
+}
+
+//SPI devices that can clock the data phase of a read on 2 or 4 lines, like the STM32 QUADSPI.
+//Instruction, address and dummy byte (header) are sent on IO0 only.
+pub trait MultiIo: embedded_hal_1::spi::SpiDevice {
+    fn read_wide(&mut self, header: &[u8], lines: u8, data: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+//SPI devices that can move the data phase of a transfer in the background (DMA).
+//The header (instruction and address) is sent first with chip select held, then the
+//first len bytes of buffer. The buffer is owned by the transfer, and handed back by
+//poll_transfer once chip select is released, also when the transfer failed. Ordinary
+//transactions wait for a running transfer to finish first.
+pub trait DmaDevice: embedded_hal_1::spi::SpiDevice {
+    #[allow(clippy::type_complexity)]
+    fn start_read(&mut self, header: &[u8], buffer: &'static mut [u8], len: usize) -> Result<(), (Self::Error, &'static mut [u8])>;
+    #[allow(clippy::type_complexity)]
+    fn start_write(&mut self, header: &[u8], buffer: &'static mut [u8], len: usize) -> Result<(), (Self::Error, &'static mut [u8])>;
+    //WouldBlock until the transfer is done. The buffer is only missing when there was no transfer.
+    #[allow(clippy::type_complexity)]
+    fn poll_transfer(&mut self) -> nb::Result<&'static mut [u8], (Self::Error, Option<&'static mut [u8]>)>;
+}
+
+//Hardware independent interface to a NOR flash.
+//Implemented by the W25Q128 driver and by the RAM backed simulator (sim::SimFlash),
+//so storage logic can be written once and tested on the host with:
+//cargo test --target x86_64-unknown-linux-gnu --lib
+pub trait NorFlash {
+    //Read a predefined lenght into a buffer reference (capped at the buffer size).
+    fn read(&mut self, addr: u32, len: usize, data: &mut [u8]) -> Result<(), Error>;
+    //Program data from addr, split on page boundaries. Bits only go from 1 to 0.
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), Error>;
+    //Erase the sector/block containing addr, or the whole chip. Erased bytes read 0xFF.
+    fn delete(&mut self, option: Delete, addr: u32) -> Result<(), Error>;
+    //True while a program or erase is in progress.
+    fn is_busy(&mut self) -> Result<bool, Error>;
+    //Geometry of the flash.
+    fn info(&self) -> &FlashInfo;
+
+    fn get_info_sectorsize(&self) -> u32 {
+        self.info().sector_size
+    }
+
+    //Write data at addr whatever is stored there. Where only 1 -> 0 bits change it is
+    //programmed directly, otherwise the sector is read, erased and written back with data
+    //merged in. Spans sectors. A power loss between erase and write back loses the sector.
+    fn update(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        let sector_size = self.info().sector_size as usize;
+        let page_size = self.info().page_size as usize;
+        let mut buffer = [0u8; 4096];
+        if sector_size > buffer.len() {
+            return Err(Error::Unsupported);
+        }
+        let mut address = addr;
+        let mut index = 0;
+        while index < data.len() {
+            let sector = address - address % sector_size as u32;
+            let offset = (address - sector) as usize;
+            let len = (sector_size - offset).min(data.len() - index);
+            let chunk = &data[index..index + len];
+
+            self.read(address, len, &mut buffer[..len])?;
+            let programmable = buffer[..len].iter().zip(chunk).all(|(old, new)| old & new == *new);
+            if !programmable {
+                self.read(sector, sector_size, &mut buffer[..sector_size])?;
+                buffer[offset..offset + len].copy_from_slice(chunk);
+                self.delete(Delete::SectorErase, sector)?;
+                //Erased pages are left alone
+                for (i, page) in buffer[..sector_size].chunks(page_size).enumerate() {
+                    if page.iter().any(|b| *b != 0xff) {
+                        self.write(sector + (i * page_size) as u32, page)?;
+                    }
+                }
+            } else if buffer[..len] != *chunk {
+                self.write(address, chunk)?;
+            }
+            address += len as u32;
+            index += len;
+        }
+        Ok(())
+    }
+}
+
+//NorFlash that can also read and program in the background, like w25q128::Memory over a
+//DmaDevice, with the same rules: the first len bytes of buffer are moved, and ordinary
+//accesses wait for a running transfer. The flash can be handed to other users between
+//start and poll_transfer. A program stays within a page, and through bad::BadSectors a
+//read within a sector.
+pub trait DmaFlash: NorFlash {
+    #[allow(clippy::type_complexity)]
+    fn start_read(&mut self, addr: u32, buffer: &'static mut [u8], len: usize) -> Result<(), (Error, &'static mut [u8])>;
+    #[allow(clippy::type_complexity)]
+    fn start_write_page(&mut self, addr: u32, buffer: &'static mut [u8], len: usize) -> Result<(), (Error, &'static mut [u8])>;
+    //WouldBlock until the transfer is done, see DmaDevice::poll_transfer.
+    #[allow(clippy::type_complexity)]
+    fn poll_transfer(&mut self) -> nb::Result<&'static mut [u8], (Error, Option<&'static mut [u8]>)>;
+}
@@ -0,0 +1,250 @@
+//Circular event log on a region of whole sectors.
+//Records have a fixed size, so a sector holds a whole number of them. When the newest
+//sector is full, the next sector in the ring is erased and the oldest records are lost.
+//Every record carries a sequence number and a CRC. A record torn by a reset fails the
+//CRC, and its slot is skipped. The newest sector is the one whose records have the
+//highest sequence numbers, so nothing but the records is stored.
+//Like the key-value store the log does not own the flash, it is passed to every call.
+use crate::crc::crc16;
+use crate::w25q128::Delete;
+use crate::{Error, NorFlash};
+
+const SECTOR_SIZE: u32 = 0x1000;
+pub const RECORD_SIZE: u32 = 20;
+const SLOTS: u32 = SECTOR_SIZE / RECORD_SIZE; //Records per sector
+
+//Record: |seq (4 bytes)|time (4 bytes)|source|kind|data (8 bytes)|CRC-16|, BE.
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub struct Event {
+    pub seq: u32,      //Sequence number, one higher for every record
+    pub time: u32,     //RTC time (unix) of the record
+    pub source: u8,    //Who logged it, like a CAN transmitter ID
+    pub kind: u8,      //What it is, chosen by the source
+    pub data: [u8; 8], //One CAN frame of data
+}
+
+impl Event {
+    fn to_bytes(self) -> [u8; RECORD_SIZE as usize] {
+        let mut raw = [0u8; RECORD_SIZE as usize];
+        raw[..4].copy_from_slice(&self.seq.to_be_bytes());
+        raw[4..8].copy_from_slice(&self.time.to_be_bytes());
+        raw[8] = self.source;
+        raw[9] = self.kind;
+        raw[10..18].copy_from_slice(&self.data);
+        let crc = crc16(&raw[..18]).to_be_bytes();
+        raw[18..].copy_from_slice(&crc);
+        raw
+    }
+
+    //None for an erased or torn record.
+    fn from_bytes(raw: &[u8; RECORD_SIZE as usize]) -> Option<Event> {
+        if crc16(&raw[..18]).to_be_bytes() != raw[18..] || raw.iter().all(|b| *b == 0xff) {
+            return None;
+        }
+        let mut data = [0u8; 8];
+        data.copy_from_slice(&raw[10..18]);
+        Some(Event {
+            seq: u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]),
+            time: u32::from_be_bytes([raw[4], raw[5], raw[6], raw[7]]),
+            source: raw[8],
+            kind: raw[9],
+            data,
+        })
+    }
+}
+
+pub struct EventLog {
+    start: u32,   //Address of the first sector
+    sectors: u32, //Sectors in the ring, at least 2
+    head: u32,    //Slot of the next record, counted from the first slot of the region
+    seq: u32,     //Sequence number of the next record
+}
+
+impl EventLog {
+    //Find the newest record, and continue after it.
+    pub fn mount<F: NorFlash>(flash: &mut F, start: u32, sectors: u32) -> Result<Self, Error> {
+        let end = start as u64 + sectors as u64 * SECTOR_SIZE as u64;
+        if !start.is_multiple_of(SECTOR_SIZE) || sectors < 2 || end > flash.info().capacity() as u64
+        {
+            return Err(Error::OutOfRange);
+        }
+        let mut log = EventLog {
+            start,
+            sectors,
+            head: 0,
+            seq: 0,
+        };
+        let mut newest: Option<(u32, u32)> = None; //Sector and highest sequence number
+        for sector in 0..sectors {
+            if let Some(event) = log.first_in(flash, sector)? {
+                if newest.is_none_or(|(_, seq)| event.seq > seq) {
+                    newest = Some((sector, event.seq));
+                }
+            }
+        }
+        if let Some((sector, _)) = newest {
+            //Continue after the last used slot, torn or not.
+            for slot in sector * SLOTS..(sector + 1) * SLOTS {
+                let raw = log.read_slot(flash, slot)?;
+                if raw.iter().any(|b| *b != 0xff) {
+                    log.head = (slot + 1) % (sectors * SLOTS);
+                }
+                if let Some(event) = Event::from_bytes(&raw) {
+                    log.seq = log.seq.max(event.seq + 1);
+                }
+            }
+        }
+        Ok(log)
+    }
+
+    fn slot_address(&self, slot: u32) -> u32 {
+        let sector = slot / SLOTS % self.sectors;
+        self.start + sector * SECTOR_SIZE + (slot % SLOTS) * RECORD_SIZE
+    }
+
+    fn read_slot<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        slot: u32,
+    ) -> Result<[u8; RECORD_SIZE as usize], Error> {
+        let mut raw = [0u8; RECORD_SIZE as usize];
+        flash.read(self.slot_address(slot), raw.len(), &mut raw)?;
+        Ok(raw)
+    }
+
+    //First valid record of a sector.
+    fn first_in<F: NorFlash>(&self, flash: &mut F, sector: u32) -> Result<Option<Event>, Error> {
+        for slot in sector * SLOTS..(sector + 1) * SLOTS {
+            if let Some(event) = Event::from_bytes(&self.read_slot(flash, slot)?) {
+                return Ok(Some(event));
+            }
+        }
+        Ok(None)
+    }
+
+    //Sequence number the next record gets.
+    pub fn next_seq(&self) -> u32 {
+        self.seq
+    }
+
+    //Sequence number of the oldest record still stored, None if the log is empty.
+    pub fn oldest<F: NorFlash>(&self, flash: &mut F) -> Result<Option<u32>, Error> {
+        Ok(self.read_from(flash, 0)?.map(|event| event.seq))
+    }
+
+    //Store a record, and return its sequence number.
+    pub fn append<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        time: u32,
+        source: u8,
+        kind: u8,
+        data: [u8; 8],
+    ) -> Result<u32, Error> {
+        if self.head.is_multiple_of(SLOTS) {
+            //Entering a sector: it holds the oldest records.
+            let sector = self.head / SLOTS % self.sectors;
+            flash.delete(Delete::SectorErase, self.start + sector * SECTOR_SIZE)?;
+        }
+        let event = Event {
+            seq: self.seq,
+            time,
+            source,
+            kind,
+            data,
+        };
+        let slot = self.head;
+        //The slot is used even if the write fails, it may hold part of the record.
+        self.head = (self.head + 1) % (self.sectors * SLOTS);
+        self.seq += 1;
+        flash.write(self.slot_address(slot), &event.to_bytes())?;
+        Ok(event.seq)
+    }
+
+    //Oldest record with a sequence number of at least seq. Records lost to a reset are
+    //skipped, so the result can have a higher sequence number than asked for.
+    pub fn read_from<F: NorFlash>(&self, flash: &mut F, seq: u32) -> Result<Option<Event>, Error> {
+        //Start in the last sector beginning at or before seq, or the oldest sector.
+        let slots = self.sectors * SLOTS;
+        let newest = (self.head + slots - 1) % slots / SLOTS; //Sector of the last record
+        let mut from = (newest + 1) % self.sectors;
+        for i in 1..=self.sectors {
+            let sector = (newest + i) % self.sectors;
+            if let Some(first) = self.first_in(flash, sector)? {
+                if first.seq <= seq {
+                    from = sector;
+                }
+            }
+        }
+        for i in 0..self.sectors {
+            let sector = (from + i) % self.sectors;
+            for slot in sector * SLOTS..(sector + 1) * SLOTS {
+                if let Some(event) = Event::from_bytes(&self.read_slot(flash, slot)?) {
+                    if event.seq >= seq && event.seq < self.seq {
+                        return Ok(Some(event));
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimFlash;
+
+    type Sim = SimFlash<0x4000>;
+
+    #[test]
+    fn oldest_sector_is_evicted() {
+        let mut flash = Sim::new();
+        let mut log = EventLog::mount(&mut flash, 0x1000, 3).unwrap();
+        assert_eq!(log.oldest(&mut flash).unwrap(), None);
+        let total = 3 * SLOTS + 10;
+        for i in 0..total {
+            let seq = log
+                .append(&mut flash, 1000 + i, 2, 0, [i as u8; 8])
+                .unwrap();
+            assert_eq!(seq, i);
+            if i == 3 * SLOTS - 1 {
+                //Full, nothing evicted yet.
+                assert_eq!(log.oldest(&mut flash).unwrap(), Some(0));
+            }
+        }
+        //The first sector was erased for the last 10 records.
+        assert_eq!(log.oldest(&mut flash).unwrap(), Some(SLOTS));
+        assert_eq!(log.read_from(&mut flash, 0).unwrap().unwrap().seq, SLOTS);
+        let event = log.read_from(&mut flash, total - 1).unwrap().unwrap();
+        assert_eq!(event.time, 1000 + total - 1);
+        assert_eq!(event.data, [(total - 1) as u8; 8]);
+        assert_eq!(log.read_from(&mut flash, total).unwrap(), None);
+        assert_eq!(flash.as_slice()[..0x1000], [0xff; 0x1000]); //Outside the region
+
+        //A remount continues the sequence.
+        let mut log = EventLog::mount(&mut flash, 0x1000, 3).unwrap();
+        assert_eq!(log.next_seq(), total);
+        log.append(&mut flash, 0, 2, 0, [0; 8]).unwrap();
+        assert_eq!(
+            log.read_from(&mut flash, total).unwrap().unwrap().seq,
+            total
+        );
+    }
+
+    #[test]
+    fn torn_record_is_skipped() {
+        let mut flash = Sim::new();
+        let mut log = EventLog::mount(&mut flash, 0, 2).unwrap();
+        for i in 0..5 {
+            log.append(&mut flash, i, 1, 1, [0; 8]).unwrap();
+        }
+        //Half of record 5, as left by a reset.
+        flash.write(5 * RECORD_SIZE, &[0, 0, 0, 5, 0, 0]).unwrap();
+        let mut log = EventLog::mount(&mut flash, 0, 2).unwrap();
+        assert_eq!(log.next_seq(), 5);
+        assert_eq!(log.append(&mut flash, 9, 1, 1, [0; 8]).unwrap(), 5);
+        assert_eq!(log.read_from(&mut flash, 5).unwrap().unwrap().time, 9);
+        assert_eq!(log.read_from(&mut flash, 4).unwrap().unwrap().seq, 4);
+    }
+}
@@ -0,0 +1,164 @@
+//JEDEC Serial Flash Discoverable Parameters (JESD216), read with instruction 0x5A.
+//Only the Basic Flash Parameter Table is used: density, page size, erase types and the
+//supported read instructions. Memory::read_sfdp reads the tables from the chip.
+use crate::w25q128::FlashInfo;
+use crate::Error;
+
+pub const HEADER_SIZE: usize = 16; //SFDP header and the first parameter header
+pub const MAX_TABLE: usize = 16 * 4; //Basic table DWORDs used, JESD216B has 16
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EraseType {
+    pub size: u32, //Bytes
+    pub opcode: u8,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Sfdp {
+    pub info: FlashInfo,
+    pub erase_types: [Option<EraseType>; 4],
+    pub dual_output: bool, //1-1-2 fast read, 0x3B
+    pub quad_output: bool, //1-1-4 fast read, 0x6B
+}
+
+fn dword(table: &[u8], n: usize) -> u32 {
+    let i = (n - 1) * 4; //DWORDs are numbered from 1 in the standard
+    u32::from_le_bytes([table[i], table[i + 1], table[i + 2], table[i + 3]])
+}
+
+//Address and length in bytes of the basic table, from the start of the SFDP area.
+//|"SFDP"|minor|major|headers - 1|0xFF| then |ID LSB|minor|major|DWORDs|pointer (3 bytes)|ID MSB|
+pub fn basic_table(header: &[u8; HEADER_SIZE]) -> Result<(u32, usize), Error> {
+    if header[..4] != *b"SFDP" || header[8] != 0x00 || header[15] != 0xff {
+        return Err(Error::Unsupported);
+    }
+    let address = u32::from_le_bytes([header[12], header[13], header[14], 0]);
+    Ok((address, header[11] as usize * 4))
+}
+
+//Parse the basic table. Parts that only take 4 byte addresses are not supported.
+pub fn parse_basic(table: &[u8]) -> Result<Sfdp, Error> {
+    if table.len() < 9 * 4 {
+        return Err(Error::Unsupported); //JESD216 has at least 9 DWORDs
+    }
+    let dw1 = dword(table, 1);
+    if (dw1 >> 17) & 0b11 == 0b10 {
+        return Err(Error::Unsupported);
+    }
+    //Density in bits: N + 1, or 2^N with bit 31 set.
+    let dw2 = dword(table, 2);
+    let bits = if dw2 & 0x8000_0000 > 0 {
+        1u64 << (dw2 & 0x7fff_ffff).min(63)
+    } else {
+        dw2 as u64 + 1
+    };
+    let capacity = (bits / 8).min(1 << 31) as u32; //Far beyond 3 byte addresses anyway
+
+    //Erase types: |opcode|size as 2^N| twice in DWORD 8 and 9, N = 0 when unused.
+    let mut erase_types = [None; 4];
+    for (i, erase) in erase_types.iter_mut().enumerate() {
+        let field = dword(table, 8 + i / 2) >> (16 * (i % 2));
+        let size = field & 0xff;
+        if size != 0 && size < 32 {
+            *erase = Some(EraseType {
+                size: 1 << size,
+                opcode: (field >> 8) as u8,
+            });
+        }
+    }
+    //Older tables only give the 4K erase in DWORD 1.
+    if erase_types.iter().all(|e| e.is_none()) && dw1 & 0b11 == 0b01 {
+        erase_types[0] = Some(EraseType {
+            size: 0x1000,
+            opcode: (dw1 >> 8) as u8,
+        });
+    }
+    let sizes = erase_types.iter().flatten().map(|e| e.size);
+    let sector_size = sizes.clone().min().ok_or(Error::Unsupported)?;
+    let block_size = sizes.max().unwrap_or(sector_size);
+
+    //Page size is in DWORD 11 since JESD216A, before that only "64 bytes or more".
+    let page_size: u32 = if table.len() >= 11 * 4 {
+        1 << ((dword(table, 11) >> 4) & 0xf)
+    } else if dw1 & 0b100 > 0 {
+        256
+    } else {
+        return Err(Error::Unsupported); //Single byte programming
+    };
+    Ok(Sfdp {
+        info: FlashInfo {
+            page_size: page_size as u16,
+            sector_size,
+            page_count: capacity / page_size,
+            sector_count: capacity / sector_size,
+            block_size,
+            block_count: capacity / block_size,
+            capacity_mbit: (bits >> 20) as u32,
+        },
+        erase_types,
+        dual_output: dw1 & (1 << 16) > 0,
+        quad_output: dw1 & (1 << 22) > 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(dwords: &[u32]) -> Vec<u8> {
+        dwords.iter().flat_map(|d| d.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn header_points_at_basic_table() {
+        let header = *b"SFDP\x06\x01\x00\xff\x00\x06\x01\x10\x80\x00\x00\xff";
+        assert_eq!(basic_table(&header), Ok((0x80, 64)));
+        let mut bad = header;
+        bad[0] = b'X';
+        assert_eq!(basic_table(&bad), Err(Error::Unsupported));
+    }
+
+    #[test]
+    fn jesd216b_table() {
+        //64 Mbit, 4K/32K/64K erase, 256 byte pages, 1-1-2 and 1-1-4 reads.
+        let mut dwords = [0xffff_ffffu32; 16];
+        dwords[0] = 0xfff9_20e5;
+        dwords[1] = 0x03ff_ffff;
+        dwords[7] = 0x520f_200c;
+        dwords[8] = 0x0000_d810;
+        dwords[10] = 0x0000_0080;
+        let sfdp = parse_basic(&table(&dwords)).unwrap();
+        assert_eq!(sfdp.info.capacity(), 8 << 20);
+        assert_eq!(sfdp.info.capacity_mbit, 64);
+        assert_eq!(sfdp.info.page_size, 256);
+        assert_eq!(
+            (sfdp.info.sector_size, sfdp.info.block_size),
+            (0x1000, 0x10000)
+        );
+        assert_eq!(
+            sfdp.erase_types[1],
+            Some(EraseType {
+                size: 0x8000,
+                opcode: 0x52
+            })
+        );
+        assert!(sfdp.dual_output && sfdp.quad_output);
+    }
+
+    #[test]
+    fn jesd216_table_and_power_of_two_density() {
+        //Original 9 DWORD table: 4K erase only in DWORD 1, density as 2^N bits.
+        let mut dwords = [0u32; 9];
+        dwords[0] = 0xff20_20e5 & !(1 << 22);
+        dwords[1] = 0x8000_0018; //2^24 bits, 16 Mbit
+        let sfdp = parse_basic(&table(&dwords)).unwrap();
+        assert_eq!(sfdp.info.capacity_mbit, 16);
+        assert_eq!(sfdp.info.sector_size, 0x1000);
+        assert_eq!(sfdp.info.block_size, 0x1000);
+        assert_eq!(sfdp.info.page_size, 256);
+        assert!(!sfdp.quad_output);
+        //4 byte addresses only
+        dwords[0] |= 0b10 << 17;
+        assert_eq!(parse_basic(&table(&dwords)).err(), Some(Error::Unsupported));
+    }
+}
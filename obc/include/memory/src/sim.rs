@@ -0,0 +1,1022 @@
+//RAM backed flash simulator with W25Q128 semantics.
+//Programming can only clear bits (1 -> 0), erasing sets bytes to 0xFF,
+//and a single page program wraps around at the page boundary, 256 bytes unless set.
+use crate::w25q128::{Delete, FlashInfo, RELEASE_NS};
+use crate::{DmaDevice, DmaFlash, Error, MultiIo, NorFlash};
+use embedded_hal_1::spi::{ErrorKind, ErrorType, Operation, SpiDevice};
+
+const PAGE_SIZE: u32 = 256;
+const SECTOR_SIZE: u32 = 0x1000;
+const BLOCK32_SIZE: u32 = 0x8000;
+const BLOCK64_SIZE: u32 = 0x10000;
+
+//SIZE is the simulated capacity in bytes, and should be a multiple of the sector size.
+pub struct SimFlash<const SIZE: usize> {
+    mem: [u8; SIZE],
+    flash: FlashInfo,
+    pub worn: u64, //Bit n set: sector n no longer erases or programs
+    transfer: Option<&'static mut [u8]>, //Background transfer, done on the second poll
+    polled: bool,
+}
+
+impl<const SIZE: usize> SimFlash<SIZE> {
+    //Creates an erased flash.
+    pub fn new() -> Self {
+        Self::with_page_size(PAGE_SIZE)
+    }
+
+    //Creates an erased flash with pages of page_size bytes, a power of two.
+    pub fn with_page_size(page_size: u32) -> Self {
+        let size = SIZE as u32;
+        SimFlash {
+            mem: [0xff; SIZE],
+            flash: FlashInfo {
+                page_size: page_size as u16,
+                sector_size: SECTOR_SIZE,
+                page_count: size / page_size,
+                sector_count: size / SECTOR_SIZE,
+                block_size: BLOCK64_SIZE,
+                block_count: size / BLOCK64_SIZE,
+                capacity_mbit: size / (0x100000 / 8),
+            },
+            worn: 0,
+            transfer: None,
+            polled: false,
+        }
+    }
+
+    //Raw view of the simulated memory, for inspection in tests.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.mem
+    }
+
+    //Maps an address onto the simulated memory, wrapping like the chip does at the end.
+    fn index(&self, addr: u32) -> usize {
+        addr as usize % SIZE
+    }
+
+    fn check_range(&self, addr: u32, len: usize) -> Result<(), Error> {
+        if addr as usize + len > SIZE {
+            return Err(Error::OutOfRange);
+        }
+        Ok(())
+    }
+
+    fn is_worn(&self, addr: u32) -> bool {
+        let sector = addr / SECTOR_SIZE;
+        sector < 64 && self.worn & (1 << sector) > 0
+    }
+
+    //Programming a page: bits can only be cleared, and the address wraps within the page.
+    fn write_page(&mut self, addr: u32, data: &[u8]) {
+        if self.is_worn(addr) {
+            return;
+        }
+        let page = self.flash.page_size as u32;
+        let page_start = addr & !(page - 1);
+        let mut offset = addr & (page - 1);
+        for byte in data {
+            let i = self.index(page_start + offset);
+            self.mem[i] &= *byte;
+            offset = (offset + 1) % page;
+        }
+    }
+
+    //Set an aligned area to 0xFF
+    fn erase(&mut self, addr: u32, size: u32) {
+        let start = self.index(addr & !(size - 1));
+        let end = (start + size as usize).min(SIZE);
+        for sector in (start..end).step_by(SECTOR_SIZE as usize) {
+            if !self.is_worn(sector as u32) {
+                self.mem[sector..sector + SECTOR_SIZE as usize].fill(0xff);
+            }
+        }
+    }
+}
+
+impl<const SIZE: usize> Default for SimFlash<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SIZE: usize> NorFlash for SimFlash<SIZE> {
+    fn read(&mut self, addr: u32, len: usize, data: &mut [u8]) -> Result<(), Error> {
+        let len = len.min(data.len()); //Read cap at buffersize.
+        self.check_range(addr, len)?;
+        let start = addr as usize;
+        data[..len].copy_from_slice(&self.mem[start..start + len]);
+        Ok(())
+    }
+
+    //Same page splitting as w25q128::Memory::write
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        self.check_range(addr, data.len())?;
+        let page = self.flash.page_size as u32;
+        let mut address = addr;
+        let mut index = 0;
+        while index < data.len() {
+            let room = (page - (address & (page - 1))) as usize;
+            let end = (index + room).min(data.len());
+            self.write_page(address, &data[index..end]);
+            address += (end - index) as u32;
+            index = end;
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, option: Delete, addr: u32) -> Result<(), Error> {
+        self.check_range(addr, 1)?;
+        match option {
+            Delete::SectorErase => self.erase(addr, SECTOR_SIZE),
+            Delete::BlockErase32 => self.erase(addr, BLOCK32_SIZE),
+            Delete::BlockErase64 => self.erase(addr, BLOCK64_SIZE),
+            Delete::ChipErase => self.erase(0, SIZE as u32),
+        }
+        Ok(())
+    }
+
+    //Operations complete instantly.
+    fn is_busy(&mut self) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    fn info(&self) -> &FlashInfo {
+        &self.flash
+    }
+}
+
+//The transfer happens at start, poll_transfer pretends it took a while.
+impl<const SIZE: usize> DmaFlash for SimFlash<SIZE> {
+    fn start_read(
+        &mut self,
+        addr: u32,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        if self.transfer.is_some() {
+            return Err((Error::Bus(ErrorKind::Other), buffer));
+        }
+        if len > buffer.len() {
+            return Err((Error::OutOfRange, buffer));
+        }
+        if let Err(e) = self.read(addr, len, buffer) {
+            return Err((e, buffer));
+        }
+        self.transfer = Some(buffer);
+        self.polled = false;
+        Ok(())
+    }
+
+    fn start_write_page(
+        &mut self,
+        addr: u32,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        if self.transfer.is_some() {
+            return Err((Error::Bus(ErrorKind::Other), buffer));
+        }
+        let page = self.flash.page_size as u32;
+        if len > buffer.len() || addr % page + len as u32 > page {
+            return Err((Error::OutOfRange, buffer));
+        }
+        if let Err(e) = self.write(addr, &buffer[..len]) {
+            return Err((e, buffer));
+        }
+        self.transfer = Some(buffer);
+        self.polled = false;
+        Ok(())
+    }
+
+    fn poll_transfer(
+        &mut self,
+    ) -> nb::Result<&'static mut [u8], (Error, Option<&'static mut [u8]>)> {
+        let Some(buffer) = self.transfer.take() else {
+            return Err(nb::Error::Other((Error::Bus(ErrorKind::Other), None)));
+        };
+        if !self.polled {
+            self.polled = true;
+            self.transfer = Some(buffer);
+            return Err(nb::Error::WouldBlock);
+        }
+        Ok(buffer)
+    }
+}
+
+//Wraps a flash and cuts the power after a number of programmed bytes and erases, to test
+//what a reset leaves behind. The byte being programmed is left half done, an interrupted
+//erase only clears the first 2K, and nothing can be read after the cut.
+pub struct PowerCut<F> {
+    pub flash: F,
+    pub budget: usize, //Bytes and erases before the cut, usize::MAX for none
+    pub used: usize,
+}
+
+impl<F: NorFlash> PowerCut<F> {
+    pub fn new(flash: F, budget: usize) -> Self {
+        PowerCut {
+            flash,
+            budget,
+            used: 0,
+        }
+    }
+
+    fn spend(&mut self) -> bool {
+        if self.used == self.budget {
+            return false;
+        }
+        self.used += 1;
+        true
+    }
+}
+
+impl<F: NorFlash> NorFlash for PowerCut<F> {
+    fn read(&mut self, addr: u32, len: usize, data: &mut [u8]) -> Result<(), Error> {
+        if self.used == self.budget {
+            return Err(Error::Timeout);
+        }
+        self.flash.read(addr, len, data)
+    }
+
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        for (i, byte) in data.iter().enumerate() {
+            let address = addr + i as u32;
+            if !self.spend() {
+                self.flash.write(address, &[byte | 0xf0])?;
+                return Err(Error::Timeout);
+            }
+            self.flash.write(address, &[*byte])?;
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, option: Delete, addr: u32) -> Result<(), Error> {
+        if !self.spend() {
+            let mut half = [0u8; 0x800];
+            self.flash.read(addr + 0x800, 0x800, &mut half)?;
+            self.flash.delete(option, addr)?;
+            self.flash.write(addr + 0x800, &half)?;
+            return Err(Error::Timeout);
+        }
+        self.flash.delete(option, addr)
+    }
+
+    fn is_busy(&mut self) -> Result<bool, Error> {
+        self.flash.is_busy()
+    }
+
+    fn info(&self) -> &FlashInfo {
+        self.flash.info()
+    }
+}
+
+//A program spends the budget when it starts, a read after the cut fails.
+impl<F: DmaFlash> DmaFlash for PowerCut<F> {
+    fn start_read(
+        &mut self,
+        addr: u32,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        if self.used == self.budget {
+            return Err((Error::Timeout, buffer));
+        }
+        self.flash.start_read(addr, buffer, len)
+    }
+
+    fn start_write_page(
+        &mut self,
+        addr: u32,
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        if len <= buffer.len() && self.budget - self.used < len {
+            let e = self.write(addr, &buffer[..len]).unwrap_err();
+            return Err((e, buffer));
+        }
+        self.flash.start_write_page(addr, buffer, len)?;
+        self.used += len;
+        Ok(())
+    }
+
+    fn poll_transfer(
+        &mut self,
+    ) -> nb::Result<&'static mut [u8], (Error, Option<&'static mut [u8]>)> {
+        self.flash.poll_transfer()
+    }
+}
+
+//Mock SPI bus decoding the W25Q instruction set on top of a SimFlash.
+//Lets the w25q128::Memory opcode handling run on the host.
+pub struct SimSpi<const SIZE: usize> {
+    pub flash: SimFlash<SIZE>,
+    pub stuck: bool,                //Keep the BUSY bit set, like a hung chip
+    pub jedec_id: [u8; 3],          //Answer to 0x9F
+    pub unique_id: [u8; 8],         //Answer to 0x4B
+    pub sfdp: [u8; 0x100],          //SFDP area read with 0x5A, all ones for a part without it
+    pub security: [[u8; 0x100]; 3], //Security registers 1-3
+    pub status: [u8; 3],            //Writable bits of status register 1-3
+    pub erase_time: u32,            //Status reads a sector/block erase stays busy, 0 is instant
+    pub suspends: u32,              //Number of erase suspends
+    pub powered_down: bool,
+    pub ignored: u32,    //Instructions sent during power-down or before tRES1 passed
+    pub dma_error: bool, //The next DMA transfer ends with a bus error
+    waking_ns: u32,      //Time left of tRES1
+    erasing: Option<(Delete, u32, u32)>, //Erase in progress and the status reads left
+    suspended: bool,
+    wel: bool,       //Write enable latch
+    frame: [u8; 4],  //Instruction and address of the current chip select frame
+    count: usize,    //Bytes clocked in the current frame
+    page: [u8; 256], //Page program data, committed when chip select goes high
+    page_len: usize,
+    transfer: Option<&'static mut [u8]>, //"DMA" buffer, done on the second poll
+    polled: bool,
+}
+
+impl<const SIZE: usize> SimSpi<SIZE> {
+    pub fn new() -> Self {
+        SimSpi {
+            flash: SimFlash::new(),
+            stuck: false,
+            jedec_id: [0xef, 0x40, 0x18], //W25Q128JV
+            unique_id: *b"SIMFLASH",
+            sfdp: [0xff; 0x100],
+            security: [[0xff; 0x100]; 3],
+            wel: false,
+            status: [0; 3],
+            erase_time: 0,
+            suspends: 0,
+            powered_down: false,
+            ignored: 0,
+            waking_ns: 0,
+            erasing: None,
+            suspended: false,
+            frame: [0; 4],
+            count: 0,
+            page: [0; 256],
+            page_len: 0,
+            dma_error: false,
+            transfer: None,
+            polled: false,
+        }
+    }
+
+    fn address(&self) -> u32 {
+        u32::from_be_bytes([0, self.frame[1], self.frame[2], self.frame[3]])
+    }
+
+    //Security register addressed by the frame, and whether its LB bit leaves it writable.
+    fn security_register(&self) -> Option<(usize, bool)> {
+        match self.frame[2] >> 4 {
+            register @ 1..=3 => {
+                let locked = self.status[1] & (1 << (register + 2)) > 0;
+                Some((register as usize - 1, !locked))
+            }
+            _ => None,
+        }
+    }
+
+    //Status register 1 as seen by the driver. Operations complete instantly, so only busy when stuck.
+    fn status1(&self) -> u8 {
+        let busy = self.stuck || (self.erasing.is_some() && !self.suspended);
+        self.status[0] | (self.wel as u8) << 1 | busy as u8
+    }
+
+    //Each status read is one step of a running erase, which takes effect when it is done.
+    fn erase_step(&mut self) {
+        match self.erasing {
+            Some((option, address, left)) if !self.suspended => {
+                if left > 1 {
+                    self.erasing = Some((option, address, left - 1));
+                } else {
+                    self.erasing = None;
+                    self.flash.delete(option, address).ok();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    //Only Release Power-Down is accepted until tRES1 has passed after it.
+    fn asleep(&self) -> bool {
+        (self.powered_down || self.waking_ns > 0) && self.frame[0] != 0xab
+    }
+
+    //One byte on the bus: MOSI in, MISO out.
+    fn clock(&mut self, mosi: u8) -> u8 {
+        let index = self.count;
+        self.count += 1;
+        if index < self.frame.len() {
+            self.frame[index] = mosi;
+        }
+        if self.asleep() {
+            return 0xff; //MISO is not driven
+        }
+        match self.frame[0] {
+            0x05 if index > 0 => {
+                let status = self.status1();
+                self.erase_step();
+                status
+            }
+            0x35 if index > 0 => self.status[1],
+            0x15 if index > 0 => self.status[2],
+            0xab if index >= 4 => 0x17, //Device ID of the W25Q128
+            0x9f if index > 0 => *self.jedec_id.get(index - 1).unwrap_or(&0xff),
+            0x4b if index >= 5 => *self.unique_id.get(index - 5).unwrap_or(&0xff),
+            0x5a if index >= 5 => self.sfdp[(self.address() as usize + index - 5) % 0x100],
+            0x03 if index >= 4 => {
+                self.flash.mem[self.flash.index(self.address() + (index - 4) as u32)]
+            }
+            0x0b | 0x3b | 0x6b if index >= 5 => {
+                self.flash.mem[self.flash.index(self.address() + (index - 5) as u32)]
+            }
+            0x48 if index >= 5 => match self.security_register() {
+                Some((register, _)) => {
+                    self.security[register][(self.frame[3] as usize + index - 5) % 0x100]
+                }
+                None => 0xff,
+            },
+            0x02 | 0x42 if index >= 4 => {
+                //The chip only keeps the last 256 bytes clocked in.
+                self.page[self.page_len % 256] = mosi;
+                self.page_len += 1;
+                0
+            }
+            _ => 0,
+        }
+    }
+
+    //Chip select goes high: execute the instruction.
+    fn end_frame(&mut self) {
+        let (opcode, count) = (self.frame[0], self.count);
+        if count > 0 && self.asleep() {
+            self.ignored += 1;
+        }
+        match (opcode, count) {
+            _ if self.asleep() => {}
+            (0xab, _) => {
+                self.powered_down = false;
+                self.waking_ns = RELEASE_NS;
+            }
+            (0xb9, 1) if self.status1() & 0x01 == 0 => self.powered_down = true,
+            (0x06, 1) => self.wel = true,
+            (0x04, 1) => self.wel = false,
+            (0x02, _) if count > 4 && self.wel => {
+                let len = self.page_len.min(256);
+                let page = self.page;
+                self.flash.write_page(self.address(), &page[..len]);
+                self.wel = false;
+            }
+            (0x42, _) if count > 4 && self.wel => {
+                if let Some((register, true)) = self.security_register() {
+                    for i in 0..self.page_len.min(256) {
+                        let byte =
+                            &mut self.security[register][(self.frame[3] as usize + i) % 0x100];
+                        *byte &= self.page[i];
+                    }
+                }
+                self.wel = false;
+            }
+            (0x44, 4) if self.wel => {
+                if let Some((register, true)) = self.security_register() {
+                    self.security[register] = [0xff; 0x100];
+                }
+                self.wel = false;
+            }
+            (0x01, 2) if self.wel => self.write_status(0, 0xfc),
+            (0x31, 2) if self.wel => {
+                //LB bits are OTP: once set they stay set.
+                let lb = self.status[1] & 0x38;
+                self.write_status(1, 0x7b);
+                self.status[1] |= lb;
+            }
+            (0x11, 2) if self.wel => self.write_status(2, 0x64),
+            (0x20, 4) if self.wel => self.erase(Delete::SectorErase),
+            (0x52, 4) if self.wel => self.erase(Delete::BlockErase32),
+            (0xd8, 4) if self.wel => self.erase(Delete::BlockErase64),
+            (0xc7, 1) | (0x60, 1) if self.wel => self.erase(Delete::ChipErase),
+            (0x75, 1) if self.erasing.is_some() && !self.suspended => {
+                self.suspended = true;
+                self.suspends += 1;
+                self.status[1] |= 0x80;
+            }
+            (0x7a, 1) if self.suspended => {
+                self.suspended = false;
+                self.status[1] &= !0x80;
+            }
+            _ => {}
+        }
+        self.count = 0;
+        self.page_len = 0;
+        self.frame = [0; 4];
+    }
+
+    fn write_status(&mut self, reg: usize, mask: u8) {
+        self.status[reg] = self.frame[1] & mask;
+        self.wel = false;
+    }
+
+    fn erase(&mut self, option: Delete) {
+        let address = self.flash.index(self.address()) as u32;
+        if self.erase_time > 0 && option != Delete::ChipErase {
+            self.erasing = Some((option, address, self.erase_time));
+        } else {
+            self.flash.delete(option, address).ok();
+        }
+        self.wel = false;
+    }
+}
+
+impl<const SIZE: usize> Default for SimSpi<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SIZE: usize> ErrorType for SimSpi<SIZE> {
+    type Error = ErrorKind;
+}
+
+impl<const SIZE: usize> SpiDevice for SimSpi<SIZE> {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), ErrorKind> {
+        for operation in operations.iter_mut() {
+            match operation {
+                Operation::Write(data) => data.iter().for_each(|byte| {
+                    self.clock(*byte);
+                }),
+                Operation::Read(data) => data.iter_mut().for_each(|byte| *byte = self.clock(0)),
+                Operation::Transfer(read, write) => {
+                    for i in 0..read.len().max(write.len()) {
+                        let miso = self.clock(*write.get(i).unwrap_or(&0));
+                        if let Some(byte) = read.get_mut(i) {
+                            *byte = miso;
+                        }
+                    }
+                }
+                Operation::TransferInPlace(data) => {
+                    data.iter_mut().for_each(|byte| *byte = self.clock(*byte))
+                }
+                Operation::DelayNs(ns) => self.waking_ns = self.waking_ns.saturating_sub(*ns),
+            }
+        }
+        self.end_frame();
+        Ok(())
+    }
+}
+
+//Wide reads decode like single line reads. Quad output without QE gives no data.
+impl<const SIZE: usize> MultiIo for SimSpi<SIZE> {
+    fn read_wide(&mut self, header: &[u8], lines: u8, data: &mut [u8]) -> Result<(), ErrorKind> {
+        let quad_enabled = self.status[1] & 0x02 > 0;
+        self.transaction(&mut [Operation::Write(header), Operation::Read(data)])?;
+        if lines == 4 && !quad_enabled {
+            data.fill(0xff);
+        }
+        Ok(())
+    }
+}
+
+//The transfer happens at start, poll_transfer pretends it took a while. A bus error
+//hands the buffer back like the DMA device does.
+impl<const SIZE: usize> DmaDevice for SimSpi<SIZE> {
+    fn start_read(
+        &mut self,
+        header: &[u8],
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorKind, &'static mut [u8])> {
+        if self.transfer.is_some() || len > buffer.len() {
+            return Err((ErrorKind::Other, buffer));
+        }
+        self.transaction(&mut [
+            Operation::Write(header),
+            Operation::Read(&mut buffer[..len]),
+        ])
+        .ok();
+        self.transfer = Some(buffer);
+        self.polled = false;
+        Ok(())
+    }
+
+    fn start_write(
+        &mut self,
+        header: &[u8],
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (ErrorKind, &'static mut [u8])> {
+        if self.transfer.is_some() || len > buffer.len() {
+            return Err((ErrorKind::Other, buffer));
+        }
+        self.transaction(&mut [Operation::Write(header), Operation::Write(&buffer[..len])])
+            .ok();
+        self.transfer = Some(buffer);
+        self.polled = false;
+        Ok(())
+    }
+
+    fn poll_transfer(
+        &mut self,
+    ) -> nb::Result<&'static mut [u8], (ErrorKind, Option<&'static mut [u8]>)> {
+        let Some(buffer) = self.transfer.take() else {
+            return Err(nb::Error::Other((ErrorKind::Other, None)));
+        };
+        if !self.polled {
+            self.polled = true;
+            self.transfer = Some(buffer);
+            return Err(nb::Error::WouldBlock);
+        }
+        if self.dma_error {
+            self.dma_error = false;
+            return Err(nb::Error::Other((ErrorKind::Overrun, Some(buffer))));
+        }
+        Ok(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::Status2;
+    use crate::w25q128::{Memory, ReadMode, Timeouts, W25Q128, W25Q32, W25Q64};
+
+    #[test]
+    fn program_only_clears_bits() {
+        let mut flash = SimFlash::<0x2000>::new();
+        flash.write(0x10, &[0b1010_1010]).unwrap();
+        flash.write(0x10, &[0b0110_0110]).unwrap();
+        let mut byte = [0u8; 1];
+        flash.read(0x10, 1, &mut byte).unwrap();
+        assert_eq!(byte[0], 0b0010_0010);
+    }
+
+    #[test]
+    fn erase_sets_sector_to_ff() {
+        let mut flash = SimFlash::<0x2000>::new();
+        flash.write(0x0ff0, &[0; 0x20]).unwrap();
+        flash.delete(Delete::SectorErase, 0x0ff8).unwrap();
+        assert!(flash.as_slice()[..0x1000].iter().all(|b| *b == 0xff));
+        assert!(flash.as_slice()[0x1000..0x1010].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn page_program_wraps_within_page() {
+        let mut flash = SimFlash::<0x1000>::new();
+        flash.write_page(0x1fe, &[1, 2, 3, 4]);
+        assert_eq!(flash.as_slice()[0x1fe..0x200], [1, 2]);
+        assert_eq!(flash.as_slice()[0x100..0x102], [3, 4]);
+        assert_eq!(flash.as_slice()[0x200], 0xff);
+    }
+
+    #[test]
+    fn write_spans_pages() {
+        let mut flash = SimFlash::<0x1000>::new();
+        let data: [u8; 600] = core::array::from_fn(|i| i as u8);
+        flash.write(0xf0, &data).unwrap();
+        let mut read_back = [0u8; 600];
+        flash.read(0xf0, 600, &mut read_back).unwrap();
+        assert_eq!(read_back, data);
+    }
+
+    #[test]
+    fn memory_over_mock_bus() {
+        let mut memory = Memory::new_w25q128_device(SimSpi::<0x10000>::new());
+        let data: [u8; 300] = core::array::from_fn(|i| (i * 7) as u8);
+        memory.write(0x0ff0, &data).unwrap();
+        let mut read_back = [0u8; 300];
+        memory.read(0x0ff0, 300, &mut read_back).unwrap();
+        assert_eq!(read_back, data);
+
+        memory.delete(Delete::SectorErase, 0x1000).unwrap();
+        memory.read(0x0ff0, 300, &mut read_back).unwrap();
+        assert_eq!(read_back[..0x10], data[..0x10]);
+        assert!(read_back[0x10..].iter().all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn out_of_range_is_reported() {
+        let mut memory = Memory::new_w25q128_device(SimSpi::<0x1000>::new());
+        let mut flash = SimFlash::<0x1000>::new();
+        assert_eq!(flash.write(0xff0, &[0; 0x20]), Err(Error::OutOfRange));
+        assert_eq!(memory.write(0x1000000, &[0]), Err(Error::OutOfRange));
+    }
+
+    #[test]
+    fn stuck_chip_times_out() {
+        let mut memory = Memory::new_w25q128_device(SimSpi::<0x1000>::new());
+        memory.set_timeouts(Timeouts {
+            page_program: 3,
+            sector_erase: 3,
+            block_erase32: 3,
+            block_erase64: 3,
+            chip_erase: 3,
+            write_status: 3,
+            suspend: 3,
+        });
+        memory.write(0x10, &[0x55]).unwrap();
+        let mut spi = memory.release();
+        spi.stuck = true;
+        let mut memory = Memory::new_w25q128_device(spi);
+        assert_eq!(memory.read(0x10, 1, &mut [0u8; 1]), Err(Error::Timeout));
+        assert_eq!(memory.write(0x10, &[0]), Err(Error::Timeout));
+    }
+
+    #[test]
+    fn probe_selects_geometry_from_jedec_id() {
+        let mut spi = SimSpi::<0x1000>::new();
+        spi.jedec_id = [0xef, 0x40, 0x17];
+        let mut memory = Memory::probe(spi).ok().unwrap();
+        assert_eq!(memory.get_info().capacity(), W25Q64.capacity());
+        assert_eq!(memory.read_unique_id().unwrap(), *b"SIMFLASH");
+
+        let mut spi = memory.release();
+        spi.jedec_id = [0xff; 3];
+        let (e, mut spi) = Memory::probe(spi).err().unwrap();
+        assert_eq!(e, Error::NoDevice);
+
+        spi.jedec_id = [0xc2, 0x20, 0x18];
+        let mut memory = Memory::new_w25q128_device(spi);
+        assert_eq!(
+            memory.detect().err(),
+            Some(Error::UnknownDevice([0xc2, 0x20, 0x18]))
+        );
+        assert_eq!(memory.get_info().capacity(), W25Q128.capacity());
+    }
+
+    //32 Mbit part with 4K and 64K erase only, basic table at 0x30. Pages of 2^page_bits bytes.
+    fn sfdp_part(page_bits: u32) -> SimSpi<0x20000> {
+        let mut spi = SimSpi::<0x20000>::new();
+        spi.flash = SimFlash::with_page_size(1 << page_bits);
+        spi.jedec_id = [0xc2, 0x20, 0x16];
+        spi.sfdp[..16].copy_from_slice(b"SFDP\x06\x01\x00\xff\x00\x06\x01\x10\x30\x00\x00\xff");
+        let basic: [u32; 16] = [
+            0xfff1_20e5,
+            0x01ff_ffff,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0x0000_200c,
+            0x0000_d810,
+            0,
+            page_bits << 4,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        for (i, dword) in basic.iter().enumerate() {
+            spi.sfdp[0x30 + i * 4..0x34 + i * 4].copy_from_slice(&dword.to_le_bytes());
+        }
+        spi
+    }
+
+    #[test]
+    fn unknown_part_is_described_by_sfdp() {
+        let mut memory = Memory::probe(sfdp_part(8)).ok().unwrap();
+        assert_eq!(memory.get_info().capacity_mbit, 32);
+        assert_eq!(memory.get_info().capacity(), W25Q32.capacity());
+        assert_eq!(memory.get_info().block_size, 0x10000);
+        assert!(memory.erase_types()[1].is_none());
+
+        memory.write(0x1000, &[0; 4]).unwrap();
+        memory.delete(Delete::SectorErase, 0x1000).unwrap();
+        assert_eq!(
+            memory.delete(Delete::BlockErase32, 0),
+            Err(Error::Unsupported)
+        );
+        memory.delete(Delete::BlockErase64, 0x10000).unwrap();
+        assert_eq!(memory.release().flash.as_slice()[0x1000..0x1004], [0xff; 4]);
+    }
+
+    #[test]
+    fn writes_are_split_at_the_sfdp_page_size() {
+        //Programs that cross a 64 byte page would wrap around to its start.
+        let mut memory = Memory::probe(sfdp_part(6)).ok().unwrap();
+        assert_eq!(memory.get_info().page_size, 64);
+        let data: [u8; 300] = core::array::from_fn(|i| i as u8 ^ 0x5a);
+        memory.write(0x1030, &data).unwrap();
+        let flash = memory.release().flash;
+        assert_eq!(flash.as_slice()[0x1030..0x115c], data);
+        assert_eq!(flash.as_slice()[0x102f], 0xff);
+        assert_eq!(flash.as_slice()[0x115c], 0xff);
+
+        let mut flash = SimFlash::<0x1000>::with_page_size(64);
+        flash.write(0x30, &data).unwrap();
+        assert_eq!(flash.as_slice()[0x30..0x15c], data);
+    }
+
+    #[test]
+    fn security_registers_lock_only_when_armed() {
+        let mut memory = Memory::new_w25q128_device(SimSpi::<0x1000>::new());
+        memory
+            .program_security_register(2, 0x10, b"AAUSAT4")
+            .unwrap();
+        let mut id = [0u8; 7];
+        memory.read_security_register(2, 0x10, &mut id).unwrap();
+        assert_eq!(&id, b"AAUSAT4");
+        assert_eq!(
+            memory.program_security_register(2, 0xfc, &[0; 8]),
+            Err(Error::OutOfRange)
+        );
+        assert_eq!(memory.erase_security_register(4), Err(Error::OutOfRange));
+        memory.erase_security_register(1).unwrap();
+
+        //Status register writes never touch the lock bits.
+        let mut status2 = memory.read_status2().unwrap();
+        status2.lb = 0b111;
+        memory.write_status2(status2).unwrap();
+        assert!(!memory.is_security_register_locked(2).unwrap());
+
+        let lock = memory.arm_security_lock(2).unwrap();
+        assert_eq!(lock.register(), 2);
+        memory.lock_security_register(lock).unwrap();
+        assert!(memory.is_security_register_locked(2).unwrap());
+        assert!(!memory.is_security_register_locked(1).unwrap());
+        assert_eq!(
+            memory.erase_security_register(2),
+            Err(Error::WriteProtected)
+        );
+        assert_eq!(
+            memory.arm_security_lock(2).err(),
+            Some(Error::WriteProtected)
+        );
+        //Clearing LB with a status write has no effect.
+        memory.write_status2(Status2::from(0)).unwrap();
+        assert!(memory.is_security_register_locked(2).unwrap());
+        let spi = memory.release();
+        assert_eq!(&spi.security[1][0x10..0x17], b"AAUSAT4");
+        assert_eq!(spi.security[0], [0xff; 0x100]);
+    }
+
+    #[test]
+    fn protect_range_picks_smallest_cover() {
+        let mut memory = Memory::new_w25q128_device(SimSpi::<0x1000>::new());
+        //Flight plan: 48 tasks of 256 bytes from 0, covered by the bottom 16K.
+        assert_eq!(memory.protect_range(0, 48 * 256), Ok((0, 0x4000)));
+        let status1 = memory.read_status1().unwrap();
+        assert_eq!((status1.bp, status1.tb, status1.sec), (3, true, true));
+        assert!(!memory.read_status2().unwrap().cmp);
+        assert_eq!(memory.protected_range(), Ok((0, 0x4000)));
+        //Past 32K only the 64K block areas are left, the bottom 1/64 of the chip.
+        assert_eq!(memory.protect_range(0, 0x9000), Ok((0, 0x40000)));
+
+        assert_eq!(
+            memory.protect_range(0xff_f000, 0x800),
+            Ok((0xff_f000, 0x100_0000))
+        );
+        assert!(memory.read_status1().unwrap().sec);
+        //Everything but the top 4K is the complement of the top sector.
+        assert_eq!(memory.protect_range(0, 0xff_f000), Ok((0, 0xff_f000)));
+        assert!(memory.read_status2().unwrap().cmp);
+        assert_eq!(memory.protect_range(0, 0), Ok((0, 0)));
+    }
+
+    #[test]
+    fn fast_and_wide_reads() {
+        let mut memory = Memory::new_w25q128_device(SimSpi::<0x1000>::new());
+        let data: [u8; 40] = core::array::from_fn(|i| i as u8 ^ 0x5a);
+        memory.write(0x123, &data).unwrap();
+        let mut read_back = [0u8; 40];
+
+        memory.set_read_mode(ReadMode::Fast).unwrap();
+        memory.read(0x123, 40, &mut read_back).unwrap();
+        assert_eq!(read_back, data);
+
+        assert_eq!(
+            memory.set_read_mode(ReadMode::DualOutput),
+            Err(Error::Unsupported)
+        );
+        memory.enable_multi_io();
+        for mode in [ReadMode::DualOutput, ReadMode::QuadOutput] {
+            memory.set_read_mode(mode).unwrap();
+            read_back = [0; 40];
+            memory.read(0x123, 40, &mut read_back).unwrap();
+            assert_eq!(read_back, data);
+        }
+        assert!(memory.read_status2().unwrap().qe);
+    }
+
+    #[test]
+    fn dma_read_and_program() {
+        let mut memory = Memory::new_w25q128_device(SimSpi::<0x1000>::new());
+        let page: &'static mut [u8] = Box::leak(Box::new([0xa5u8; 200]));
+        memory.start_write_page(0x210, page, 200).ok().unwrap();
+        assert!(matches!(memory.poll_transfer(), Err(nb::Error::WouldBlock)));
+        let page = nb::block!(memory.poll_transfer()).unwrap();
+        assert_eq!(page.len(), 200);
+        //Crossing the page boundary is refused, and the buffer handed back.
+        let (e, _) = memory.start_write_page(0x280, page, 200).err().unwrap();
+        assert_eq!(e, Error::OutOfRange);
+
+        let buffer: &'static mut [u8] = Box::leak(Box::new([0u8; 210]));
+        memory.start_read(0x20b, buffer, 210).ok().unwrap();
+        let buffer = nb::block!(memory.poll_transfer()).unwrap();
+        assert_eq!(buffer[..5], [0xff; 5]);
+        assert!(buffer[5..205].iter().all(|b| *b == 0xa5));
+        assert_eq!(buffer[205..], [0xff; 5]);
+    }
+
+    #[test]
+    fn dma_error_hands_back_the_buffer() {
+        let mut spi = SimSpi::<0x1000>::new();
+        spi.dma_error = true;
+        let mut memory = Memory::new_w25q128_device(spi);
+        let buffer: &'static mut [u8] = Box::leak(Box::new([0u8; 64]));
+        let ptr = buffer.as_ptr();
+        memory.start_read(0x0, buffer, 64).ok().unwrap();
+        let (e, buffer) = match nb::block!(memory.poll_transfer()) {
+            Err((e, Some(buffer))) => (e, buffer),
+            _ => panic!("the transfer should fail with its buffer"),
+        };
+        assert_eq!(e, Error::Bus(ErrorKind::Overrun));
+        assert_eq!(buffer.as_ptr(), ptr);
+        //The same buffer is used again once the bus works.
+        memory.start_read(0x0, buffer, 64).ok().unwrap();
+        assert_eq!(nb::block!(memory.poll_transfer()).unwrap(), [0xff; 64]);
+        assert!(matches!(
+            memory.poll_transfer(),
+            Err(nb::Error::Other((_, None)))
+        ));
+    }
+
+    #[test]
+    fn read_suspends_erase_elsewhere() {
+        let mut spi = SimSpi::<0x4000>::new();
+        spi.erase_time = 50;
+        let mut memory = Memory::new_w25q128_device(spi);
+        memory.write(0x0100, &[1, 2, 3]).unwrap();
+        memory.write(0x2100, &[4, 5, 6]).unwrap();
+
+        memory.delete(Delete::SectorErase, 0x0000).unwrap();
+        let mut data = [0u8; 3];
+        memory.read(0x2100, 3, &mut data).unwrap();
+        assert_eq!(data, [4, 5, 6]);
+        assert!(!memory.is_suspended());
+        //Reading the sector being erased waits for the erase instead.
+        memory.read(0x0100, 3, &mut data).unwrap();
+        assert_eq!(data, [0xff; 3]);
+
+        let spi = memory.release();
+        assert_eq!(spi.suspends, 1);
+        assert!(!spi.suspended);
+    }
+
+    #[test]
+    fn power_down_wakes_on_access() {
+        let mut spi = SimSpi::<0x3000>::new();
+        spi.erase_time = 5;
+        let mut memory = Memory::new_w25q128_device(spi);
+        memory.write(0x0100, &[1, 2, 3]).unwrap();
+        assert_eq!(memory.power_down(), Ok(true));
+        assert!(memory.is_powered_down());
+        let mut data = [0u8; 3];
+        memory.read(0x0100, 3, &mut data).unwrap();
+        assert_eq!(data, [1, 2, 3]);
+        assert!(!memory.is_powered_down());
+
+        //Not while an erase runs.
+        memory.delete(Delete::SectorErase, 0x1000).unwrap();
+        assert_eq!(memory.power_down(), Ok(false));
+        memory.read(0x1000, 1, &mut data).unwrap();
+        assert_eq!(memory.power_down(), Ok(true));
+        let spi = memory.release();
+        assert!(spi.powered_down);
+        assert_eq!(spi.ignored, 0, "no instruction before tRES1");
+    }
+
+    #[test]
+    fn probe_wakes_a_sleeping_chip() {
+        //The MCU was reset while the chip was powered down.
+        let mut spi = SimSpi::<0x3000>::new();
+        spi.powered_down = true;
+        let memory = Memory::probe(spi).ok().unwrap();
+        assert_eq!(memory.release().ignored, 0);
+    }
+
+    #[test]
+    fn update_erases_only_when_needed() {
+        let mut memory = Memory::new_w25q128_device(SimSpi::<0x3000>::new());
+        memory.write(0x0800, &[0xf0; 16]).unwrap();
+        //1 -> 0 only: programmed in place, the rest of the sector is kept.
+        memory.update(0x0800, &[0x30; 16]).unwrap();
+        assert_eq!(
+            memory.release().flash.as_slice()[0x0800..0x0810],
+            [0x30; 16]
+        );
+
+        let mut memory = Memory::new_w25q128_device(SimSpi::<0x3000>::new());
+        memory.write(0x0ff0, &[0x00; 0x20]).unwrap();
+        memory.write(0x1100, &[0x42; 16]).unwrap();
+        //0 -> 1 across a sector boundary: both sectors are rewritten.
+        let data: [u8; 0x20] = core::array::from_fn(|i| i as u8 | 0x80);
+        memory.update(0x0ff0, &data).unwrap();
+        let mut read_back = [0u8; 0x20];
+        memory.read(0x0ff0, 0x20, &mut read_back).unwrap();
+        assert_eq!(read_back, data);
+        memory.read(0x1100, 16, &mut read_back[..16]).unwrap();
+        assert_eq!(read_back[..16], [0x42; 16]);
+    }
+}
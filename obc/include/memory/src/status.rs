@@ -0,0 +1,149 @@
+//Typed W25Q status registers and the block protection table.
+//Bit layout from the W25Q128JV datasheet, shared by the W25Q16/32/64/128.
+
+//Status register 1: |SRP|SEC|TB|BP2|BP1|BP0|WEL|BUSY|
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub struct Status1 {
+    pub busy: bool, //Program, erase or status write in progress (read only)
+    pub wel: bool,  //Write enable latch (read only)
+    pub bp: u8,     //Block protect bits BP2-BP0
+    pub tb: bool,   //Protect from the bottom instead of the top
+    pub sec: bool,  //Protect 4K sectors instead of 64K blocks
+    pub srp: bool,  //Status register protect, together with SRL and /WP
+}
+
+//Status register 2: |SUS|CMP|LB3|LB2|LB1|R|QE|SRL|
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub struct Status2 {
+    pub srl: bool, //Status register lock
+    pub qe: bool,  //Quad enable, /WP and /HOLD become IO2 and IO3
+    pub lb: u8,    //Security register lock bits LB3-LB1 (OTP)
+    pub cmp: bool, //Complement the block protection
+    pub sus: bool, //Erase/program suspended (read only)
+}
+
+//Status register 3: |R|DRV1|DRV0|R|R|WPS|R|R|
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub struct Status3 {
+    pub wps: bool, //Individual block locks instead of BP/TB/SEC/CMP
+    pub drv: u8,   //Output driver strength, 0 = 100%, 1 = 75%, 2 = 50%, 3 = 25%
+}
+
+impl From<u8> for Status1 {
+    fn from(reg: u8) -> Self {
+        Status1 {
+            busy: reg & 0x01 > 0,
+            wel: reg & 0x02 > 0,
+            bp: (reg >> 2) & 0b111,
+            tb: reg & 0x20 > 0,
+            sec: reg & 0x40 > 0,
+            srp: reg & 0x80 > 0,
+        }
+    }
+}
+impl From<Status1> for u8 {
+    fn from(s: Status1) -> u8 {
+        s.busy as u8
+            | (s.wel as u8) << 1
+            | (s.bp & 0b111) << 2
+            | (s.tb as u8) << 5
+            | (s.sec as u8) << 6
+            | (s.srp as u8) << 7
+    }
+}
+
+impl From<u8> for Status2 {
+    fn from(reg: u8) -> Self {
+        Status2 {
+            srl: reg & 0x01 > 0,
+            qe: reg & 0x02 > 0,
+            lb: (reg >> 3) & 0b111,
+            cmp: reg & 0x40 > 0,
+            sus: reg & 0x80 > 0,
+        }
+    }
+}
+impl From<Status2> for u8 {
+    fn from(s: Status2) -> u8 {
+        s.srl as u8
+            | (s.qe as u8) << 1
+            | (s.lb & 0b111) << 3
+            | (s.cmp as u8) << 6
+            | (s.sus as u8) << 7
+    }
+}
+
+impl From<u8> for Status3 {
+    fn from(reg: u8) -> Self {
+        Status3 {
+            wps: reg & 0x04 > 0,
+            drv: (reg >> 5) & 0b11,
+        }
+    }
+}
+impl From<Status3> for u8 {
+    fn from(s: Status3) -> u8 {
+        (s.wps as u8) << 2 | (s.drv & 0b11) << 5
+    }
+}
+
+//The BP/TB/SEC/CMP bits that select a protected area.
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub struct Protection {
+    pub bp: u8,
+    pub tb: bool,
+    pub sec: bool,
+    pub cmp: bool,
+}
+
+impl Protection {
+    //Protected addresses [start, end) on a chip of capacity bytes.
+    pub fn range(&self, capacity: u32) -> (u32, u32) {
+        let size = match (self.bp, self.sec) {
+            (0, _) => 0,
+            (7, _) => capacity,
+            (bp, false) => (capacity / 64) << (bp - 1), //1/64 up to 1/2 of the chip
+            (bp, true) => 0x1000 << (bp - 1).min(3),    //4K up to 32K
+        };
+        //TB selects the end of the chip the area grows from, CMP protects everything else.
+        let bottom = self.tb != self.cmp;
+        let size = if self.cmp { capacity - size } else { size };
+        if size == 0 {
+            (0, 0)
+        } else if bottom {
+            (0, size)
+        } else {
+            (capacity - size, capacity)
+        }
+    }
+
+    //Smallest protectable area covering [start, end), the whole chip if nothing smaller does.
+    //An empty range gives no protection.
+    pub fn covering(start: u32, end: u32, capacity: u32) -> Protection {
+        let mut best = Protection {
+            bp: 7,
+            tb: false,
+            sec: false,
+            cmp: false,
+        };
+        if start >= end {
+            best.bp = 0;
+            return best;
+        }
+        let mut best_size = capacity;
+        for bits in 0..64u8 {
+            let option = Protection {
+                bp: bits & 0b111,
+                tb: bits & 0x08 > 0,
+                sec: bits & 0x10 > 0,
+                cmp: bits & 0x20 > 0,
+            };
+            let (s, e) = option.range(capacity);
+            if s <= start && end <= e && e - s < best_size {
+                best = option;
+                best_size = e - s;
+            }
+        }
+        best
+    }
+}
@@ -0,0 +1,356 @@
+//embedded-hal SpiDevice made from the STM32F4 hal SPI and a GPIO chip select pin.
+//Used by w25q128::Memory::new/new_w25q128, so the flash driver only has to talk SpiDevice.
+use crate::DmaDevice;
+use core::sync::atomic::{compiler_fence, Ordering};
+use embedded_hal_1::spi::{ErrorKind, ErrorType, Operation, SpiBus, SpiDevice};
+use stm32f4xx_hal::{
+    dma::{
+        traits::{Channel, DMASet, Stream},
+        ChannelX, DmaChannel, DmaDataSize, DmaDirection, MemoryToPeripheral, PeripheralToMemory,
+    },
+    gpio,
+    gpio::{Pin, PinState},
+    spi::{self, Instance, Spi},
+};
+
+//Spins on the BSY flag after a transaction. BSY clears one frame after the last byte,
+//which is a few thousand core cycles at the slowest SPI clock.
+const BSY_POLLS: u32 = 100_000;
+//Spins on the DMA flags before an ordinary transaction. A 64K transfer takes 1.5 s at
+//the slowest SPI clock.
+const DMA_POLLS: u32 = 50_000_000;
+
+//Errors of the hal SPI, plus a BSY flag that never cleared.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    Spi(spi::Error),
+    Busy, //BSY stuck, a DMA transfer not collected yet, or one that never finished
+    Idle, //poll_transfer without a transfer
+}
+
+impl embedded_hal_1::spi::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Spi(e) => e.kind(),
+            Error::Busy | Error::Idle => ErrorKind::Other,
+        }
+    }
+}
+
+pub struct HalDevice<SPI: Instance, const P: char, const N: u8, MODE> {
+    spi: Spi<SPI>,                     //Our Hal spi
+    cs: Pin<P, N, gpio::Output<MODE>>, //Chip select pin
+    cs_active: PinState,               //Active state
+}
+
+impl<SPI: Instance, const P: char, const N: u8, MODE> HalDevice<SPI, P, N, MODE> {
+    //Chip select is active low by default.
+    pub fn new(spi: Spi<SPI>, cs: Pin<P, N, gpio::Output<MODE>>) -> Self {
+        let mut device = HalDevice {
+            spi,
+            cs,
+            cs_active: PinState::Low,
+        };
+        device.init(); //Init chip select
+        device
+    }
+    //Change the active state of the chip select:
+    pub fn change_active(&mut self, state: PinState) {
+        self.cs_active = state;
+        self.init();
+    }
+    //Set chip select inactive
+    fn init(&mut self) {
+        if self.cs.get_state() == self.cs_active {
+            self.cs.toggle();
+        }
+    }
+    //Give back the hal SPI and the chip select pin.
+    pub fn release(self) -> (Spi<SPI>, Pin<P, N, gpio::Output<MODE>>) {
+        (self.spi, self.cs)
+    }
+
+    fn wait_bsy(&mut self) -> Result<(), Error> {
+        let mut polls = 0;
+        while self.spi.is_busy() {
+            polls += 1;
+            if polls > BSY_POLLS {
+                return Err(Error::Busy);
+            }
+        }
+        Ok(())
+    }
+
+    fn operation(&mut self, operation: &mut Operation<'_, u8>) -> Result<(), spi::Error> {
+        match operation {
+            Operation::Write(data) => SpiBus::write(&mut self.spi, data),
+            Operation::Read(data) => SpiBus::read(&mut self.spi, data),
+            Operation::Transfer(read, write) => SpiBus::transfer(&mut self.spi, read, write),
+            Operation::TransferInPlace(data) => SpiBus::transfer_in_place(&mut self.spi, data),
+            //No timer available, assumes a core clock of at most 200 MHz (5 ns per cycle).
+            Operation::DelayNs(ns) => {
+                cortex_m::asm::delay(ns.div_ceil(5));
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<SPI: Instance, const P: char, const N: u8, MODE> ErrorType for HalDevice<SPI, P, N, MODE> {
+    type Error = Error;
+}
+
+impl<SPI: Instance, const P: char, const N: u8, MODE> SpiDevice for HalDevice<SPI, P, N, MODE> {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Error> {
+        self.cs.set_state(self.cs_active);
+        let mut result = Ok(());
+        for operation in operations.iter_mut() {
+            result = self.operation(operation).map_err(Error::Spi);
+            if result.is_err() {
+                break;
+            }
+        }
+        result = result.and(self.wait_bsy());
+        self.cs.set_state(!self.cs_active); //Done
+        result
+    }
+}
+
+//Clocked out on MOSI while the DMA reads, the chip ignores it.
+static DUMMY: u8 = 0;
+
+//HalDevice with two DMA streams for the data phase of reads and page programs,
+//so the CPU is free while up to 64K bytes move. An ordinary transaction waits for a
+//running transfer to finish, and keeps its result for poll_transfer. Used through
+//DmaFlash, see examples/dma_read.rs.
+//SPI1 uses DMA2 stream 0 or 2 (RX) and stream 3 or 5 (TX), all on channel 3.
+pub struct DmaHalDevice<SPI: Instance, const P: char, const N: u8, MODE, RX, TX> {
+    device: HalDevice<SPI, P, N, MODE>,
+    rx: RX,
+    tx: TX,
+    channels: (DmaChannel, DmaChannel),          //RX, TX
+    transfer: Option<(&'static mut [u8], bool)>, //Buffer of the running transfer, true for a read
+    done: Option<Result<(), Error>>,             //Transfer finished, waiting for poll_transfer
+}
+
+impl<SPI: Instance, const P: char, const N: u8, MODE, RX: Stream, TX: Stream>
+    DmaHalDevice<SPI, P, N, MODE, RX, TX>
+{
+    //The channels follow from the stream/peripheral pair, see the reference manual DMA request mapping.
+    pub fn new<const RXC: u8, const TXC: u8>(
+        device: HalDevice<SPI, P, N, MODE>,
+        rx: RX,
+        tx: TX,
+    ) -> Self
+    where
+        SPI: DMASet<RX, RXC, PeripheralToMemory> + DMASet<TX, TXC, MemoryToPeripheral>,
+        ChannelX<RXC>: Channel,
+        ChannelX<TXC>: Channel,
+    {
+        DmaHalDevice {
+            device,
+            rx,
+            tx,
+            channels: (ChannelX::<RXC>::VALUE, ChannelX::<TXC>::VALUE),
+            transfer: None,
+            done: None,
+        }
+    }
+    //Give back the device and the streams.
+    pub fn release(self) -> (HalDevice<SPI, P, N, MODE>, RX, TX) {
+        (self.device, self.rx, self.tx)
+    }
+    //Enable the transfer complete interrupt of the stream that finishes last.
+    pub fn listen(&mut self) {
+        self.rx.listen_transfer_complete();
+        self.tx.listen_transfer_complete();
+    }
+
+    fn setup(
+        stream: &mut impl Stream,
+        channel: DmaChannel,
+        direction: DmaDirection,
+        memory: u32,
+        len: usize,
+        increment: bool,
+    ) {
+        let dr = unsafe { &(*SPI::ptr()).dr } as *const _ as u32;
+        stream.clear_all_flags();
+        stream.set_channel(channel);
+        stream.set_direction(direction);
+        stream.set_peripheral_address(dr);
+        stream.set_memory_address(memory);
+        stream.set_number_of_transfers(len as u16);
+        stream.set_memory_increment(increment);
+        stream.set_peripheral_increment(false);
+        //NOTE(unsafe) The SPI data register is used 8 bits at a time.
+        unsafe {
+            stream.set_memory_size(DmaDataSize::Byte);
+            stream.set_peripheral_size(DmaDataSize::Byte);
+        }
+    }
+
+    //Chip select, blocking header, then DMA for the first len bytes of the buffer.
+    fn start(
+        &mut self,
+        header: &[u8],
+        buffer: &'static mut [u8],
+        len: usize,
+        read: bool,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        if self.transfer.is_some() {
+            return Err((Error::Busy, buffer));
+        }
+        if len == 0 || len > buffer.len() || len > u16::MAX as usize {
+            return Err((Error::Spi(spi::Error::ModeFault), buffer));
+        }
+        let device = &mut self.device;
+        device.cs.set_state(device.cs_active);
+        if let Err(e) = SpiBus::write(&mut device.spi, header) {
+            device.cs.set_state(!device.cs_active);
+            return Err((Error::Spi(e), buffer));
+        }
+        let memory = buffer.as_mut_ptr() as u32;
+        if read {
+            Self::setup(
+                &mut self.rx,
+                self.channels.0,
+                DmaDirection::PeripheralToMemory,
+                memory,
+                len,
+                true,
+            );
+            Self::setup(
+                &mut self.tx,
+                self.channels.1,
+                DmaDirection::MemoryToPeripheral,
+                &DUMMY as *const u8 as u32,
+                len,
+                false,
+            );
+        } else {
+            Self::setup(
+                &mut self.tx,
+                self.channels.1,
+                DmaDirection::MemoryToPeripheral,
+                memory,
+                len,
+                true,
+            );
+        }
+        compiler_fence(Ordering::SeqCst);
+        let regs = unsafe { &*SPI::ptr() };
+        //NOTE(unsafe) Both streams point at the SPI data register and a buffer owned until poll_transfer.
+        unsafe {
+            if read {
+                self.rx.enable();
+                regs.cr2.modify(|_, w| w.rxdmaen().enabled());
+            }
+            self.tx.enable();
+        }
+        regs.cr2.modify(|_, w| w.txdmaen().enabled());
+        self.transfer = Some((buffer, read));
+        Ok(())
+    }
+
+    //Once the running transfer is over: Ok if it is done, Err if the DMA failed.
+    fn finished(&self, read: bool) -> Option<Result<(), Error>> {
+        let error = self.tx.is_transfer_error() || (read && self.rx.is_transfer_error());
+        let done = self.tx.is_transfer_complete() && (!read || self.rx.is_transfer_complete());
+        match (error, done) {
+            (true, _) => Some(Err(Error::Spi(spi::Error::Overrun))),
+            (false, true) => Some(Ok(())),
+            _ => None,
+        }
+    }
+
+    //Stop the streams and release chip select, with the result of the transfer.
+    fn stop(&mut self, result: Result<(), Error>) -> Result<(), Error> {
+        let regs = unsafe { &*SPI::ptr() };
+        //NOTE(unsafe) Stopping the streams, the buffer is not touched afterwards.
+        unsafe {
+            self.tx.disable();
+            self.rx.disable();
+        }
+        self.tx.clear_all_flags();
+        self.rx.clear_all_flags();
+        compiler_fence(Ordering::SeqCst);
+        let bsy = self.device.wait_bsy();
+        regs.cr2
+            .modify(|_, w| w.txdmaen().disabled().rxdmaen().disabled());
+        //A write leaves the received bytes behind, reading DR then SR clears the overrun.
+        let _ = regs.dr.read();
+        let _ = regs.sr.read();
+        self.device.cs.set_state(!self.device.cs_active);
+        result.and(bsy)
+    }
+}
+
+impl<SPI: Instance, const P: char, const N: u8, MODE, RX: Stream, TX: Stream> ErrorType
+    for DmaHalDevice<SPI, P, N, MODE, RX, TX>
+{
+    type Error = Error;
+}
+
+impl<SPI: Instance, const P: char, const N: u8, MODE, RX: Stream, TX: Stream> SpiDevice
+    for DmaHalDevice<SPI, P, N, MODE, RX, TX>
+{
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Error> {
+        if let (Some((_, read)), None) = (&self.transfer, &self.done) {
+            let read = *read;
+            let mut polls = 0;
+            let result = loop {
+                match self.finished(read) {
+                    Some(result) => break result,
+                    None if polls > DMA_POLLS => break Err(Error::Busy),
+                    None => polls += 1,
+                }
+            };
+            self.done = Some(self.stop(result));
+        }
+        self.device.transaction(operations)
+    }
+}
+
+impl<SPI: Instance, const P: char, const N: u8, MODE, RX: Stream, TX: Stream> DmaDevice
+    for DmaHalDevice<SPI, P, N, MODE, RX, TX>
+{
+    fn start_read(
+        &mut self,
+        header: &[u8],
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        self.start(header, buffer, len, true)
+    }
+
+    fn start_write(
+        &mut self,
+        header: &[u8],
+        buffer: &'static mut [u8],
+        len: usize,
+    ) -> Result<(), (Error, &'static mut [u8])> {
+        self.start(header, buffer, len, false)
+    }
+
+    fn poll_transfer(
+        &mut self,
+    ) -> nb::Result<&'static mut [u8], (Error, Option<&'static mut [u8]>)> {
+        let read = match &self.transfer {
+            Some((_, read)) => *read,
+            None => return Err(nb::Error::Other((Error::Idle, None))),
+        };
+        if self.done.is_none() {
+            match self.finished(read) {
+                Some(result) => self.done = Some(self.stop(result)),
+                None => return Err(nb::Error::WouldBlock),
+            }
+        }
+        let result = self.done.take().unwrap();
+        let (buffer, _) = self.transfer.take().unwrap();
+        match result {
+            Ok(()) => Ok(buffer),
+            Err(e) => Err(nb::Error::Other((e, Some(buffer)))),
+        }
+    }
+}
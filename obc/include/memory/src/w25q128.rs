@@ -1,12 +1,12 @@
-
-
 //Bunch of includes to make typedefinition easier.
-    use cortex_m::prelude::_embedded_hal_blocking_spi_Write;
-    use stm32f4xx_hal::{spi::{Instance, Spi, Error}, gpio, gpio::Pin, pac::rcc::csr::CSR_SPEC};
-    use embedded_hal::{digital::v2::PinState, spi::FullDuplex};
+    use embedded_hal_1::spi::{Operation, SpiDevice};
+    use stm32f4xx_hal::{spi::{Instance, Spi}, gpio, gpio::PinState};
+    use crate::stm32::HalDevice;
+    use crate::sfdp::{self, EraseType, Sfdp};
+    use crate::status::{Protection, Status1, Status2, Status3};
+    use crate::{DmaDevice, DmaFlash, Error, MultiIo, NorFlash};
     //Spi struct
     
-    const DUMMY: u8 = 0x0;
     //Standard SPI Instructions
     #[allow(unused)]
     #[repr(u8)]
@@ -17,6 +17,9 @@
         //3 byte address and data read/write
         PageProgram = 0x02,
         Read = 0x03,
+        FastRead = 0x0b, //One dummy byte
+        FastReadDual = 0x3b, //One dummy byte, data on IO0-IO1
+        FastReadQuad = 0x6b, //One dummy byte, data on IO0-IO3
 
         //Erase: - 3 Byte trailing address
         SectorErase = 0x20,
@@ -25,6 +28,10 @@
         ChipErase1 = 0xc7, //Part 1 
         ChipErase2 = 0x60, //Part 2 
 
+        //Suspend/resume a sector/block erase or a page program
+        Suspend = 0x75,
+        Resume = 0x7a,
+
         //Registers - 1 trailing byte Read/Write
         ReadStatus1 = 0x05,
         ReadStatus2 = 0x35,
@@ -35,19 +42,30 @@
         WriteStatus3 = 0x11,
 
 
+        //Power:
+        PowerDown = 0xb9,
+
         //Ids:
         ManId = 0x90, //2 dummy and 0x0 - 2 Byte Read
         JedecId = 0x9f, //3 Byte Read
-        DeviceId = 0xAB, //Three dummy, - 1 Byte Read
+        DeviceId = 0xAB, //Three dummy, - 1 Byte Read. Alone it releases power-down
         UniqueId = 0x4b, //Four dummy, - 8 Byte Read
 
+        //Security registers: 3 byte address |0|register, 0|byte|
+        EraseSecurity = 0x44,
+        ProgramSecurity = 0x42,
+        ReadSecurity = 0x48, //One dummy byte
+        ReadSfdp = 0x5a, //3 byte address and one dummy
+
     }
+    #[derive(Clone, Copy, Debug, PartialEq)]
     pub enum Delete {
         SectorErase,
         BlockErase32,
         BlockErase64,
         ChipErase
     }
+    #[derive(Clone, Copy, Debug)]
     pub struct FlashInfo {
         pub page_size: u16,
         pub sector_size: u32,
@@ -57,249 +75,765 @@
         pub block_count: u32,
         pub capacity_mbit: u32,   
     }
+    impl FlashInfo {
+        //Size of the memory in bytes.
+        pub fn capacity(&self) -> u32 {
+            self.sector_count * self.sector_size
+        }
+    }
     //Predefined flash:
-    const W25Q128: FlashInfo = FlashInfo {
-        page_size: 256,
-        sector_size: 0x1000,
-        page_count: (128 * 16 * 0x1000) / 256,
-        sector_count: 128 * 16,
-        block_size: 0x1000 * 16,
-        block_count: 128,
-        capacity_mbit: 128,
+    pub const W25Q128: FlashInfo = winbond(128);
+    pub const W25Q16: FlashInfo = winbond(16);
+    pub const W25Q32: FlashInfo = winbond(32);
+    pub const W25Q64: FlashInfo = winbond(64);
+    pub const W25Q256: FlashInfo = winbond(256);
+
+    //All W25Q parts: 256 byte pages, 4K sectors, 64K blocks.
+    const fn winbond(capacity_mbit: u32) -> FlashInfo {
+        let blocks = capacity_mbit * 2; //64K blocks per Mbit / 8
+        FlashInfo {
+            page_size: 256,
+            sector_size: 0x1000,
+            page_count: (blocks * 16 * 0x1000) / 256,
+            sector_count: blocks * 16,
+            block_size: 0x1000 * 16,
+            block_count: blocks,
+            capacity_mbit,
+        }
+    }
+
+    //Known parts by JEDEC ID |Manufacturer|Memory type|Capacity|.
+    //Memory type 0x40 is the standard part, 0x70 the IM/DTR variant with the same geometry.
+    pub fn flash_info_for(jedec_id: [u8; 3]) -> Option<FlashInfo> {
+        if jedec_id[0] != 0xef || !(jedec_id[1] == 0x40 || jedec_id[1] == 0x70) {
+            return None;
+        }
+        match jedec_id[2] {
+            0x15 => Some(W25Q16),
+            0x16 => Some(W25Q32),
+            0x17 => Some(W25Q64),
+            0x18 => Some(W25Q128),
+            0x19 => Some(W25Q256),
+            _ => None,
+        }
+    }
+    //Erase instructions of the W25Q parts, used until SFDP says otherwise.
+    pub const WINBOND_ERASE: [Option<EraseType>; 4] = [
+        Some(EraseType { size: 0x1000, opcode: OpCode::SectorErase as u8 }),
+        Some(EraseType { size: 0x8000, opcode: OpCode::BlockErase32 as u8 }),
+        Some(EraseType { size: 0x10000, opcode: OpCode::BlockErase64 as u8 }),
+        None,
+    ];
+    pub const SECURITY_REGISTER_SIZE: usize = 256;
+    //Permission to lock one security register, from Memory::arm_security_lock.
+    //Only Memory::lock_security_register takes it, so locking is always two separate calls.
+    #[must_use]
+    #[derive(Debug)]
+    pub struct SecurityLock {
+        register: u8,
+    }
+    impl SecurityLock {
+        pub fn register(&self) -> u8 {
+            self.register
+        }
+    }
+    //The driver sends 3 byte addresses, so only the lower 16 MB of a W25Q256 is reachable.
+    const ADDRESS_LIMIT: u64 = 1 << 24;
+
+    //Instruction used by Memory::read.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum ReadMode {
+        Standard,   //0x03, up to 50 MHz
+        Fast,       //0x0B, full clock speed
+        DualOutput, //0x3B, needs a MultiIo bus
+        QuadOutput, //0x6B, needs a MultiIo bus, sets the QE bit
+    }
+
+    //Worst case time of each operation, counted in busy polls.
+    //Every poll waits POLL_INTERVAL_NS, so a poll is at least 10 us whatever the SPI clock.
+    #[derive(Clone, Copy, Debug)]
+    pub struct Timeouts {
+        pub page_program: u32,
+        pub sector_erase: u32,
+        pub block_erase32: u32,
+        pub block_erase64: u32,
+        pub chip_erase: u32,
+        pub write_status: u32,
+        pub suspend: u32,
+    }
+    pub const POLL_INTERVAL_NS: u32 = 10_000;
+    //W25Q128JV datasheet maximums (tPP, tSE, tBE1, tBE2, tCE, tW, tSUS).
+    pub const W25Q128_TIMEOUTS: Timeouts = Timeouts {
+        page_program: 300,       //3 ms
+        sector_erase: 40_000,    //400 ms
+        block_erase32: 160_000,  //1.6 s
+        block_erase64: 200_000,  //2 s
+        chip_erase: 20_000_000,  //200 s
+        write_status: 1_500,     //15 ms
+        suspend: 2,              //20 us
     };
+    //Power-down entry (tDP) and release (tRES1) times, instructions are ignored until they pass.
+    pub const POWER_DOWN_NS: u32 = 3_000;
+    pub const RELEASE_NS: u32 = 3_000;
+
+    //What keeps the chip busy, only program and sector/block erase can be suspended.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Busy {
+        Idle,
+        Program,
+        Erase(u32, u32), //Area being erased [start, end)
+        Other,           //Chip erase, status register write
+    }
 
-    
     //Take address in format: |Dummy|A23-A16|A15-A8|A7-A0|
     //Output in format [A23-16, A15-A8, A7-A0]
     pub fn split_address (address: u32) -> [u8;3] {
         let tmp: [u8; 4] = address.to_be_bytes();
         tmp[1..].try_into().unwrap()
     }
-    pub struct Memory<SPI: Instance, PINS,   const P: char, const N: u8, MODE> {
-        spi: Spi<SPI, PINS>, //Our Hal spi
-        cs: Pin<P, N, gpio::Output<MODE>>, //Chip select pin
-        cs_active: PinState, //Active state
-        flash: FlashInfo
+    //Data phase on 2 or 4 lines, only set when the bus is MultiIo.
+    type WideRead<SPI> = fn(&mut SPI, &[u8], u8, &mut [u8]) -> Result<(), Error>;
+    pub struct Memory<SPI> {
+        spi: SPI, //SPI device, handles chip select
+        flash: FlashInfo,
+        verify: bool, //Read back pages after programming
+        timeouts: Timeouts,
+        busy_polls: u32, //Polls allowed for the operation in progress
+        busy: Busy,
+        suspended: Option<(Busy, u32)>, //Suspended operation and its polls
+        read_mode: ReadMode,
+        wide_read: Option<WideRead<SPI>>,
+        powered_down: bool, //Deep power-down, the next access wakes the chip
+        erase_types: [Option<EraseType>; 4],
     }
-    impl <SPI: Instance, PINS, const P: char, const N: u8, MODE>
-    Memory<SPI, PINS, P, N, MODE> {
+    //Constructors for the STM32F4 hal SPI and a GPIO chip select.
+    impl <SPI: Instance, const P: char, const N: u8, MODE>
+    Memory<HalDevice<SPI, P, N, MODE>> {
         //Constructor with cusom flash parameters.
-        pub fn new(spi: Spi<SPI, PINS>, cs: gpio::Pin<P, N, gpio::Output<MODE>>, flash: FlashInfo) -> Self {
-            
-            let mut spi = Memory {
-                spi,
-                cs,
-                cs_active: PinState::Low,
-                flash,
-            };
-            //Set the chipselect
-            spi.init();
-            spi //Return
+        pub fn new(spi: Spi<SPI>, cs: gpio::Pin<P, N, gpio::Output<MODE>>, flash: FlashInfo) -> Self {
+            Memory::new_device(HalDevice::new(spi, cs), flash)
         }
         //Constructor for the ws25j128 type.
-        pub fn new_w25q128(spi: Spi<SPI, PINS>, cs: gpio::Pin<P, N, gpio::Output<MODE>>) -> Self {
-            
-            let mut spi = Memory {
-                spi,
-                cs,
-                cs_active: PinState::Low,
-                flash: W25Q128, //Predefine flash
-            };
-            spi.init(); //Init chip select 
-            spi
+        pub fn new_w25q128(spi: Spi<SPI>, cs: gpio::Pin<P, N, gpio::Output<MODE>>) -> Self {
+            Memory::new_w25q128_device(HalDevice::new(spi, cs))
         }
         //Change the active state of the flash:
         pub fn change_active(&mut self, state: PinState) {
-            self.cs_active = state;
-            self.init();
+            self.spi.change_active(state);
         } 
-        //Set chip select inactive
-        fn init(&mut self) {
-            if self.cs.get_state() == self.cs_active {
-                self.cs.toggle();
+    }
+    impl <SPI: SpiDevice> Memory<SPI> {
+        //Constructor for any embedded-hal SpiDevice, chip select is handled by the device.
+        pub fn new_device(spi: SPI, flash: FlashInfo) -> Self {
+            Memory {
+                spi,
+                flash,
+                verify: false,
+                timeouts: W25Q128_TIMEOUTS,
+                //An erase started before a reset of the MCU may still be running.
+                busy_polls: W25Q128_TIMEOUTS.block_erase64,
+                busy: Busy::Other,
+                suspended: None,
+                read_mode: ReadMode::Standard,
+                wide_read: None,
+                //The chip may have been put to sleep before a reset of the MCU.
+                powered_down: true,
+                erase_types: WINBOND_ERASE,
             }
         }
+        //Constructor for the ws25j128 type on any embedded-hal SpiDevice.
+        pub fn new_w25q128_device(spi: SPI) -> Self {
+            Memory::new_device(spi, W25Q128) //Predefine flash
+        }
+        //Constructor reading the JEDEC ID and picking the geometry from flash_info_for.
+        //A missing or unknown chip gives the SPI device back with the error.
+        pub fn probe(spi: SPI) -> Result<Self, (Error, SPI)> {
+            let mut memory = Memory::new_w25q128_device(spi);
+            match memory.detect() {
+                Ok(_) => Ok(memory),
+                Err(e) => Err((e, memory.release())),
+            }
+        }
+        //Read the JEDEC ID and update the geometry to match the chip.
+        //Parts missing from flash_info_for are described by their SFDP table instead.
+        pub fn detect(&mut self) -> Result<&FlashInfo, Error> {
+            let id = self.read_jedec_id()?;
+            //Nothing drives MISO without a chip, it floats to all ones or all zeroes.
+            if id == [0xff; 3] || id == [0; 3] {
+                return Err(Error::NoDevice);
+            }
+            match flash_info_for(id) {
+                Some(flash) => {
+                    self.flash = flash;
+                    self.erase_types = WINBOND_ERASE;
+                }
+                None => {
+                    let sfdp = self.read_sfdp().map_err(|_| Error::UnknownDevice(id))?;
+                    self.flash = sfdp.info;
+                    self.erase_types = sfdp.erase_types;
+                }
+            }
+            Ok(&self.flash)
+        }
+        //Read and parse the SFDP header and basic parameter table.
+        pub fn read_sfdp(&mut self) -> Result<Sfdp, Error> {
+            let mut header = [0u8; sfdp::HEADER_SIZE];
+            self.read_sfdp_bytes(0, &mut header)?;
+            let (address, len) = sfdp::basic_table(&header)?;
+            let mut table = [0u8; sfdp::MAX_TABLE];
+            let len = len.min(sfdp::MAX_TABLE);
+            self.read_sfdp_bytes(address, &mut table[..len])?;
+            sfdp::parse_basic(&table[..len])
+        }
+        fn read_sfdp_bytes(&mut self, address: u32, buf: &mut [u8]) -> Result<(), Error> {
+            self.wait_ready()?;
+            let addr = split_address(address);
+            self.spi.transaction(&mut [
+                Operation::Write(&[OpCode::ReadSfdp as u8, addr[0], addr[1], addr[2], 0]),
+                Operation::Read(buf),
+            ]).map_err(Error::bus)
+        }
+        //Erase sizes and instructions in use.
+        pub fn erase_types(&self) -> &[Option<EraseType>; 4] {
+            &self.erase_types
+        }
+        //Give back the SPI device.
+        pub fn release(self) -> SPI {
+            self.spi
+        }
         //Return memory info:
         pub fn get_info_sectorsize(&self) -> u32 {
             self.flash.sector_size
         } 
+        pub fn get_info(&self) -> &FlashInfo {
+            &self.flash
+        }
+        //Read back every programmed page, and fail with Error::VerifyMismatch if it differs.
+        pub fn set_verify(&mut self, verify: bool) {
+            self.verify = verify;
+        }
+        //Select the read instruction. Dual and Quad output need enable_multi_io first.
+        pub fn set_read_mode(&mut self, mode: ReadMode) -> Result<(), Error> {
+            match mode {
+                ReadMode::DualOutput | ReadMode::QuadOutput if self.wide_read.is_none() => {
+                    return Err(Error::Unsupported);
+                }
+                ReadMode::QuadOutput => {
+                    //IO2 and IO3 are /WP and /HOLD until QE is set
+                    let mut status2 = self.read_status2()?;
+                    if !status2.qe {
+                        status2.qe = true;
+                        self.write_status2(status2)?;
+                    }
+                }
+                _ => {}
+            }
+            self.read_mode = mode;
+            Ok(())
+        }
+        //Change how long an operation may keep the chip busy before Error::Timeout.
+        pub fn set_timeouts(&mut self, timeouts: Timeouts) {
+            self.timeouts = timeouts;
+        }
+
+        //Deep power-down, unless an operation is running or suspended: then Ok(false).
+        //Every access wakes the chip again, see wake.
+        pub fn power_down(&mut self) -> Result<bool, Error> {
+            if self.powered_down {
+                return Ok(true);
+            }
+            if self.suspended.is_some() || self.is_busy()? {
+                return Ok(false);
+            }
+            self.write_single(OpCode::PowerDown as u8)?;
+            self.spi.transaction(&mut [Operation::DelayNs(POWER_DOWN_NS)]).map_err(Error::bus)?;
+            self.powered_down = true;
+            Ok(true)
+        }
+        //Release power-down and wait tRES1. Harmless if the chip is awake.
+        pub fn wake(&mut self) -> Result<(), Error> {
+            self.write_single(OpCode::DeviceId as u8)?;
+            self.spi.transaction(&mut [Operation::DelayNs(RELEASE_NS)]).map_err(Error::bus)?;
+            self.powered_down = false;
+            Ok(())
+        }
+        pub fn is_powered_down(&self) -> bool {
+            self.powered_down
+        }
+        //Called before anything is sent to the chip.
+        fn awake(&mut self) -> Result<(), Error> {
+            if self.powered_down {
+                self.wake()?;
+            }
+            Ok(())
+        }
+
+        //Wait for the operation in progress, at most its worst case time.
+        fn wait_ready(&mut self) -> Result<(), Error> {
+            self.awake()?;
+            let mut status = [0u8; 1];
+            for _ in 0..=self.busy_polls {
+                self.spi.transaction(&mut [
+                    Operation::Write(&[OpCode::ReadStatus1 as u8]),
+                    Operation::Read(&mut status),
+                    Operation::DelayNs(POLL_INTERVAL_NS), //SR1 is clocked out again if read, harmless.
+                ]).map_err(Error::bus)?;
+                if status[0] & 0b1 == 0 {
+                    self.busy_polls = 0;
+                    self.busy = Busy::Idle;
+                    return Ok(());
+                }
+            }
+            Err(Error::Timeout)
+        }
 
         //Check busy bit of the flash status register (SR):
-        pub fn is_busy(&mut self) -> bool {
-            (self.read_status_reg() & 0b1) > 0
+        pub fn is_busy(&mut self) -> Result<bool, Error> {
+            Ok((self.read_status_reg()? & 0b1) > 0)
         }
 
         //Read flash SR1
-        fn read_status_reg(&mut self) -> u8 {
-            self.cs.set_state(self.cs_active);
-            self.spi.write(&[OpCode::ReadStatus1 as u8]).unwrap_or_default();
-            let status = self.read_single();
-            while self.spi.is_busy() {}
-            self.cs.toggle();
-            status
-        }
-
-        //Read and return one byte from the SPI bus.
-        //No change of the CS pin.
-        fn read_single(&mut self) -> u8 {
-            while self.spi.is_busy() {}
-            self.spi.send(DUMMY).unwrap_or_default();
-            while self.spi.is_busy() {}
-            self.spi.read().unwrap()
-            
+        pub fn read_status_reg(&mut self) -> Result<u8, Error> {
+            self.read_register(OpCode::ReadStatus1)
+        }
+
+        //Manufacturer, memory type and capacity.
+        pub fn read_jedec_id(&mut self) -> Result<[u8; 3], Error> {
+            self.awake()?;
+            let mut id = [0u8; 3];
+            self.spi.transaction(&mut [
+                Operation::Write(&[OpCode::JedecId as u8]),
+                Operation::Read(&mut id),
+            ]).map_err(Error::bus)?;
+            Ok(id)
+        }
+        //64 bit factory programmed ID, unique per chip.
+        pub fn read_unique_id(&mut self) -> Result<[u8; 8], Error> {
+            let mut id = [0u8; 8];
+            self.wait_ready()?;
+            self.spi.transaction(&mut [
+                Operation::Write(&[OpCode::UniqueId as u8, 0, 0, 0, 0]), //Four dummy bytes
+                Operation::Read(&mut id),
+            ]).map_err(Error::bus)?;
+            Ok(id)
+        }
+
+        //Typed status registers:
+        pub fn read_status1(&mut self) -> Result<Status1, Error> {
+            Ok(Status1::from(self.read_register(OpCode::ReadStatus1)?))
+        }
+        pub fn read_status2(&mut self) -> Result<Status2, Error> {
+            Ok(Status2::from(self.read_register(OpCode::ReadStatus2)?))
+        }
+        pub fn read_status3(&mut self) -> Result<Status3, Error> {
+            Ok(Status3::from(self.read_register(OpCode::ReadStatus3)?))
+        }
+        //Non-volatile writes. BUSY, WEL and SUS are read only and ignored.
+        //Fails with Error::WriteProtected if SRP/SRL or /WP kept the old value.
+        pub fn write_status1(&mut self, status: Status1) -> Result<(), Error> {
+            let reg = u8::from(status) & 0xfc;
+            self.write_register(OpCode::WriteStatus1, OpCode::ReadStatus1, reg, 0xfc)
+        }
+        //The LB bits are written as 0, which leaves them as they are. Only
+        //lock_security_register sets them.
+        pub fn write_status2(&mut self, status: Status2) -> Result<(), Error> {
+            let reg = u8::from(status) & 0x47;
+            self.write_register(OpCode::WriteStatus2, OpCode::ReadStatus2, reg, 0x47)
+        }
+        pub fn write_status3(&mut self, status: Status3) -> Result<(), Error> {
+            let reg = u8::from(status);
+            self.write_register(OpCode::WriteStatus3, OpCode::ReadStatus3, reg, 0x64)
+        }
+        fn read_register(&mut self, opcode: OpCode) -> Result<u8, Error> {
+            self.awake()?;
+            let mut reg = [0u8; 1];
+            self.spi.transaction(&mut [
+                Operation::Write(&[opcode as u8]),
+                Operation::Read(&mut reg),
+            ]).map_err(Error::bus)?;
+            Ok(reg[0])
         }
+        //Write a status register and compare the writable bits (mask) after the write cycle.
+        fn write_register(&mut self, write: OpCode, read: OpCode, reg: u8, mask: u8) -> Result<(), Error> {
+            self.write_enable()?;
+            self.busy_polls = self.timeouts.write_status;
+            self.busy = Busy::Other;
+            self.spi.write(&[write as u8, reg]).map_err(Error::bus)?;
+            self.wait_ready()?;
+            if (self.read_register(read)? ^ reg) & mask != 0 {
+                return Err(Error::WriteProtected);
+            }
+            Ok(())
+        }
+
+        //Block protection: lock the smallest area the BP/TB/SEC/CMP bits can describe
+        //covering [start, start+len). Returns the area that is now read only, len 0 unlocks all.
+        //Does not touch SRP/SRL, so the setting can still be changed by software.
+        pub fn protect_range(&mut self, start: u32, len: u32) -> Result<(u32, u32), Error> {
+            if self.flash.capacity_mbit > 128 {
+                return Err(Error::Unsupported); //W25Q256 has four BP bits and no SEC/CMP
+            }
+            self.check_range(start, len as usize)?;
+            let capacity = self.flash.capacity();
+            let protection = Protection::covering(start, start + len, capacity);
+            let mut status1 = self.read_status1()?;
+            let mut status2 = self.read_status2()?;
+            if self.read_status3()?.wps {
+                return Err(Error::Unsupported); //Individual block locks in use instead
+            }
+            status1.bp = protection.bp;
+            status1.tb = protection.tb;
+            status1.sec = protection.sec;
+            status2.cmp = protection.cmp;
+            self.write_status2(status2)?;
+            self.write_status1(status1)?;
+            Ok(protection.range(capacity))
+        }
+        //The area currently protected by BP/TB/SEC/CMP.
+        pub fn protected_range(&mut self) -> Result<(u32, u32), Error> {
+            let status1 = self.read_status1()?;
+            let protection = Protection {
+                bp: status1.bp,
+                tb: status1.tb,
+                sec: status1.sec,
+                cmp: self.read_status2()?.cmp,
+            };
+            Ok(protection.range(self.flash.capacity()))
+        }
+
+        //Check that [addr, addr+len) is inside the memory.
+        fn check_range(&self, addr: u32, len: usize) -> Result<(), Error> {
+            let end = (self.flash.capacity() as u64).min(ADDRESS_LIMIT);
+            if addr as u64 + len as u64 > end {
+                return Err(Error::OutOfRange);
+            }
+            Ok(())
+        }
+
         //Read a predefined lenght into a buffer reference
-        pub fn read(&mut self, addr: u32, len: usize, data: &mut [u8]) {
+        pub fn read(&mut self, addr: u32, len: usize, data: &mut [u8]) -> Result<(), Error> {
             let addr_data = split_address(addr);
-            let mut readlenght = 0;
-
             //Checks for buffer potential bufferoverflow:
-            if len > data.len() {
-                readlenght = data.len(); //Set read cap at buffersize.
-            } else {
-                readlenght = len;
+            let readlenght = len.min(data.len()); //Set read cap at buffersize.
+            self.check_range(addr, readlenght)?;
+
+            let suspended = self.suspend_for_read(addr, readlenght)?;
+            //Read instruction set, the fast reads are followed by one dummy byte
+            let (opcode, lines) = match self.read_mode {
+                ReadMode::Standard => (OpCode::Read, 1),
+                ReadMode::Fast => (OpCode::FastRead, 1),
+                ReadMode::DualOutput => (OpCode::FastReadDual, 2),
+                ReadMode::QuadOutput => (OpCode::FastReadQuad, 4),
+            };
+            let instruction = [opcode as u8, addr_data[0], addr_data[1], addr_data[2], 0];
+            let header = if self.read_mode == ReadMode::Standard { &instruction[..4] } else { &instruction };
+            let result = match self.wide_read {
+                Some(wide_read) if lines > 1 => wide_read(&mut self.spi, header, lines, &mut data[..readlenght]),
+                _ => self.spi.transaction(&mut [
+                    Operation::Write(header),
+                    Operation::Read(&mut data[..readlenght]),
+                ]).map_err(Error::bus),
+            };
+            if suspended {
+                self.resume()?;
+            }
+            result
+        }
+        //A sector/block erase elsewhere is suspended, so reads are not held up for up to 2 s.
+        //Reading the area being erased has to wait for the erase.
+        fn suspend_for_read(&mut self, addr: u32, len: usize) -> Result<bool, Error> {
+            if let Busy::Erase(start, end) = self.busy {
+                let overlaps = addr < end && start < addr + len as u32;
+                if !overlaps && self.suspend()? {
+                    return Ok(true);
+                }
             }
+            self.wait_ready()?;
+            Ok(false)
+        }
 
-            while self.is_busy() {}
-            self.cs.set_state(self.cs_active);
-            //Read instruction set
-            self.spi.write(&[OpCode::Read as u8]).unwrap_or_default();
-            self.spi.write(&addr_data).unwrap_or_default();
-            
-            for i in 0..readlenght {
-                while self.spi.is_busy() {}
-                self.spi.send(DUMMY).unwrap_or_default();
-                while self.spi.is_busy() {}
-                data[i] = self.spi.read().unwrap_or_default();
+        //Erase/program suspend. Ok(false) if nothing that can be suspended was running.
+        //Only reads are allowed until resume.
+        pub fn suspend(&mut self) -> Result<bool, Error> {
+            if self.suspended.is_some() {
+                return Ok(true);
+            }
+            if !matches!(self.busy, Busy::Program | Busy::Erase(..)) || !self.is_busy()? {
+                return Ok(false);
+            }
+            self.write_single(OpCode::Suspend as u8)?;
+            let (busy, polls) = (self.busy, self.busy_polls);
+            self.busy_polls = self.timeouts.suspend;
+            self.wait_ready()?;
+            //SUS is not set if the operation finished before the suspend.
+            if !self.read_status2()?.sus {
+                return Ok(false);
+            }
+            self.suspended = Some((busy, polls));
+            Ok(true)
+        }
+        //Continue a suspended erase/program.
+        pub fn resume(&mut self) -> Result<(), Error> {
+            if let Some((busy, polls)) = self.suspended.take() {
+                self.write_single(OpCode::Resume as u8)?;
+                self.busy = busy;
+                self.busy_polls = polls;
+                //The operation has to run for tSUS before it may be suspended again.
+                self.spi.transaction(&mut [Operation::DelayNs(POLL_INTERVAL_NS * self.timeouts.suspend)]).map_err(Error::bus)?;
             }
-            while self.spi.is_busy() {}
-            self.cs.toggle(); //Done
+            Ok(())
+        }
+        //True between suspend and resume.
+        pub fn is_suspended(&self) -> bool {
+            self.suspended.is_some()
         }
 
         //Delete functions:
-        //Update to single function taking opcode: //
-        fn sector_erase(&mut self, addr: [u8;3]) {
-            self.write_enable();
-            while self.is_busy(){}
-            self.cs.set_state(self.cs_active);
-            while self.spi.is_busy() {}
-            self.spi.write(&[OpCode::SectorErase as u8]).unwrap();
-            self.spi.write(&addr).unwrap();
-            while self.spi.is_busy() {}
-            self.cs.toggle();
-
-        }
-        //32kB block erase
-        fn block32_erase(&mut self, addr: [u8;3]) {
-            self.write_enable();
-            while self.is_busy(){}
-            self.cs.set_state(self.cs_active);
-            while self.spi.is_busy() {}
-            self.spi.write(&[OpCode::BlockErase32 as u8]).unwrap();
-            self.spi.write(&addr).unwrap();
-            while self.spi.is_busy() {}
-            self.cs.toggle();
-
-        }
-        //64kB block erase 
-         fn block64_erase(&mut self, addr: [u8;3]) {
-            self.write_enable();
-            while self.is_busy(){}
-            self.cs.set_state(self.cs_active);
-            while self.spi.is_busy() {}
-            self.spi.write(&[OpCode::BlockErase64 as u8]).unwrap();
-            self.spi.write(&addr).unwrap();
-            while self.spi.is_busy() {}
-            self.cs.toggle();
-
+        //Erase instruction with a trailing 3 byte address, picked from the erase types by size.
+        fn erase(&mut self, size: u32, address: u32) -> Result<(), Error> {
+            let opcode = self.erase_types.iter().flatten()
+                .find(|erase| erase.size == size)
+                .ok_or(Error::Unsupported)?.opcode;
+            let addr = split_address(address);
+            self.write_enable()?;
+            let polls = match size {
+                0..=0x1000 => self.timeouts.sector_erase,
+                0x1001..=0x8000 => self.timeouts.block_erase32,
+                _ => self.timeouts.block_erase64,
+            };
+            let start = address & !(size - 1);
+            self.busy_polls = polls;
+            self.busy = Busy::Erase(start, start + size);
+            let instruction = [opcode, addr[0], addr[1], addr[2]];
+            self.spi.write(&instruction).map_err(Error::bus)
         }
         //Chip erase USE WITH CAUTION
-         fn chip_erase(&mut self) {
-            self.write_enable();
-            while self.is_busy(){}
-            self.cs.set_state(self.cs_active);
-            while self.spi.is_busy() {}
-            let opcode: [u8;2] = [OpCode::ChipErase1 as u8, OpCode::ChipErase2 as u8];
-            self.spi.write(&opcode).unwrap();
-
-            while self.spi.is_busy() {}
-            self.cs.toggle();
-
+        fn chip_erase(&mut self) -> Result<(), Error> {
+            self.write_enable()?;
+            self.busy_polls = self.timeouts.chip_erase;
+            self.busy = Busy::Other;
+            //Only one of the two chip erase codes, the chip ignores instructions longer than 8 bits.
+            self.write_single(OpCode::ChipErase1 as u8)
         }
         //Public interface for delete functions:
-        pub fn delete(&mut self, option: Delete, addr: u32) {
-            let addr_split = split_address(addr);
+        pub fn delete(&mut self, option: Delete, addr: u32) -> Result<(), Error> {
+            self.check_range(addr, 1)?;
             match option {
-                Delete::SectorErase => self.sector_erase(addr_split),
-                Delete::BlockErase32 => self.block32_erase(addr_split),
-                Delete::BlockErase64 => self.block64_erase(addr_split),
+                Delete::SectorErase => self.erase(self.flash.sector_size, addr),
+                Delete::BlockErase32 => self.erase(0x8000, addr),
+                Delete::BlockErase64 => self.erase(0x10000, addr),
                 Delete::ChipErase => self.chip_erase(),
             }
         }
 
         //For single word instructions
-        fn write_single(&mut self, byte: u8){
-            self.cs.set_state(self.cs_active);
-            while self.spi.is_busy() {}
-            self.spi.write(&[byte]).unwrap_or_default();
-            while self.spi.is_busy() {}
-            self.cs.toggle();
+        fn write_single(&mut self, byte: u8) -> Result<(), Error> {
+            self.spi.write(&[byte]).map_err(Error::bus)
         }
         //Software write enable (WEL).
         //Used for pageprogram, and erasure.
-        fn write_enable(&mut self) {
-            while self.is_busy() {}
-            self.write_single(OpCode::WriteEnable as u8);
+        //If the WEL bit does not latch, the chip refuses writes.
+        fn write_enable(&mut self) -> Result<(), Error> {
+            self.wait_ready()?;
+            self.write_single(OpCode::WriteEnable as u8)?;
+            if self.read_status_reg()? & 0b10 == 0 {
+                return Err(Error::WriteProtected);
+            }
+            Ok(())
         }
         //Disable software WEL
-        fn write_disable(&mut self) {
-            self.write_single(OpCode::WriteDisable as u8);
+        #[allow(unused)]
+        fn write_disable(&mut self) -> Result<(), Error> {
+            self.write_single(OpCode::WriteDisable as u8)
         }
 
         //Programming a page in flash:
-        fn write_page(&mut self, addr: [u8;3], data: &[u8]) {
-            // defmt::info!("Writing {} bytes to addr: {:x}{:x}{:x}", data.len(), addr[0],addr[1], addr[2]);
-            while self.is_busy() {}
-            self.write_enable();
-            self.cs.set_state(self.cs_active);
-            self.spi.write(&[OpCode::PageProgram as u8]).unwrap_or_default();
-            self.spi.write(&addr).unwrap_or_default();
-            self.spi.write(data).unwrap();
-            while self.spi.is_busy() {}
-            self.cs.toggle();
-        }
-        //public Write function, allow for single aswell as multi page programming:
-        pub fn write(&mut self, addr: u32, data: &[u8] ) {
-            let mut address = addr; //Copy of the address
-            let first = address & 0xff; //Index on page
-            //Check if everything fits on the remaining space of the page:
-            if (first as usize + data.len()) > self.flash.page_size as usize { 
-                let mut index = 0; //Data index
-                let first_page = self.flash.page_size - (first as u16); //Find data boundaries for first page
-
-                let full_pages = (data.len() - first_page as usize) / self.flash.page_size as usize;
-                //Program first page
-                self.write_page(split_address(address), &data[index..first_page as usize]);
-                index = first_page as usize;
-                address -= first; //Set page index to 0
-
-                //Program full pages:
-                if full_pages > 0 { 
-                    for i in 0..full_pages {
-                        address +=0x100; //Address jump one page.
-                        self.write_page(split_address(address), &data[index..(index+self.flash.page_size as usize)]);
-                        index +=self.flash.page_size as usize; //Update index
-                    }
+        fn write_page(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+            // defmt::info!("Writing {} bytes to addr: {:x}", data.len(), addr);
+            let addr_data = split_address(addr);
+            self.write_enable()?;
+            self.busy_polls = self.timeouts.page_program;
+            self.busy = Busy::Program;
+            let instruction = [OpCode::PageProgram as u8, addr_data[0], addr_data[1], addr_data[2]];
+            self.spi.transaction(&mut [
+                Operation::Write(&instruction),
+                Operation::Write(data),
+            ]).map_err(Error::bus)?;
+            if self.verify {
+                self.verify_page(addr, data)?;
+            }
+            Ok(())
+        }
+        //Compare a programmed page with the data it was given.
+        fn verify_page(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+            let mut read_back = [0u8; 256];
+            for (i, chunk) in data.chunks(read_back.len()).enumerate() {
+                self.read(addr + (i * read_back.len()) as u32, chunk.len(), &mut read_back)?;
+                if read_back[..chunk.len()] != *chunk {
+                    return Err(Error::VerifyMismatch);
                 }
-                address +=0x100;
-                //Write last partial page.
-                self.write_page(split_address(address), &data[index..]); 
             }
-            //Fits on the page, write.
-            else {
-                self.write_page(split_address(address), data);
+            Ok(())
+        }
+        //Overwrite whatever is stored, erasing sectors when needed. See NorFlash::update.
+        pub fn update(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+            NorFlash::update(self, addr, data)
+        }
+        //public Write function, allow for single aswell as multi page programming.
+        //Split at the page size of the chip, from SFDP for parts that are not known.
+        pub fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+            self.check_range(addr, data.len())?;
+            let page = self.flash.page_size as u32;
+            let mut address = addr; //Copy of the address
+            let mut index = 0; //Data index
+            while index < data.len() {
+                let room = (page - address % page) as usize; //Left on the page
+                let end = (index + room).min(data.len());
+                self.write_page(address, &data[index..end])?;
+                address += (end - index) as u32;
+                index = end;
+            }
+            Ok(())
+        }
+    }
+
+    //Security registers 1-3, 256 bytes each, for data that must outlive any erase of the array.
+    //Program and erase are refused during a suspend, so they are too.
+    impl <SPI: SpiDevice> Memory<SPI> {
+        fn security_address(register: u8, offset: usize, len: usize) -> Result<[u8; 3], Error> {
+            if !(1..=3).contains(&register) || offset + len > SECURITY_REGISTER_SIZE {
+                return Err(Error::OutOfRange);
+            }
+            Ok([0, register << 4, offset as u8])
+        }
+        //Refuse to change a locked register, the chip would silently ignore it.
+        fn security_writable(&mut self, register: u8) -> Result<(), Error> {
+            if self.suspended.is_some() {
+                return Err(Error::Unsupported);
+            }
+            if self.is_security_register_locked(register)? {
+                return Err(Error::WriteProtected);
             }
-            
+            Ok(())
+        }
+        pub fn read_security_register(&mut self, register: u8, offset: usize, buf: &mut [u8]) -> Result<(), Error> {
+            let addr = Self::security_address(register, offset, buf.len())?;
+            self.wait_ready()?;
+            self.spi.transaction(&mut [
+                Operation::Write(&[OpCode::ReadSecurity as u8, addr[0], addr[1], addr[2], 0]),
+                Operation::Read(buf),
+            ]).map_err(Error::bus)
+        }
+        //Only clears bits, like a page program. Always read back, the data is meant to be permanent.
+        pub fn program_security_register(&mut self, register: u8, offset: usize, data: &[u8]) -> Result<(), Error> {
+            let addr = Self::security_address(register, offset, data.len())?;
+            self.security_writable(register)?;
+            self.write_enable()?;
+            self.busy_polls = self.timeouts.page_program;
+            self.busy = Busy::Other;
+            self.spi.transaction(&mut [
+                Operation::Write(&[OpCode::ProgramSecurity as u8, addr[0], addr[1], addr[2]]),
+                Operation::Write(data),
+            ]).map_err(Error::bus)?;
+            let mut read_back = [0u8; SECURITY_REGISTER_SIZE];
+            let read_back = &mut read_back[..data.len()];
+            self.read_security_register(register, offset, read_back)?;
+            if read_back != data {
+                return Err(Error::VerifyMismatch);
+            }
+            Ok(())
+        }
+        pub fn erase_security_register(&mut self, register: u8) -> Result<(), Error> {
+            let addr = Self::security_address(register, 0, 0)?;
+            self.security_writable(register)?;
+            self.write_enable()?;
+            self.busy_polls = self.timeouts.sector_erase;
+            self.busy = Busy::Other;
+            self.spi.write(&[OpCode::EraseSecurity as u8, addr[0], addr[1], addr[2]]).map_err(Error::bus)
+        }
+        pub fn is_security_register_locked(&mut self, register: u8) -> Result<bool, Error> {
+            Self::security_address(register, 0, 0)?;
+            Ok(self.read_status2()?.lb & (1 << (register - 1)) > 0)
+        }
+        //First step of locking: check the register and hand out the permission to lock it.
+        pub fn arm_security_lock(&mut self, register: u8) -> Result<SecurityLock, Error> {
+            self.security_writable(register)?;
+            Ok(SecurityLock { register })
+        }
+        //Set the LB bit of an armed register. This is one time programmable: the register can
+        //never be programmed or erased again.
+        pub fn lock_security_register(&mut self, lock: SecurityLock) -> Result<(), Error> {
+            let bit = 1 << (lock.register + 2);
+            let reg = self.read_register(OpCode::ReadStatus2)? & 0x47 | bit;
+            self.write_register(OpCode::WriteStatus2, OpCode::ReadStatus2, reg, bit)
+        }
+    }
 
+    impl <SPI: MultiIo> Memory<SPI> {
+        //Allow ReadMode::DualOutput and ReadMode::QuadOutput on this bus.
+        pub fn enable_multi_io(&mut self) {
+            self.wide_read = Some(|spi, header, lines, data| {
+                spi.read_wide(header, lines, data).map_err(Error::bus)
+            });
         }
-    }
\ No newline at end of file
+    }
+
+    //Background transfers, see crate::DmaFlash. The buffer is owned by the bus until poll_transfer
+    //returns it. Start waits for a program or erase in progress, so check is_busy first to avoid blocking.
+    impl <SPI: DmaDevice> DmaFlash for Memory<SPI> {
+        //Read len bytes from addr.
+        fn start_read(&mut self, addr: u32, buffer: &'static mut [u8], len: usize) -> Result<(), (Error, &'static mut [u8])> {
+            if len > buffer.len() {
+                return Err((Error::OutOfRange, buffer));
+            }
+            if let Err(e) = self.check_range(addr, len).and_then(|_| self.wait_ready()) {
+                return Err((e, buffer));
+            }
+            let addr_data = split_address(addr);
+            let (opcode, header) = match self.read_mode {
+                ReadMode::Standard => (OpCode::Read, 4),
+                _ => (OpCode::FastRead, 5), //Only one data line, the wide reads fall back to Fast Read.
+            };
+            let instruction = [opcode as u8, addr_data[0], addr_data[1], addr_data[2], 0];
+            self.spi.start_read(&instruction[..header], buffer, len).map_err(|(e, buffer)| (Error::bus(e), buffer))
+        }
+        //Program len bytes from addr, all of them inside one page.
+        fn start_write_page(&mut self, addr: u32, buffer: &'static mut [u8], len: usize) -> Result<(), (Error, &'static mut [u8])> {
+            let room = self.flash.page_size as usize - (addr as usize & (self.flash.page_size as usize - 1));
+            if len > room || len > buffer.len() {
+                return Err((Error::OutOfRange, buffer));
+            }
+            if let Err(e) = self.check_range(addr, len).and_then(|_| self.write_enable()) {
+                return Err((e, buffer));
+            }
+            self.busy_polls = self.timeouts.page_program;
+            self.busy = Busy::Program;
+            let addr_data = split_address(addr);
+            let instruction = [OpCode::PageProgram as u8, addr_data[0], addr_data[1], addr_data[2]];
+            self.spi.start_write(&instruction, buffer, len).map_err(|(e, buffer)| (Error::bus(e), buffer))
+        }
+        //Poll from the DMA interrupt or a task: the buffer once the transfer is done, or with
+        //the error if it failed. A program still runs in the chip afterwards, see is_busy.
+        fn poll_transfer(&mut self) -> nb::Result<&'static mut [u8], (Error, Option<&'static mut [u8]>)> {
+            self.spi.poll_transfer().map_err(|e| e.map(|(e, buffer)| (Error::bus(e), buffer)))
+        }
+    }
+
+    //Hardware independent interface, see crate::NorFlash.
+    impl <SPI: SpiDevice> NorFlash for Memory<SPI> {
+        fn read(&mut self, addr: u32, len: usize, data: &mut [u8]) -> Result<(), Error> {
+            Memory::read(self, addr, len, data)
+        }
+        fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+            Memory::write(self, addr, data)
+        }
+        fn delete(&mut self, option: Delete, addr: u32) -> Result<(), Error> {
+            Memory::delete(self, option, addr)
+        }
+        fn is_busy(&mut self) -> Result<bool, Error> {
+            Memory::is_busy(self)
+        }
+        fn info(&self) -> &FlashInfo {
+            &self.flash
+        }
+    }
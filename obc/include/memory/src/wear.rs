@@ -0,0 +1,431 @@
+//Wear leveling on top of any NorFlash.
+//A region of physical sectors is presented as fewer logical sectors. Erasing a logical
+//sector maps it onto the least worn free (spare) sector instead of erasing in place, and
+//when the erase counts drift too far apart, data that is never erased is moved onto a
+//worn sector so the sector it sat on gets used.
+//The mapping and the erase counts are kept in an append only log in two extra sectors.
+use crate::w25q128::{Delete, FlashInfo};
+use crate::{Error, NorFlash};
+
+const SECTOR_SIZE: usize = 0x1000;
+const RECORD_SIZE: u32 = 8;
+
+//Log records: |tag|a|b|count (4 bytes, BE)|check|
+const HEADER: u8 = 0x48; //Log header, count is the log sequence number
+const MAP: u8 = 0x4d; //Logical a is on physical b, with count erases
+const SPARE: u8 = 0x43; //Physical b is spare, with count erases
+const UNUSED: u8 = 0xff;
+
+#[derive(Clone, Copy, Debug)]
+pub struct WearConfig {
+    pub start: u32,     //Address of the first physical sector of the region
+    pub sectors: usize, //Physical sectors in the region, at most N
+    pub spares: usize,  //Sectors kept free for rotation, the rest are logical sectors
+    pub log: u32,       //Address of two sectors for the log, outside the region
+    pub threshold: u32, //Erase count spread that makes cold data move
+}
+
+//N is the largest region supported, at most 255 sectors.
+pub struct WearLevel<F: NorFlash, const N: usize> {
+    flash: F,
+    config: WearConfig,
+    info: FlashInfo,
+    map: [u8; N],     //Physical sector of each logical sector
+    counts: [u32; N], //Erase count of each physical sector
+    log: u32,         //Active log sector
+    log_offset: u32,  //Next free record in the active log sector
+    seq: u32,         //Sequence number of the active log sector
+}
+
+pub(crate) fn record(tag: u8, a: u8, b: u8, count: u32) -> [u8; 8] {
+    let c = count.to_be_bytes();
+    let mut record = [tag, a, b, c[0], c[1], c[2], c[3], 0];
+    record[7] = !record[..7].iter().fold(0, |x, b| x ^ b);
+    record
+}
+
+//None for an erased or torn record.
+pub(crate) fn parse(record: &[u8]) -> Option<(u8, u8, u8, u32)> {
+    if record[0] == UNUSED || !record[..7].iter().fold(0, |x, b| x ^ b) != record[7] {
+        return None;
+    }
+    let count = u32::from_be_bytes([record[3], record[4], record[5], record[6]]);
+    Some((record[0], record[1], record[2], count))
+}
+
+//True if every byte from start up to end reads erased.
+pub(crate) fn erased<F: NorFlash>(flash: &mut F, start: u32, end: u32) -> Result<bool, Error> {
+    let mut chunk = [0u8; 256];
+    let mut address = start;
+    while address < end {
+        let len = ((end - address) as usize).min(chunk.len());
+        flash.read(address, len, &mut chunk)?;
+        if chunk[..len].iter().any(|b| *b != 0xff) {
+            return Ok(false);
+        }
+        address += len as u32;
+    }
+    Ok(true)
+}
+
+impl<F: NorFlash, const N: usize> WearLevel<F, N> {
+    //Load the mapping from the log, or start one with logical sector n on physical sector n.
+    pub fn mount(mut flash: F, config: WearConfig) -> Result<Self, Error> {
+        let sector_size = flash.info().sector_size;
+        let logical = config.sectors.saturating_sub(config.spares);
+        if sector_size as usize != SECTOR_SIZE
+            || config.sectors > N.min(255)
+            || config.spares == 0
+            || logical == 0
+        {
+            return Err(Error::Unsupported);
+        }
+        let region_end = config.start + (config.sectors * SECTOR_SIZE) as u32;
+        let log_end = config.log + 2 * sector_size;
+        if region_end > flash.info().capacity() || log_end > flash.info().capacity() {
+            return Err(Error::OutOfRange);
+        }
+        if config.log < region_end && config.start < log_end {
+            return Err(Error::OutOfRange); //Log inside the region
+        }
+        let info = FlashInfo {
+            page_size: flash.info().page_size,
+            sector_size,
+            page_count: (logical * SECTOR_SIZE) as u32 / flash.info().page_size as u32,
+            sector_count: logical as u32,
+            block_size: flash.info().block_size,
+            block_count: (logical * SECTOR_SIZE) as u32 / flash.info().block_size,
+            capacity_mbit: (logical * SECTOR_SIZE * 8 / 0x100000) as u32,
+        };
+        //Newest log sector with a valid header.
+        let mut active = None;
+        for log in [config.log, config.log + sector_size] {
+            let mut header = [0u8; 8];
+            flash.read(log, 8, &mut header)?;
+            if let Some((HEADER, _, _, seq)) = parse(&header) {
+                if active.is_none_or(|(_, newest)| seq > newest) {
+                    active = Some((log, seq));
+                }
+            }
+        }
+        let mut wear = WearLevel {
+            flash,
+            config,
+            info,
+            map: core::array::from_fn(|i| i as u8),
+            counts: [0; N],
+            log: config.log,
+            log_offset: RECORD_SIZE,
+            seq: 0,
+        };
+        match active {
+            Some((log, seq)) => wear.replay(log, seq)?,
+            None => wear.compact()?,
+        }
+        Ok(wear)
+    }
+
+    fn replay(&mut self, log: u32, seq: u32) -> Result<(), Error> {
+        self.log = log;
+        self.seq = seq;
+        self.log_offset = RECORD_SIZE;
+        let mut page = [0u8; 256];
+        'pages: for page_addr in (log..log + SECTOR_SIZE as u32).step_by(page.len()) {
+            self.flash.read(page_addr, page.len(), &mut page)?;
+            for (i, raw) in page.chunks(RECORD_SIZE as usize).enumerate() {
+                let offset = page_addr - log + (i as u32 * RECORD_SIZE);
+                if offset < RECORD_SIZE {
+                    continue; //Header
+                }
+                match parse(raw) {
+                    Some((MAP, logical, physical, count)) if self.valid(logical, physical) => {
+                        self.map[logical as usize] = physical;
+                        self.counts[physical as usize] = count;
+                    }
+                    Some((SPARE, _, physical, count))
+                        if (physical as usize) < self.config.sectors =>
+                    {
+                        self.counts[physical as usize] = count;
+                    }
+                    _ => break 'pages, //End of the log, or a record torn by a power loss
+                }
+                self.log_offset = offset + RECORD_SIZE;
+            }
+        }
+        //Records appended over a torn one would be torn as well, so the log is moved.
+        let end = log + SECTOR_SIZE as u32;
+        if !erased(&mut self.flash, log + self.log_offset, end)? {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn valid(&self, logical: u8, physical: u8) -> bool {
+        (logical as u32) < self.info.sector_count && (physical as usize) < self.config.sectors
+    }
+
+    //Write the whole mapping to the other log sector. The header goes last, so an
+    //interrupted compaction leaves the old log in use.
+    fn compact(&mut self) -> Result<(), Error> {
+        let sector_size = SECTOR_SIZE as u32;
+        let next = if self.seq == 0 || self.log != self.config.log {
+            self.config.log
+        } else {
+            self.config.log + sector_size
+        };
+        self.flash.delete(Delete::SectorErase, next)?;
+        let mut offset = RECORD_SIZE;
+        for physical in 0..self.config.sectors {
+            let count = self.counts[physical];
+            let record = match self.logical_of(physical) {
+                Some(logical) => record(MAP, logical as u8, physical as u8, count),
+                None => record(SPARE, 0xff, physical as u8, count),
+            };
+            self.flash.write(next + offset, &record)?;
+            offset += RECORD_SIZE;
+        }
+        self.flash
+            .write(next, &record(HEADER, 0, 0, self.seq + 1))?;
+        self.log = next;
+        self.log_offset = offset;
+        self.seq += 1;
+        Ok(())
+    }
+
+    fn append(&mut self, record: [u8; 8]) -> Result<(), Error> {
+        if self.log_offset + RECORD_SIZE > SECTOR_SIZE as u32 {
+            return self.compact(); //The snapshot includes the change
+        }
+        self.flash.write(self.log + self.log_offset, &record)?;
+        self.log_offset += RECORD_SIZE;
+        Ok(())
+    }
+
+    fn logical_of(&self, physical: usize) -> Option<usize> {
+        self.map[..self.info.sector_count as usize]
+            .iter()
+            .position(|p| *p as usize == physical)
+    }
+
+    fn physical_address(&self, logical: usize) -> u32 {
+        self.config.start + self.map[logical] as u32 * SECTOR_SIZE as u32
+    }
+
+    fn erase_physical(&mut self, physical: usize) -> Result<(), Error> {
+        let address = self.config.start + (physical * SECTOR_SIZE) as u32;
+        self.flash.delete(Delete::SectorErase, address)?;
+        self.counts[physical] += 1;
+        Ok(())
+    }
+
+    //Spare sectors, by erase count.
+    fn spare(&self, most_worn: bool) -> usize {
+        let spares = (0..self.config.sectors).filter(|p| self.logical_of(*p).is_none());
+        if most_worn {
+            spares.max_by_key(|p| self.counts[*p]).unwrap()
+        } else {
+            spares.min_by_key(|p| self.counts[*p]).unwrap()
+        }
+    }
+
+    //Point a logical sector at a freshly erased spare.
+    fn erase_logical(&mut self, logical: usize) -> Result<(), Error> {
+        let physical = self.spare(false);
+        self.erase_physical(physical)?;
+        self.map[logical] = physical as u8;
+        self.append(record(
+            MAP,
+            logical as u8,
+            physical as u8,
+            self.counts[physical],
+        ))?;
+        self.level()
+    }
+
+    //Static leveling: move the least erased data onto the most erased spare.
+    fn level(&mut self) -> Result<(), Error> {
+        let logical_count = self.info.sector_count as usize;
+        let cold = (0..logical_count)
+            .min_by_key(|l| self.counts[self.map[*l] as usize])
+            .unwrap();
+        let worn = self.spare(true);
+        if self.counts[worn] <= self.counts[self.map[cold] as usize] + self.config.threshold {
+            return Ok(());
+        }
+        let mut data = [0u8; SECTOR_SIZE];
+        self.flash
+            .read(self.physical_address(cold), SECTOR_SIZE, &mut data)?;
+        self.erase_physical(worn)?;
+        let address = self.config.start + (worn * SECTOR_SIZE) as u32;
+        for (i, page) in data.chunks(self.info.page_size as usize).enumerate() {
+            if page.iter().any(|b| *b != 0xff) {
+                self.flash.write(address + (i * page.len()) as u32, page)?;
+            }
+        }
+        self.map[cold] = worn as u8;
+        self.append(record(MAP, cold as u8, worn as u8, self.counts[worn]))
+    }
+
+    //Erase count of every physical sector in the region.
+    pub fn erase_counts(&self) -> &[u32] {
+        &self.counts[..self.config.sectors]
+    }
+
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    fn check_range(&self, addr: u32, len: usize) -> Result<(), Error> {
+        if addr as u64 + len as u64 > self.info.capacity() as u64 {
+            return Err(Error::OutOfRange);
+        }
+        Ok(())
+    }
+}
+
+impl<F: NorFlash, const N: usize> NorFlash for WearLevel<F, N> {
+    fn read(&mut self, addr: u32, len: usize, data: &mut [u8]) -> Result<(), Error> {
+        let len = len.min(data.len());
+        self.check_range(addr, len)?;
+        let mut index = 0;
+        while index < len {
+            let address = addr + index as u32;
+            let offset = address as usize % SECTOR_SIZE;
+            let chunk = (SECTOR_SIZE - offset).min(len - index);
+            let physical = self.physical_address(address as usize / SECTOR_SIZE) + offset as u32;
+            self.flash
+                .read(physical, chunk, &mut data[index..index + chunk])?;
+            index += chunk;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        self.check_range(addr, data.len())?;
+        let mut index = 0;
+        while index < data.len() {
+            let address = addr + index as u32;
+            let offset = address as usize % SECTOR_SIZE;
+            let chunk = (SECTOR_SIZE - offset).min(data.len() - index);
+            let physical = self.physical_address(address as usize / SECTOR_SIZE) + offset as u32;
+            self.flash.write(physical, &data[index..index + chunk])?;
+            index += chunk;
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, option: Delete, addr: u32) -> Result<(), Error> {
+        self.check_range(addr, 1)?;
+        let sectors = match option {
+            Delete::SectorErase => 1,
+            Delete::BlockErase32 => 8,
+            Delete::BlockErase64 => 16,
+            Delete::ChipErase => self.info.sector_count as usize,
+        };
+        let logical_count = self.info.sector_count as usize;
+        let first = match option {
+            Delete::ChipErase => 0,
+            _ => addr as usize / SECTOR_SIZE / sectors * sectors,
+        };
+        for logical in first..(first + sectors).min(logical_count) {
+            self.erase_logical(logical)?;
+        }
+        Ok(())
+    }
+
+    fn is_busy(&mut self) -> Result<bool, Error> {
+        self.flash.is_busy()
+    }
+
+    fn info(&self) -> &FlashInfo {
+        &self.info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::SimFlash;
+
+    const CONFIG: WearConfig = WearConfig {
+        start: 0,
+        sectors: 16,
+        spares: 4,
+        log: 0x10000,
+        threshold: 8,
+    };
+    type Sim = SimFlash<0x12000>; //16 sectors and the two log sectors
+
+    //The flight plan: 48 tasks of 256 bytes in logical sectors 0-2. Scheduling programs
+    //a slot, and once a sector is full of executed tasks it is erased. Sectors 3-11 hold
+    //data that is written once.
+    #[test]
+    fn schedule_delete_cycles_spread_wear() {
+        let mut wear = WearLevel::<Sim, 16>::mount(Sim::new(), CONFIG).unwrap();
+        for sector in 3..12u32 {
+            wear.write(sector * 0x1000, &[sector as u8; 64]).unwrap();
+        }
+        let cycles = 5000;
+        for cycle in 0..cycles {
+            let slot = cycle % 48;
+            let task: [u8; 16] = core::array::from_fn(|i| (cycle + i as u32) as u8);
+            wear.write(slot * 256, &task).unwrap();
+            let mut read_back = [0u8; 16];
+            wear.read(slot * 256, 16, &mut read_back).unwrap();
+            assert_eq!(read_back, task);
+            if slot % 16 == 15 {
+                wear.delete(Delete::SectorErase, slot * 256).unwrap();
+            }
+        }
+        let counts = wear.erase_counts();
+        let (min, max) = (*counts.iter().min().unwrap(), *counts.iter().max().unwrap());
+        let total: u32 = counts.iter().sum();
+        println!("{} cycles, erase counts {:?}", cycles, counts);
+        println!(
+            "spread {} (min {}, max {}), {} erases",
+            max - min,
+            min,
+            max,
+            total
+        );
+        //Without leveling sectors 0-2 would have taken all 312 erases each.
+        assert!(max - min <= CONFIG.threshold + 1);
+        assert!(max < 312 / 2);
+
+        //The mapping survives a remount, and the cold data moved along.
+        let before: [u32; 16] = core::array::from_fn(|i| counts[i]);
+        let mut wear = WearLevel::<Sim, 16>::mount(wear.release(), CONFIG).unwrap();
+        assert_eq!(wear.erase_counts(), before);
+        for sector in 3..12u32 {
+            let mut data = [0u8; 64];
+            wear.read(sector * 0x1000, 64, &mut data).unwrap();
+            assert_eq!(data, [sector as u8; 64]);
+        }
+    }
+
+    #[test]
+    fn torn_log_record_is_ignored() {
+        let mut wear = WearLevel::<Sim, 16>::mount(Sim::new(), CONFIG).unwrap();
+        wear.delete(Delete::SectorErase, 0).unwrap();
+        let mapped = wear.map[0];
+        let log_end = wear.log + wear.log_offset;
+        let mut flash = wear.release();
+        //Half a record, as left by a power loss during the next erase.
+        flash.write(log_end, &[MAP, 0, 5, 0]).unwrap();
+        let mut wear = WearLevel::<Sim, 16>::mount(flash, CONFIG).unwrap();
+        assert_eq!(wear.map[0], mapped);
+        assert_ne!(
+            wear.log + wear.log_offset,
+            log_end,
+            "not appended over the torn record"
+        );
+
+        //Changes after the torn record survive the next mount.
+        wear.delete(Delete::SectorErase, 0).unwrap();
+        wear.delete(Delete::SectorErase, 0x1000).unwrap();
+        let (first, second) = (wear.map[0], wear.map[1]);
+        assert_ne!(first, mapped);
+        let wear = WearLevel::<Sim, 16>::mount(wear.release(), CONFIG).unwrap();
+        assert_eq!((wear.map[0], wear.map[1]), (first, second));
+    }
+}